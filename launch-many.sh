@@ -1,5 +1,5 @@
 #!/usr/bin/env bash
 for num in {1..10}; do
   echo $num
-  convert-invert $num
+  convert-invert run --attempt $num
 done
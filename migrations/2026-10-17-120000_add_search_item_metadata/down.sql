@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE search_items
+  DROP COLUMN artists,
+  DROP COLUMN duration_ms,
+  DROP COLUMN isrc,
+  DROP COLUMN track_number,
+  DROP COLUMN disc_number;
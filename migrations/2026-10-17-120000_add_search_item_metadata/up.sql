@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE search_items
+  ADD COLUMN artists TEXT[] NOT NULL DEFAULT '{}',
+  ADD COLUMN duration_ms INTEGER,
+  ADD COLUMN isrc VARCHAR,
+  ADD COLUMN track_number INTEGER,
+  ADD COLUMN disc_number INTEGER;
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+-- Merged duplicate rows are not restored.
+DROP INDEX IF EXISTS search_items_track_id_key;
+
+ALTER TABLE search_items ALTER COLUMN track_id TYPE INTEGER
+  USING ('x' || left(md5(track_id), 8))::bit(32)::integer;
+
+ALTER TABLE search_items DROP COLUMN spotify_id;
@@ -0,0 +1,46 @@
+-- Your SQL goes here
+--
+-- Replaces the truncated DefaultHasher integer with the text identity built
+-- by `SearchItem::identity`. Existing rows predate the spotify_id column, so
+-- they are keyed by ISRC when present and by the metadata hash otherwise:
+-- 'meta:' || first 16 hex digits of sha256(track \x1f artist \x1f album),
+-- each field ASCII-lowercased with whitespace collapsed.
+ALTER TABLE search_items ADD COLUMN spotify_id VARCHAR;
+
+CREATE FUNCTION pg_temp.normalize_identity_part(value VARCHAR) RETURNS TEXT AS $$
+  SELECT translate(
+    btrim(regexp_replace(value, '\s+', ' ', 'g')),
+    'ABCDEFGHIJKLMNOPQRSTUVWXYZ',
+    'abcdefghijklmnopqrstuvwxyz'
+  )
+$$ LANGUAGE SQL IMMUTABLE;
+
+ALTER TABLE search_items ALTER COLUMN track_id TYPE VARCHAR USING (
+  CASE
+    WHEN isrc IS NOT NULL AND isrc <> '' THEN 'isrc:' || upper(btrim(isrc))
+    ELSE 'meta:' || left(encode(sha256(convert_to(
+      pg_temp.normalize_identity_part(track) || chr(31) ||
+      pg_temp.normalize_identity_part(artist) || chr(31) ||
+      pg_temp.normalize_identity_part(album),
+      'UTF8')), 'hex'), 16)
+  END
+);
+
+-- Every run used to insert the same tracks again. Keep the oldest row per
+-- identity and point the history at it before enforcing uniqueness.
+CREATE TEMPORARY TABLE search_item_canonical AS
+SELECT id, min(id) OVER (PARTITION BY track_id) AS canonical_id
+FROM search_items;
+
+UPDATE judge_submissions js
+SET track = c.canonical_id
+FROM search_item_canonical c
+WHERE js.track = c.id AND c.id <> c.canonical_id;
+
+DELETE FROM search_items si
+USING search_item_canonical c
+WHERE si.id = c.id AND c.id <> c.canonical_id;
+
+DROP TABLE search_item_canonical;
+
+CREATE UNIQUE INDEX search_items_track_id_key ON search_items (track_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE downloaded_file DROP COLUMN submission;
+
+DROP TABLE source_tracks;
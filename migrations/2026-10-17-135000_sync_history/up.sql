@@ -0,0 +1,21 @@
+-- Tracks each source listed when it was last synced, so `sync` can tell
+-- which ones left it.
+CREATE TABLE IF NOT EXISTS source_tracks (
+  source varchar not null,
+  track_id varchar not null,
+  primary key (source, track_id)
+);
+
+-- The submission a file was downloaded for, since peers share file names.
+ALTER TABLE downloaded_file
+  ADD COLUMN submission int references judge_submissions(id) on delete set null;
+
+-- Older downloads only kept the file name, so link them to the latest
+-- submission of that file.
+UPDATE downloaded_file d
+SET submission = (
+  SELECT max(js.id)
+  FROM judge_submissions js
+  JOIN downloadable_files df ON df.id = js.query
+  WHERE df.filename = d.filename
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS run_tracks;
+DROP TABLE IF EXISTS runs;
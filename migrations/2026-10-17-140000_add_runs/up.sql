@@ -0,0 +1,14 @@
+-- One row per invocation, so `status` and `export` can report per run.
+CREATE TABLE IF NOT EXISTS runs (
+  id serial not null primary key,
+  run_id varchar not null unique,
+  command varchar not null,
+  started_at timestamp not null default now(),
+  finished_at timestamp
+);
+
+CREATE TABLE IF NOT EXISTS run_tracks (
+  run int references runs(id) on delete cascade not null,
+  track int references search_items(id) on delete cascade not null,
+  primary key (run, track)
+);
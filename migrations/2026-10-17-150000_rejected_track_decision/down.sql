@@ -0,0 +1,4 @@
+ALTER TABLE rejected_track
+  DROP COLUMN judge,
+  DROP COLUMN score,
+  DROP COLUMN threshold;
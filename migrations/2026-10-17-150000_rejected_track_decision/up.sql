@@ -0,0 +1,9 @@
+-- Record which judge rejected a file, its score and the threshold in force.
+ALTER TABLE rejected_track
+  ADD COLUMN judge varchar,
+  ADD COLUMN score real,
+  ADD COLUMN threshold real;
+
+UPDATE rejected_track
+SET score = value::real
+WHERE reason = 'low_score' AND value ~ '^-?[0-9.]+(e-?[0-9]+)?$';
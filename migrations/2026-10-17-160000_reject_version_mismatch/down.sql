@@ -0,0 +1,8 @@
+-- Enum values cannot be dropped, so rebuild the type without it.
+DELETE FROM rejected_track WHERE reason = 'version_mismatch';
+
+ALTER TYPE reject_reason RENAME TO reject_reason_old;
+CREATE TYPE reject_reason AS ENUM ('already_downloaded', 'low_score', 'not_music', 'abandoned_attempting_search');
+ALTER TABLE rejected_track
+  ALTER COLUMN reason TYPE reject_reason USING reason::text::reject_reason;
+DROP TYPE reject_reason_old;
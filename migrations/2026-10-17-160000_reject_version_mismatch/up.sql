@@ -0,0 +1,3 @@
+-- Files rejected because their version (live, remix, karaoke...) differs
+-- from the query's. `value` holds `file:<qualifier>` or `query:<qualifier>`.
+ALTER TYPE reject_reason ADD VALUE IF NOT EXISTS 'version_mismatch';
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE downloadable_files
+  DROP COLUMN bitrate,
+  DROP COLUMN duration_secs,
+  DROP COLUMN sample_rate,
+  DROP COLUMN bit_depth,
+  DROP COLUMN vbr;
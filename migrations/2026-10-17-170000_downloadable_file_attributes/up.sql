@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE downloadable_files
+  ADD COLUMN bitrate INTEGER,
+  ADD COLUMN duration_secs INTEGER,
+  ADD COLUMN sample_rate INTEGER,
+  ADD COLUMN bit_depth INTEGER,
+  ADD COLUMN vbr BOOLEAN;
@@ -0,0 +1,3 @@
+-- Files the judge could not score, e.g. because the LLM kept failing.
+-- `value` holds the error.
+ALTER TYPE reject_reason ADD VALUE IF NOT EXISTS 'judge_failed';
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE downloadable_files
+  DROP COLUMN upload_speed,
+  DROP COLUMN free_slot;
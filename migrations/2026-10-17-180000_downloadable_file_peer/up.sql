@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE downloadable_files
+  ADD COLUMN upload_speed INTEGER,
+  ADD COLUMN free_slot BOOLEAN;
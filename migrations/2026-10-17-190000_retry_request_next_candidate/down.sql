@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE retry_request DROP COLUMN next_candidate;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- The candidate downloaded after this failure, null when the track was
+-- searched again or abandoned.
+ALTER TABLE retry_request
+  ADD COLUMN next_candidate INTEGER REFERENCES downloadable_files(id);
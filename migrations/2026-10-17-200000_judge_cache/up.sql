@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS judge_cache (
+  track_id TEXT NOT NULL,
+  filename TEXT NOT NULL,
+  judge TEXT NOT NULL,
+  fingerprint TEXT NOT NULL,
+  score REAL NOT NULL,
+  -- The whole `JudgeDecision`, returned as is on a hit.
+  decision JSONB NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  PRIMARY KEY (track_id, filename, judge, fingerprint)
+);
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rejected_track DROP COLUMN verdict;
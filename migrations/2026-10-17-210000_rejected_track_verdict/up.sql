@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE rejected_track ADD COLUMN verdict JSONB;
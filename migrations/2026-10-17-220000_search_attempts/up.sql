@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- One row per query variant searched, and whether it found the track.
+CREATE TABLE IF NOT EXISTS search_attempts (
+  id serial not null primary key,
+  track int references search_items(id) on delete cascade not null,
+  run int references runs(id) on delete set null,
+  position int not null,
+  strategy varchar not null,
+  query varchar not null,
+  accepted boolean not null,
+  attempted_at timestamp not null default now()
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE retry_request DROP COLUMN next;
+DROP TYPE retry_next;
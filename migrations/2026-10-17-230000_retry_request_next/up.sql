@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- What a failed download led to. Older rows without a next candidate cannot
+-- tell searching again from giving up, and are taken to have searched.
+CREATE TYPE retry_next AS ENUM ('fallback', 'search', 'abandon');
+ALTER TABLE retry_request
+  ADD COLUMN next retry_next NOT NULL DEFAULT 'search';
+UPDATE retry_request SET next = 'fallback' WHERE next_candidate IS NOT NULL;
+ALTER TABLE retry_request ALTER COLUMN next DROP DEFAULT;
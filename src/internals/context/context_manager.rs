@@ -1,61 +1,131 @@
-use crate::internals::database::manager::DatabaseManager;
-use diesel::prelude::*;
+use crate::internals::database::{establish_connection_to, judge_cache::PgDecisionStore};
 use serde::{Deserialize, Serialize};
 use soulseek_rs::{Client, ClientSettings};
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{sync::Arc, time::Duration};
 use tokio::{
     sync::{
         RwLock, Semaphore,
         mpsc::{self, Receiver, Sender},
+        watch,
     },
     task::{JoinHandle, JoinSet},
 };
 use tracing::instrument;
 
-use anyhow::Context;
+use anyhow::{Context, bail};
 
 use crate::internals::{
     download::download_manager::DownloadManager,
-    judge::{judge_manager::JudgeManager, judges::levenshtein::Levenshtein},
+    judge::{
+        judge_manager::{BatchSettings, Judge, JudgeDecision, JudgeManager},
+        judges::{
+            cache::JudgeCache,
+            composite::{CompositeJudge, CompositeMember},
+            levenshtein::Levenshtein,
+            llm::LlmJudge,
+            plausibility::PlausibilityJudge,
+            token::TokenJudge,
+            version::{VersionFilter, VersionMismatch},
+        },
+    },
+    network::network_manager::{SearchBackend, TransferBackend},
     query::query_manager::QueryManager,
-    search::search_manager::{DownloadableFile, JudgeSubmission, SearchItem, SearchManager},
-    utils::config::config_manager::Config,
+    search::{
+        query_planner::{QueryPlanner, SearchAttempt, SearchPlan},
+        search_manager::{DownloadableFile, JudgeSubmission, SearchItem, SearchManager},
+    },
+    selection::selection_manager::{SelectionEvent, SelectionManager, SelectionSettings},
+    utils::config::config_manager::{Config, JudgeKind},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadedFile {
     pub filename: String,
+    /// The candidate the file was downloaded for.
+    pub submission: Box<JudgeSubmission>,
 }
 
-#[derive(Debug)]
+/// What happens to a track after one of its downloads failed.
+#[derive(Debug, Clone)]
+pub enum RetryNext {
+    /// Download the next ranked candidate, from a peer that has not failed.
+    Fallback(Box<JudgeSubmission>),
+    /// The candidates are exhausted, search for the track again.
+    Search,
+    /// Already searched again, give up on the track.
+    Abandon,
+}
+
+/// One link of a track's chain of failed downloads.
+#[derive(Debug, Clone)]
 pub struct RetryRequest {
     pub request: JudgeSubmission,
+    /// Failed downloads of the track so far, this one included.
     pub retry_attempts: u8,
     pub failed_download_result: DownloadableFile,
+    pub next: RetryNext,
 }
 
-#[derive(Debug)]
+/// A file the judge accepted, waiting to be ranked against the others.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub submission: JudgeSubmission,
+    pub decision: JudgeDecision,
+}
+
+#[derive(Debug, Clone)]
 pub enum Track {
     Query(SearchItem),
     Result(JudgeSubmission),
+    /// Accepted by the judge, goes through selection.
+    Candidate(Candidate),
+    /// The search for the plan's current variant sent its last result.
+    SearchDone(SearchPlan),
+    /// Whether a searched variant found the track. The next variant is
+    /// searched when it did not.
+    Attempt(SearchAttempt),
+    /// Chosen for download.
     Downloadable(JudgeSubmission),
+    /// The download failed, goes back through selection.
+    Failed(JudgeSubmission),
     File(DownloadedFile),
     Retry(RetryRequest),
     Reject(RejectedTrack),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Where a run records the tracks passing through it.
+pub trait TrackStore {
+    fn record(&mut self, track: &Track) -> anyhow::Result<()>;
+}
+
+/// Tracks kept in the order they were recorded, for runs without a database.
+#[derive(Debug, Default)]
+pub struct MemoryTrackStore {
+    pub tracks: Vec<Track>,
+}
+
+impl TrackStore for MemoryTrackStore {
+    fn record(&mut self, track: &Track) -> anyhow::Result<()> {
+        self.tracks.push(track.clone());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RejectedTrack {
     track: JudgeSubmission,
     reason: RejectReason,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RejectReason {
     AlreadyDownloaded,
-    LowScore(f32),
+    LowScore(JudgeDecision),
     NotMusic(String),
     AbandonedAttemptingSearch,
+    VersionMismatch(VersionMismatch),
+    /// The judge failed on the file, with the error.
+    JudgeFailed(String),
 }
 
 impl RejectedTrack {
@@ -68,6 +138,97 @@ impl RejectedTrack {
     }
 }
 
+/// The judge selected by `config.judge`, with its configured cutoff.
+pub fn build_judge(config: &Config) -> anyhow::Result<Box<dyn Judge>> {
+    match config.judge {
+        JudgeKind::Composite => {
+            let composite = &config.composite;
+            let members = composite
+                .judges
+                .iter()
+                .map(|member| {
+                    Ok(
+                        CompositeMember::new(build_single_judge(member.kind, config)?)
+                            .with_weight(member.weight)
+                            .with_ambiguous(member.ambiguous),
+                    )
+                })
+                .collect::<anyhow::Result<_>>()?;
+            let judge = CompositeJudge::new(members, composite.strategy, composite.threshold)
+                .context("Invalid composite judge")?;
+            Ok(Box::new(judge))
+        }
+        kind => build_single_judge(kind, config),
+    }
+}
+
+fn build_single_judge(kind: JudgeKind, config: &Config) -> anyhow::Result<Box<dyn Judge>> {
+    let judge: Box<dyn Judge> = match kind {
+        JudgeKind::Levenshtein => Box::new(Levenshtein::new(
+            config.judge_score_levenshtein.unwrap_or(0.75),
+        )),
+        JudgeKind::Composite => bail!("composite judges cannot be nested"),
+        JudgeKind::Llm => {
+            let prompt = config.llm_prompt().context("Invalid LLM judge")?;
+            Box::new(
+                LlmJudge::new(
+                    config.llm_base_url.clone(),
+                    config.llm_model.clone(),
+                    config.judge_score_llm.unwrap_or(0.5),
+                )
+                .with_api_key(config.llm_api_key.clone())
+                .with_prompt(prompt)
+                .with_timeout(Duration::from_secs(config.llm_timeout_secs))
+                .with_max_retries(config.llm_max_retries),
+            )
+        }
+        JudgeKind::Token => Box::new(TokenJudge::new(config.judge_score_token.unwrap_or(0.8))),
+        JudgeKind::Plausibility => Box::new(build_plausibility_judge(config)),
+    };
+    Ok(judge)
+}
+
+/// The configured judge behind the version and plausibility checks a run
+/// makes first.
+pub fn build_judge_manager(config: &Config) -> anyhow::Result<JudgeManager> {
+    let version_filter = config
+        .versions
+        .enabled
+        .then(|| VersionFilter::new(&config.versions.keywords));
+    let plausibility = config
+        .plausibility
+        .enabled
+        .then(|| build_plausibility_judge(config));
+    Ok(JudgeManager::new(build_judge(config)?)
+        .with_version_filter(version_filter)
+        .with_plausibility(plausibility))
+}
+
+pub fn build_plausibility_judge(config: &Config) -> PlausibilityJudge {
+    let plausibility = &config.plausibility;
+    PlausibilityJudge::new(plausibility.threshold)
+        .with_duration_tolerance_secs(plausibility.duration_tolerance_secs)
+        .with_min_bitrate_kbps(plausibility.min_bitrate_kbps)
+}
+
+/// The judge cache when enabled, on a connection of its own.
+pub fn build_judge_cache(config: &Config) -> anyhow::Result<Option<JudgeCache>> {
+    if !config.judge_cache.enabled {
+        return Ok(None);
+    }
+    let database_url = config
+        .database_url
+        .as_deref()
+        .context("database_url must be set")?;
+    let connection = establish_connection_to(database_url).context("Connecting judge cache")?;
+    let fingerprint = config
+        .judge_fingerprint()
+        .context("Fingerprinting judge settings")?;
+    let cache = JudgeCache::new(Arc::new(PgDecisionStore::new(connection)), fingerprint)
+        .with_by_size(config.uses_judge(JudgeKind::Plausibility));
+    Ok(Some(cache))
+}
+
 pub trait Manager {
     fn run(self) -> anyhow::Result<()>;
 }
@@ -77,11 +238,16 @@ pub async fn send(message: Track, chan: &Sender<Track>) -> anyhow::Result<()> {
 }
 
 pub struct Managers {
-    pub client: Arc<Client>,
+    /// Also signed in through, at the start of a run.
+    pub search_backend: Arc<dyn SearchBackend>,
     pub download_manager: DownloadManager,
     pub search_manager: SearchManager,
+    pub query_planner: QueryPlanner,
     pub query_manager: QueryManager,
     pub judge_manager: JudgeManager,
+    pub search_concurrency: usize,
+    pub download_concurrency: usize,
+    pub selection: SelectionSettings,
 }
 
 #[derive(Debug)]
@@ -115,36 +281,98 @@ pub enum QueuePriority {
     RetryRun(JoinHandle<anyhow::Result<()>>),
 }
 
+/// Counts the work of a cycle that may still send to its channel: spawned
+/// searches and downloads, events queued for selection or batch judging,
+/// and selection deadlines not yet reached. The cycle ends at zero.
+#[derive(Debug, Clone, Default)]
+pub struct PendingWork(Arc<watch::Sender<usize>>);
+
+impl PendingWork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts one piece of work until the returned token is dropped. Drop
+    /// it only after the work has sent everything it is going to send.
+    pub fn start(&self) -> WorkToken {
+        self.0.send_modify(|count| *count += 1);
+        WorkToken(Arc::clone(&self.0))
+    }
+
+    /// Resolves once no work is outstanding.
+    pub async fn settled(&self) {
+        let mut count = self.0.subscribe();
+        // The sender lives in `self`, so this cannot fail.
+        let _ = count.wait_for(|count| *count == 0).await;
+    }
+}
+
+/// One piece of outstanding work, see `PendingWork`.
+#[derive(Debug)]
+pub struct WorkToken(Arc<watch::Sender<usize>>);
+
+impl Drop for WorkToken {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
 impl Managers {
-    pub fn new(score: Option<f32>, path: PathBuf, config: Config) -> Self {
+    /// Connects to Soulseek with the configured account.
+    pub fn new(config: Config) -> anyhow::Result<Self> {
         let client_settings = ClientSettings {
-            username: config.user_name,
-            password: config.user_password,
+            username: config.user_name.clone(),
+            password: config.user_password.clone(),
             listen_port: config.listen_port,
             ..Default::default()
         };
         let mut client = Client::with_settings(client_settings);
         client.connect();
         let client = Arc::new(client);
-        let download_manager = DownloadManager::new(client.clone(), path);
-        let search_manager = SearchManager::new(client.clone());
-        let lev_judge = Levenshtein::new(score.unwrap_or(0.75));
-        let judge_manager = JudgeManager::new(Box::new(lev_judge));
-        let query_manager = QueryManager::new(
-            "1B3Q6EB9Pjb57jKywHJPfq?si=2f36139519544813",
-            config.client_id,
-            config.client_secret,
-        );
-        Managers {
-            client,
+        Managers::with_backends(config, client.clone(), client)
+    }
+    /// Searches and downloads through the given backends instead of Soulseek.
+    pub fn with_backends(
+        config: Config,
+        search_backend: Arc<dyn SearchBackend>,
+        transfer_backend: Arc<dyn TransferBackend>,
+    ) -> anyhow::Result<Self> {
+        let batch = (config.judge_batch_size > 1).then(|| BatchSettings {
+            size: config.judge_batch_size,
+            window: Duration::from_millis(config.judge_batch_window_ms),
+            concurrency: config.judge_concurrency,
+        });
+        let judge_manager = build_judge_manager(&config)?.with_batch(batch);
+        let download_manager = DownloadManager::new(transfer_backend, config.download_root)
+            .with_download_timeout(Duration::from_secs(config.download_timeout_secs));
+        let search_manager = SearchManager::new(search_backend.clone())
+            .with_search_timeout(Duration::from_secs(config.search_timeout_secs))
+            .with_idle_timeout(Duration::from_secs(config.search_idle_timeout_secs));
+        let query_manager =
+            QueryManager::new(config.sources, config.client_id, config.client_secret)
+                .with_redirect_uri(config.redirect_uri);
+        Ok(Managers {
+            search_backend,
             download_manager,
             search_manager,
+            query_planner: QueryPlanner::from(&config.query_plan),
             judge_manager,
             query_manager,
-        }
+            selection: SelectionSettings::from(&config.selection),
+            search_concurrency: config.search_concurrency,
+            download_concurrency: config.download_concurrency,
+        })
     }
-    pub async fn get_playlist(&self) -> Vec<Track> {
-        self.query_manager.clone().fetch_playlist().await.unwrap()
+    pub fn with_judge_cache(mut self, cache: Option<JudgeCache>) -> Self {
+        self.judge_manager = self.judge_manager.with_cache(cache);
+        self
+    }
+    pub async fn get_playlist(&self) -> anyhow::Result<Vec<Track>> {
+        self.query_manager
+            .clone()
+            .fetch()
+            .await
+            .context("Fetching playlist")
     }
     pub async fn inject_tracks(
         track_chunk: impl IntoIterator<Item = Track>,
@@ -156,47 +384,106 @@ impl Managers {
         Ok(sender)
     }
 
-    #[instrument(name = "run-cyle", skip(self, sender, receiver, connection))]
+    #[instrument(name = "run-cyle", skip(self, sender, receiver, store))]
     pub async fn run_cycle(
         self,
         sender: Sender<Track>,
         mut receiver: Receiver<Track>,
-        connection: &mut PgConnection,
+        store: &mut dyn TrackStore,
     ) -> anyhow::Result<()> {
         let managers = Arc::new(self);
-        let mut database_manager = DatabaseManager::new(connection);
 
-        managers.client.login().context("Could not connect")?;
+        managers
+            .search_backend
+            .login()
+            .context("Could not connect")?;
         let sender = Arc::new(sender);
         let storage = Vec::new();
         let state = Arc::new(RwLock::new(storage));
         let (task_sender, task_receiver) = mpsc::channel(300);
 
-        let search_semaphore = Arc::new(Semaphore::new(4));
-        let download_semaphore = Arc::new(Semaphore::new(5));
+        let search_semaphore = Arc::new(Semaphore::new(managers.search_concurrency));
+        let download_semaphore = Arc::new(Semaphore::new(managers.download_concurrency));
         let task_manager: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
             await_pending_tasks(task_receiver)
                 .await
                 .context("Awaiting tasks")?;
             Ok(())
         });
+        let pending = PendingWork::new();
+        let (selection_queue, selection_receiver) = mpsc::channel(20000);
+        {
+            let mut selection_manager = SelectionManager::new(managers.selection.clone());
+            let sender = Arc::clone(&sender);
+            let pending = pending.clone();
+            let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
+                selection_manager
+                    .run(selection_receiver, sender, pending)
+                    .await
+                    .context("Selecting candidates")?;
+                Ok(())
+            });
+            task_sender
+                .send(QueuePriority::NormalRun(handle))
+                .await
+                .context("Submitting task to queue")?;
+        }
+        let judge_queue = match managers.judge_manager.batch {
+            Some(settings) => {
+                let (judge_sender, judge_receiver) = mpsc::channel(20000);
+                let judge_manager = Arc::new(managers.judge_manager.clone());
+                let sender = Arc::clone(&sender);
+                let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
+                    judge_manager
+                        .run_batches(settings, judge_receiver, sender)
+                        .await
+                        .context("Judging batches")?;
+                    Ok(())
+                });
+                task_sender
+                    .send(QueuePriority::NormalRun(handle))
+                    .await
+                    .context("Submitting task to queue")?;
+                Some(judge_sender)
+            }
+            None => None,
+        };
 
-        while let Some(track) = receiver.recv().await {
+        loop {
+            let track = tokio::select! {
+                biased;
+                track = receiver.recv() => match track {
+                    Some(track) => track,
+                    None => break,
+                },
+                // Work sends before it settles, so anything it sent is
+                // already queued by now.
+                _ = pending.settled() => match receiver.try_recv() {
+                    Ok(track) => track,
+                    Err(_) => break,
+                },
+            };
             tracing::info!(?track, "Incoming package");
             let task_queue = task_sender.clone();
-            database_manager
-                .load_item_to_database(&track)
-                .context("Load into database")?;
+            store.record(&track).context("Load into database")?;
             match track {
                 Track::Query(search_item) => {
                     let managers = Arc::clone(&managers);
                     let sender = Arc::clone(&sender);
                     let semaphore = search_semaphore.clone();
                     tracing::info!(?search_item, "Enter search_item");
+                    let plan = managers.query_planner.plan(&search_item);
+                    let work = pending.start();
                     let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
+                        let _work = work;
                         managers
                             .search_manager
-                            .run(search_item, 0, semaphore, sender)
+                            .run(
+                                plan,
+                                managers.search_manager.idle_timeout,
+                                semaphore,
+                                sender,
+                            )
                             .await
                             .context("returning track")?
                             .await
@@ -211,6 +498,13 @@ impl Managers {
                         .context("Submitting task to queue")?;
                 }
                 Track::Result(judge_submission) => {
+                    if let Some(judge_queue) = &judge_queue {
+                        judge_queue
+                            .send((judge_submission, pending.start()))
+                            .await
+                            .context("Queueing for batch judging")?;
+                        continue;
+                    }
                     let managers = Arc::clone(&managers);
                     let sender = Arc::clone(&sender);
                     let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
@@ -224,6 +518,71 @@ impl Managers {
                     });
                     handle.await.context("handle-revisar")?.context("inner")?;
                 }
+                Track::Candidate(candidate) => {
+                    selection_queue
+                        .send((
+                            SelectionEvent::Candidate(Box::new(candidate)),
+                            pending.start(),
+                        ))
+                        .await
+                        .context("Queueing for selection")?;
+                }
+                Track::SearchDone(plan) => {
+                    selection_queue
+                        .send((SelectionEvent::SearchDone(Box::new(plan)), pending.start()))
+                        .await
+                        .context("Queueing for selection")?;
+                }
+                Track::Attempt(attempt) => {
+                    let variant = attempt.plan.variant();
+                    if attempt.accepted {
+                        tracing::info!(
+                            track_id = attempt.plan.item.track_id,
+                            strategy = %variant.strategy,
+                            query = variant.query,
+                            "query variant found the track"
+                        );
+                        continue;
+                    }
+                    let Some(next) = attempt.plan.next() else {
+                        tracing::info!(
+                            track_id = attempt.plan.item.track_id,
+                            "no query variant found an acceptable candidate"
+                        );
+                        continue;
+                    };
+                    tracing::info!(
+                        track_id = next.item.track_id,
+                        strategy = %next.variant().strategy,
+                        query = next.variant().query,
+                        "searching the next query variant"
+                    );
+                    let managers = Arc::clone(&managers);
+                    let sender = Arc::clone(&sender);
+                    let semaphore = search_semaphore.clone();
+                    let work = pending.start();
+                    let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
+                        let _work = work;
+                        managers
+                            .search_manager
+                            .run(
+                                next,
+                                managers.search_manager.idle_timeout,
+                                semaphore,
+                                sender,
+                            )
+                            .await
+                            .context("returning track")?
+                            .await
+                            .context("inner")?
+                            .context("one more")?;
+                        Ok(())
+                    });
+                    task_queue
+                        .send(QueuePriority::NormalRun(handle))
+                        .await
+                        .context("Submitting task to queue")?;
+                }
                 Track::Downloadable(judge_submission) => {
                     let semaphore = download_semaphore.clone();
                     let managers = Arc::clone(&managers);
@@ -231,7 +590,9 @@ impl Managers {
                     tracing::info!(?judge_submission, "Enter downloadable");
                     let judge_sub = judge_submission.clone();
                     if !state.read().await.contains(&judge_submission.track) {
+                        let work = pending.start();
                         let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
+                            let _work = work;
                             managers
                                 .download_manager
                                 .run(judge_sub, semaphore, sender)
@@ -258,29 +619,59 @@ impl Managers {
                 Track::File(downloaded_file) => {
                     tracing::info!(?downloaded_file, "Downloaded file");
                 }
-                Track::Retry(mut retry_request) => {
-                    if retry_request.retry_attempts >= 1 {
-                        let reject = RejectedTrack::new(
-                            retry_request.request,
-                            RejectReason::AbandonedAttemptingSearch,
-                        );
-                        send(Track::Reject(reject), &sender)
-                            .await
-                            .context("rejecting")?;
-                        continue;
+                Track::Failed(judge_submission) => {
+                    selection_queue
+                        .send((
+                            SelectionEvent::DownloadFailed(Box::new(judge_submission)),
+                            pending.start(),
+                        ))
+                        .await
+                        .context("Queueing for selection")?;
+                }
+                Track::Retry(retry_request) => {
+                    // The track may be downloaded again.
+                    state
+                        .write()
+                        .await
+                        .retain(|track| *track != retry_request.request.track);
+                    match &retry_request.next {
+                        RetryNext::Fallback(fallback) => {
+                            tracing::info!(?fallback, "Falling back");
+                            send(Track::Downloadable(*fallback.clone()), &sender)
+                                .await
+                                .context("sending fallback")?;
+                            continue;
+                        }
+                        RetryNext::Abandon => {
+                            let reject = RejectedTrack::new(
+                                retry_request.request,
+                                RejectReason::AbandonedAttemptingSearch,
+                            );
+                            send(Track::Reject(reject), &sender)
+                                .await
+                                .context("rejecting")?;
+                            continue;
+                        }
+                        RetryNext::Search => {}
                     }
-                    retry_request.retry_attempts += 1;
                     let managers = Arc::clone(&managers);
                     let semaphore = search_semaphore.clone();
                     let sender = Arc::clone(&sender);
                     tracing::info!(?retry_request.request, "Retry zone");
-                    let search_item = retry_request.request.clone();
+                    let plan = managers.query_planner.plan(&retry_request.request.track);
+                    // Searching again waits longer for slow peers.
+                    let idle_timeout = managers.search_manager.idle_timeout * 2;
+                    let work = pending.start();
                     let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
+                        let _work = work;
                         managers
                             .search_manager
-                            .run(search_item.track, 1, semaphore, sender)
+                            .run(plan, idle_timeout, semaphore, sender)
+                            .await
+                            .context("returning track")?
                             .await
-                            .context("returning track")?;
+                            .context("inner")?
+                            .context("one more")?;
                         Ok(())
                     });
                     task_queue
@@ -292,6 +683,11 @@ impl Managers {
                 Track::Reject(_rejected_track) => {}
             };
         }
+        // Closing the queues ends the batcher and selection, and with the
+        // task queue closed the task manager returns once both have.
+        drop(judge_queue);
+        drop(selection_queue);
+        drop(task_sender);
         task_manager.await.context("Awaiting")?.context("Inner")?;
         Ok(())
     }
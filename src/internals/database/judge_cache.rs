@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+
+use anyhow::Context;
+use diesel::{PgConnection, dsl::insert_into, prelude::*};
+
+use crate::internals::{
+    database::{model, schema},
+    judge::{
+        judge_manager::JudgeDecision,
+        judges::cache::{CacheKey, DecisionStore},
+    },
+};
+
+/// Judge decisions in the `judge_cache` table, on a connection of its own
+/// since judges run outside the main loop.
+pub struct PgDecisionStore {
+    connection: Mutex<PgConnection>,
+}
+
+impl PgDecisionStore {
+    pub fn new(connection: PgConnection) -> Self {
+        PgDecisionStore {
+            connection: Mutex::new(connection),
+        }
+    }
+}
+
+impl DecisionStore for PgDecisionStore {
+    fn get(&self, key: &CacheKey) -> anyhow::Result<Option<JudgeDecision>> {
+        use schema::judge_cache::dsl as jc;
+        let connection = &mut *self.connection.lock().expect("poisoned");
+        let decision: Option<serde_json::Value> = schema::judge_cache::table
+            .find((&key.track_id, &key.filename, &key.judge, &key.fingerprint))
+            .select(jc::decision)
+            .first(connection)
+            .optional()
+            .context("fetch cached decision")?;
+        decision
+            .map(serde_json::from_value)
+            .transpose()
+            .context("parse cached decision")
+    }
+
+    fn put(&self, key: &CacheKey, decision: &JudgeDecision) -> anyhow::Result<()> {
+        use schema::judge_cache::dsl as jc;
+        let connection = &mut *self.connection.lock().expect("poisoned");
+        let value = model::NewJudgeCacheRow {
+            track_id: &key.track_id,
+            filename: &key.filename,
+            judge: &key.judge,
+            fingerprint: &key.fingerprint,
+            score: decision.score,
+            decision: serde_json::to_value(decision).context("serialize decision")?,
+        };
+        insert_into(schema::judge_cache::table)
+            .values(&value)
+            .on_conflict((jc::track_id, jc::filename, jc::judge, jc::fingerprint))
+            .do_update()
+            .set((
+                jc::score.eq(value.score),
+                jc::decision.eq(&value.decision),
+                jc::created_at.eq(diesel::dsl::now),
+            ))
+            .execute(connection)
+            .context("Insert cached decision")?;
+        Ok(())
+    }
+
+    fn invalidate(&self, judge: &str, fingerprint: &str) -> anyhow::Result<usize> {
+        use schema::judge_cache::dsl as jc;
+        let connection = &mut *self.connection.lock().expect("poisoned");
+        diesel::delete(
+            schema::judge_cache::table
+                .filter(jc::judge.eq(judge))
+                .filter(jc::fingerprint.ne(fingerprint)),
+        )
+        .execute(connection)
+        .context("Invalidate cached decisions")
+    }
+}
@@ -1,34 +1,88 @@
 use anyhow::{Context, Ok};
 use diesel::{PgConnection, dsl::insert_into, prelude::*};
+use std::collections::{HashMap, HashSet};
 
-use crate::internals::context::context_manager::{RejectedTrack, RetryRequest, Track};
+use crate::internals::context::context_manager::{
+    RejectedTrack, RetryNext, RetryRequest, Track, TrackStore,
+};
 use crate::internals::database::{model, schema};
+use crate::internals::search::query_planner::SearchAttempt;
 use crate::internals::search::search_manager::{
     DownloadableFile as RuntimeDownloadableFile, JudgeSubmission as RuntimeJudgeSubmission,
     SearchItem as RuntimeSearchItem,
 };
 pub struct DatabaseManager<'a> {
     pub connection: &'a mut PgConnection,
+    /// Row id in `runs` that queued tracks are attributed to.
+    pub run: Option<i32>,
+}
+
+impl TrackStore for DatabaseManager<'_> {
+    fn record(&mut self, track: &Track) -> anyhow::Result<()> {
+        self.load_item_to_database(track)
+    }
 }
 
 impl<'a> DatabaseManager<'a> {
     pub fn new(connection: &'a mut PgConnection) -> Self {
-        Self { connection }
+        Self {
+            connection,
+            run: None,
+        }
+    }
+
+    pub fn with_run(mut self, run: Option<i32>) -> Self {
+        self.run = run;
+        self
     }
 
     fn insert_search_item(
         connection: &mut PgConnection,
         search_item: &RuntimeSearchItem,
     ) -> anyhow::Result<i32> {
+        use schema::search_items::dsl as sl;
         let value = model::NewSearchItemRow::from(search_item);
+        // The same track shows up on every run, so refresh its metadata
+        // instead of inserting a duplicate. A row stored under an older key is
+        // moved to the current one.
+        if let Some(existing) = Self::find_search_item(connection, search_item)? {
+            diesel::update(schema::search_items::table.find(existing))
+                .set(&value)
+                .execute(connection)
+                .context("Update search item")?;
+            return Ok(existing);
+        }
         let inserted_id = insert_into(schema::search_items::table)
             .values(&value)
+            .on_conflict(sl::track_id)
+            .do_update()
+            .set(&value)
             .returning(schema::search_items::id)
             .get_result(connection)
             .context("Insert search item")?;
         Ok(inserted_id)
     }
 
+    /// Row of the track under any of its [`RuntimeSearchItem::identities`],
+    /// preferring the current key.
+    fn find_search_item(
+        connection: &mut PgConnection,
+        search_item: &RuntimeSearchItem,
+    ) -> anyhow::Result<Option<i32>> {
+        use schema::search_items::dsl as sl;
+        let identities = search_item.identities();
+        let rows: Vec<(i32, String)> = schema::search_items::table
+            .filter(sl::track_id.eq_any(&identities))
+            .select((sl::id, sl::track_id))
+            .load(connection)
+            .context("fetch search item by identity")?;
+        Ok(identities.iter().find_map(|identity| {
+            rows.iter()
+                .find(|(_, track_id)| track_id == identity)
+                .map(|(id, _)| *id)
+        }))
+    }
+
     fn insert_downloadable_file(
         connection: &mut PgConnection,
         downloadable_file: &RuntimeDownloadableFile,
@@ -79,18 +133,27 @@ impl<'a> DatabaseManager<'a> {
         judge_submission: &RuntimeJudgeSubmission,
     ) -> anyhow::Result<i32> {
         use schema::judge_submissions::dsl as js;
-        use schema::search_items::dsl as si;
-        let search_id: i32 = schema::search_items::table
-            .filter(si::track_id.eq(judge_submission.track.track_id))
-            .select(si::id)
-            .get_result(connection)
+        let search_id = Self::get_search_item_id(connection, &judge_submission.track)
             .context("fetch search id from db JSGet")?;
+        // A track has a submission per candidate, so match the file too.
         let judge_id = schema::judge_submissions::table
+            .inner_join(schema::downloadable_files::table)
             .filter(js::track.eq(search_id))
+            .filter(schema::downloadable_files::filename.eq(&judge_submission.query.filename))
+            .filter(schema::downloadable_files::username.eq(&judge_submission.query.username))
+            .order(js::id.desc())
             .select(js::id)
-            .get_result(connection)
+            .first(connection)
+            .optional()
             .context("fetch judge id from db JSGET")?;
-        Ok(judge_id)
+        judge_id.with_context(|| {
+            format!(
+                "No submission of {} for {} from {}",
+                judge_submission.track.track_id,
+                judge_submission.query.filename,
+                judge_submission.query.username
+            )
+        })
     }
 
     fn insert_retry_request(
@@ -100,10 +163,18 @@ impl<'a> DatabaseManager<'a> {
         let request_id = Self::get_judge_submission_id(connection, &retry_request.request)?;
         let failed_download_result =
             Self::get_downloadable_file_id(connection, &retry_request.failed_download_result)?;
+        let next_candidate = match &retry_request.next {
+            RetryNext::Fallback(fallback) => {
+                Some(Self::get_downloadable_file_id(connection, &fallback.query)?)
+            }
+            RetryNext::Search | RetryNext::Abandon => None,
+        };
         let value = model::NewRetryRequestRow {
             request: request_id,
             retry_attempts: i32::from(retry_request.retry_attempts),
             failed_download_result,
+            next_candidate,
+            next: model::RetryNextRow::from(&retry_request.next),
         };
         insert_into(schema::retry_request::table)
             .values(&value)
@@ -125,31 +196,385 @@ impl<'a> DatabaseManager<'a> {
             .context("Insert rejected track")?;
         Ok(())
     }
+    fn insert_search_attempt(
+        connection: &mut PgConnection,
+        attempt: &SearchAttempt,
+        run: Option<i32>,
+    ) -> anyhow::Result<()> {
+        let track = Self::get_search_item_id(connection, &attempt.plan.item)?;
+        let variant = attempt.plan.variant();
+        let value = model::NewSearchAttemptRow {
+            track,
+            run,
+            position: i32::try_from(attempt.plan.position).unwrap_or(i32::MAX),
+            strategy: variant.strategy.as_str(),
+            query: &variant.query,
+            accepted: attempt.accepted,
+        };
+        insert_into(schema::search_attempts::table)
+            .values(&value)
+            .execute(connection)
+            .context("Insert search attempt")?;
+        Ok(())
+    }
     fn get_search_item_id(
         connection: &mut PgConnection,
         search_item: &RuntimeSearchItem,
     ) -> anyhow::Result<i32> {
+        Self::find_search_item(connection, search_item)?
+            .context("database fetch search_id in get seatch id func")
+    }
+
+    /// Identity of every track ever queued.
+    pub fn known_track_ids(&mut self) -> anyhow::Result<HashSet<String>> {
         use schema::search_items::dsl as sl;
-        let search_id = schema::search_items::table
-            .filter(sl::track_id.eq(search_item.track_id))
-            .select(schema::search_items::id)
-            .get_result(connection)
-            .context("database fetch search_id in get seatch id func")?;
-        Ok(search_id)
+        let ids: Vec<String> = schema::search_items::table
+            .select(sl::track_id)
+            .load(self.connection)
+            .context("fetch known track ids")?;
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Identity of every track with at least one completed download.
+    pub fn downloaded_track_ids(&mut self) -> anyhow::Result<HashSet<String>> {
+        use schema::search_items::dsl as sl;
+        let ids: Vec<String> = schema::downloaded_file::table
+            .inner_join(schema::judge_submissions::table.inner_join(schema::search_items::table))
+            .select(sl::track_id)
+            .distinct()
+            .load(self.connection)
+            .context("fetch downloaded track ids")?;
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Identity of every track `source` listed when it was last synced.
+    pub fn source_track_ids(&mut self, source: &str) -> anyhow::Result<HashSet<String>> {
+        use schema::source_tracks::dsl as st;
+        let ids: Vec<String> = schema::source_tracks::table
+            .filter(st::source.eq(source))
+            .select(st::track_id)
+            .load(self.connection)
+            .context("fetch source track ids")?;
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Replaces the tracks recorded for `source` with `track_ids`.
+    pub fn set_source_tracks(&mut self, source: &str, track_ids: &[String]) -> anyhow::Result<()> {
+        use schema::source_tracks::dsl as st;
+        let rows: Vec<_> = track_ids
+            .iter()
+            .map(|track_id| (st::source.eq(source), st::track_id.eq(track_id)))
+            .collect();
+        self.connection
+            .transaction::<_, anyhow::Error, _>(|connection| {
+                diesel::delete(schema::source_tracks::table.filter(st::source.eq(source)))
+                    .execute(connection)
+                    .context("Clear source tracks")?;
+                insert_into(schema::source_tracks::table)
+                    .values(&rows)
+                    .on_conflict_do_nothing()
+                    .execute(connection)
+                    .context("Insert source tracks")?;
+                Ok(())
+            })
+            .context("Record source tracks")
+    }
+
+    /// Records the start of a run, resetting it when `run_id` is reused.
+    pub fn start_run(&mut self, run_id: &str, command: &str) -> anyhow::Result<i32> {
+        use schema::runs::dsl as rn;
+        let value = model::NewRunRow {
+            run_id: run_id.to_string(),
+            command: command.to_string(),
+        };
+        let run = insert_into(schema::runs::table)
+            .values(&value)
+            .on_conflict(rn::run_id)
+            .do_update()
+            .set((
+                rn::command.eq(&value.command),
+                rn::started_at.eq(diesel::dsl::now),
+                rn::finished_at.eq(None::<chrono::NaiveDateTime>),
+            ))
+            .returning(rn::id)
+            .get_result(self.connection)
+            .context("Insert run")?;
+        diesel::delete(schema::run_tracks::table.filter(schema::run_tracks::run.eq(run)))
+            .execute(self.connection)
+            .context("Reset run tracks")?;
+        Ok(run)
+    }
+
+    pub fn finish_run(&mut self, run: i32) -> anyhow::Result<()> {
+        use schema::runs::dsl as rn;
+        diesel::update(schema::runs::table.find(run))
+            .set(rn::finished_at.eq(diesel::dsl::now))
+            .execute(self.connection)
+            .context("Finish run")?;
+        Ok(())
+    }
+
+    /// Most recent runs first.
+    pub fn list_runs(&mut self, limit: i64) -> anyhow::Result<Vec<model::RunRow>> {
+        use schema::runs::dsl as rn;
+        schema::runs::table
+            .order(rn::started_at.desc())
+            .limit(limit)
+            .select(model::RunRow::as_select())
+            .load(self.connection)
+            .context("fetch runs")
+    }
+
+    pub fn run_summary(&mut self, run_id: &str) -> anyhow::Result<Option<model::RunSummary>> {
+        use schema::judge_submissions::dsl as js;
+        use schema::run_tracks::dsl as rt;
+        use schema::runs::dsl as rn;
+        use schema::search_attempts::dsl as sa;
+        let Some(run) = schema::runs::table
+            .filter(rn::run_id.eq(run_id))
+            .select(model::RunRow::as_select())
+            .first(self.connection)
+            .optional()
+            .context("fetch run")?
+        else {
+            return Ok(None);
+        };
+        let tracks: Vec<i32> = schema::run_tracks::table
+            .filter(rt::run.eq(run.id))
+            .select(rt::track)
+            .load(self.connection)
+            .context("fetch run tracks")?;
+        let downloaded: HashSet<i32> = schema::downloaded_file::table
+            .inner_join(schema::judge_submissions::table)
+            .filter(js::track.eq_any(&tracks))
+            .select(js::track)
+            .load::<i32>(self.connection)
+            .context("fetch downloaded run tracks")?
+            .into_iter()
+            .collect();
+        let rejected: HashSet<i32> = schema::rejected_track::table
+            .inner_join(schema::judge_submissions::table)
+            .filter(js::track.eq_any(&tracks))
+            .select(js::track)
+            .load::<i32>(self.connection)
+            .context("fetch rejected run tracks")?
+            .into_iter()
+            .collect();
+        let retried: HashSet<i32> = schema::retry_request::table
+            .inner_join(schema::judge_submissions::table)
+            .filter(js::track.eq_any(&tracks))
+            .select(js::track)
+            .load::<i32>(self.connection)
+            .context("fetch retried run tracks")?
+            .into_iter()
+            .collect();
+        let mut found_by: Vec<(String, i64)> = schema::search_attempts::table
+            .filter(sa::run.eq(run.id))
+            .filter(sa::accepted)
+            .group_by(sa::strategy)
+            .select((sa::strategy, diesel::dsl::count_star()))
+            .load(self.connection)
+            .context("fetch search attempts")?;
+        found_by.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        Ok(Some(model::RunSummary {
+            run,
+            tracks: tracks.len(),
+            downloaded: downloaded.len(),
+            rejected: rejected.len(),
+            retried: retried.len(),
+            found_by,
+        }))
+    }
+
+    /// Every queued track, or only the ones queued by `run_id`, with the
+    /// file it was downloaded as, if any.
+    pub fn track_history(
+        &mut self,
+        run_id: Option<&str>,
+    ) -> anyhow::Result<Vec<model::TrackHistory>> {
+        use schema::run_tracks::dsl as rt;
+        use schema::runs::dsl as rn;
+        use schema::search_items::dsl as sl;
+        let mut query = schema::search_items::table
+            .order(sl::id)
+            .select(model::SearchItemRow::as_select())
+            .into_boxed();
+        if let Some(run_id) = run_id {
+            query = query.filter(
+                sl::id.eq_any(
+                    schema::run_tracks::table
+                        .inner_join(schema::runs::table)
+                        .filter(rn::run_id.eq(run_id.to_string()))
+                        .select(rt::track),
+                ),
+            );
+        }
+        let rows = query.load(self.connection).context("fetch search items")?;
+        let files: HashMap<i32, String> = schema::downloaded_file::table
+            .inner_join(schema::judge_submissions::table)
+            .select((
+                schema::judge_submissions::track,
+                schema::downloaded_file::filename,
+            ))
+            .load::<(i32, String)>(self.connection)
+            .context("fetch downloaded files")?
+            .into_iter()
+            .collect();
+        Ok(rows
+            .into_iter()
+            .map(|row| model::TrackHistory {
+                downloaded_file: files.get(&row.id).cloned(),
+                item: row.into(),
+            })
+            .collect())
+    }
+
+    /// Every candidate ever judged, once per track, file and peer, with
+    /// whether that file was downloaded.
+    pub fn judged_submissions(&mut self) -> anyhow::Result<Vec<(RuntimeJudgeSubmission, bool)>> {
+        use schema::judge_submissions::dsl as js;
+        let rows: Vec<(model::SearchItemRow, model::DownloadableFileRow)> =
+            schema::judge_submissions::table
+                .inner_join(schema::search_items::table)
+                .inner_join(schema::downloadable_files::table)
+                .order(js::id)
+                .select((
+                    model::SearchItemRow::as_select(),
+                    model::DownloadableFileRow::as_select(),
+                ))
+                .load(self.connection)
+                .context("fetch judged submissions")?;
+        let downloaded: HashSet<(i32, String, String)> = schema::downloaded_file::table
+            .inner_join(
+                schema::judge_submissions::table.inner_join(schema::downloadable_files::table),
+            )
+            .select((
+                js::track,
+                schema::downloadable_files::filename,
+                schema::downloadable_files::username,
+            ))
+            .load(self.connection)
+            .context("fetch downloaded files")?
+            .into_iter()
+            .collect();
+        let mut seen = HashSet::new();
+        Ok(rows
+            .into_iter()
+            .filter(|(item, file)| {
+                seen.insert((item.id, file.filename.clone(), file.username.clone()))
+            })
+            .map(|(item, file)| {
+                let was_downloaded =
+                    downloaded.contains(&(item.id, file.filename.clone(), file.username.clone()));
+                let submission = RuntimeJudgeSubmission {
+                    track: item.into(),
+                    query: file.into(),
+                };
+                (submission, was_downloaded)
+            })
+            .collect())
+    }
+
+    /// Rejected files, newest first, optionally only for tracks queued by
+    /// `run_id` or whose title or artist contains `track`.
+    pub fn rejections(
+        &mut self,
+        run_id: Option<&str>,
+        track: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<RejectedTrack>> {
+        use schema::rejected_track::dsl as rj;
+        use schema::run_tracks::dsl as rt;
+        use schema::runs::dsl as rn;
+        use schema::search_items::dsl as sl;
+        let mut query = schema::rejected_track::table
+            .inner_join(
+                schema::judge_submissions::table
+                    .inner_join(schema::search_items::table)
+                    .inner_join(schema::downloadable_files::table),
+            )
+            .order(rj::id.desc())
+            .limit(limit)
+            .select((
+                model::RejectedTrackRow::as_select(),
+                model::JudgeSubmissionRow::as_select(),
+                model::SearchItemRow::as_select(),
+                model::DownloadableFileRow::as_select(),
+            ))
+            .into_boxed();
+        if let Some(run_id) = run_id {
+            query = query.filter(
+                sl::id.eq_any(
+                    schema::run_tracks::table
+                        .inner_join(schema::runs::table)
+                        .filter(rn::run_id.eq(run_id.to_string()))
+                        .select(rt::track),
+                ),
+            );
+        }
+        if let Some(track) = track {
+            let pattern = format!("%{track}%");
+            query = query.filter(
+                sl::track
+                    .ilike(pattern.clone())
+                    .or(sl::artist.ilike(pattern)),
+            );
+        }
+        let rows: Vec<(
+            model::RejectedTrackRow,
+            model::JudgeSubmissionRow,
+            model::SearchItemRow,
+            model::DownloadableFileRow,
+        )> = query.load(self.connection).context("fetch rejections")?;
+        Ok(rows
+            .into_iter()
+            .map(|(row, submission, track, query)| {
+                model::RejectedTrackJoined {
+                    row,
+                    track: model::JudgeSubmissionJoined {
+                        row: submission,
+                        track,
+                        query,
+                    },
+                }
+                .into()
+            })
+            .collect())
+    }
+
+    /// Tracks queued at some point that never got a completed download.
+    pub fn undownloaded_search_items(&mut self) -> anyhow::Result<Vec<RuntimeSearchItem>> {
+        Ok(self
+            .track_history(None)?
+            .into_iter()
+            .filter(|history| history.downloaded_file.is_none())
+            .map(|history| history.item)
+            .collect())
     }
 
     pub fn load_item_to_database(&mut self, item: &Track) -> anyhow::Result<()> {
+        let run = self.run;
         self.connection
             .transaction::<_, anyhow::Error, _>(|connection| {
                 match item {
                     Track::Query(search_item) => {
-                        Self::insert_search_item(connection, search_item)?;
+                        let track = Self::insert_search_item(connection, search_item)?;
+                        if let Some(run) = run {
+                            insert_into(schema::run_tracks::table)
+                                .values(model::NewRunTrackRow { run, track })
+                                .on_conflict_do_nothing()
+                                .execute(connection)
+                                .context("Insert run track")?;
+                        }
                     }
                     Track::Result(judge_submission) | Track::Downloadable(judge_submission) => {
                         Self::insert_judge_submission(connection, judge_submission)?;
                     }
                     Track::File(downloaded_file) => {
-                        let value = model::NewDownloadedFileRow::from(downloaded_file);
+                        let submission =
+                            Self::get_judge_submission_id(connection, &downloaded_file.submission)?;
+                        let value =
+                            model::NewDownloadedFileRow::from_runtime(submission, downloaded_file);
                         insert_into(schema::downloaded_file::table)
                             .values(value)
                             .execute(connection)
@@ -161,6 +586,12 @@ impl<'a> DatabaseManager<'a> {
                     Track::Reject(rejected_track) => {
                         Self::insert_rejected_track(connection, rejected_track)?;
                     }
+                    Track::Attempt(attempt) => {
+                        Self::insert_search_attempt(connection, attempt, run)?;
+                    }
+                    // Stored when they arrived as `Result`, failures once
+                    // selection turned them into a `Retry`.
+                    Track::Candidate(_) | Track::SearchDone(_) | Track::Failed(_) => {}
                 }
                 Ok(())
             })
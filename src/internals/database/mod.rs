@@ -1,7 +1,9 @@
+use anyhow::Context;
 use diesel::prelude::*;
 use dotenvy::dotenv;
 use std::env;
 
+pub mod judge_cache;
 pub mod manager;
 pub mod model;
 pub mod schema;
@@ -12,3 +14,7 @@ pub fn establish_connection() -> PgConnection {
     PgConnection::establish(&database_url)
         .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
 }
+
+pub fn establish_connection_to(database_url: &str) -> anyhow::Result<PgConnection> {
+    PgConnection::establish(database_url).context("Connecting to database")
+}
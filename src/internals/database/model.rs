@@ -1,7 +1,9 @@
 use std::io::Write;
 
+use chrono::NaiveDateTime;
 use diesel::{
-    AsExpression, Associations, FromSqlRow, Identifiable, Insertable, Queryable, Selectable,
+    AsChangeset, AsExpression, Associations, FromSqlRow, Identifiable, Insertable, Queryable,
+    Selectable,
     deserialize::{self, FromSql},
     pg::{Pg, PgValue},
     serialize::{self, IsNull, Output, ToSql},
@@ -10,9 +12,10 @@ use serde::{Deserialize, Serialize};
 
 use crate::internals::{
     context::context_manager::{
-        DownloadedFile, RejectReason as RuntimeRejectReason, RejectedTrack, RetryRequest,
+        DownloadedFile, RejectReason as RuntimeRejectReason, RejectedTrack, RetryNext, RetryRequest,
     },
     database::schema::{self, sql_types},
+    judge::{judge_manager::JudgeDecision, judges::version::VersionMismatch},
     search::search_manager::{
         DownloadableFile as RuntimeDownloadableFile, JudgeSubmission as RuntimeJudgeSubmission,
         SearchItem as RuntimeSearchItem,
@@ -23,28 +26,46 @@ use crate::internals::{
 #[diesel(table_name = schema::search_items)]
 pub struct SearchItemRow {
     pub id: i32,
-    pub track_id: i32,
+    pub track_id: String,
     pub track: String,
     pub artist: String,
     pub album: String,
+    pub artists: Vec<Option<String>>,
+    pub duration_ms: Option<i32>,
+    pub isrc: Option<String>,
+    pub track_number: Option<i32>,
+    pub disc_number: Option<i32>,
+    pub spotify_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Insertable)]
+#[derive(Debug, Clone, Insertable, AsChangeset)]
 #[diesel(table_name = schema::search_items)]
 pub struct NewSearchItemRow {
-    pub track_id: i32,
+    pub track_id: String,
     pub track: String,
     pub artist: String,
     pub album: String,
+    pub artists: Vec<Option<String>>,
+    pub duration_ms: Option<i32>,
+    pub isrc: Option<String>,
+    pub track_number: Option<i32>,
+    pub disc_number: Option<i32>,
+    pub spotify_id: Option<String>,
 }
 
 impl From<&RuntimeSearchItem> for NewSearchItemRow {
     fn from(value: &RuntimeSearchItem) -> Self {
         Self {
-            track_id: value.track_id,
+            track_id: value.track_id.clone(),
             track: value.track.clone(),
             artist: value.artist.clone(),
             album: value.album.clone(),
+            artists: value.artists.iter().cloned().map(Some).collect(),
+            duration_ms: value.duration_ms,
+            isrc: value.isrc.clone(),
+            track_number: value.track_number,
+            disc_number: value.disc_number,
+            spotify_id: value.spotify_id.clone(),
         }
     }
 }
@@ -62,6 +83,12 @@ impl From<SearchItemRow> for RuntimeSearchItem {
             track: value.track,
             artist: value.artist,
             album: value.album,
+            artists: value.artists.into_iter().flatten().collect(),
+            duration_ms: value.duration_ms,
+            isrc: value.isrc,
+            track_number: value.track_number,
+            disc_number: value.disc_number,
+            spotify_id: value.spotify_id,
         }
     }
 }
@@ -73,6 +100,13 @@ pub struct DownloadableFileRow {
     pub filename: String,
     pub username: String,
     pub size: i32,
+    pub bitrate: Option<i32>,
+    pub duration_secs: Option<i32>,
+    pub sample_rate: Option<i32>,
+    pub bit_depth: Option<i32>,
+    pub vbr: Option<bool>,
+    pub upload_speed: Option<i32>,
+    pub free_slot: Option<bool>,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -81,6 +115,13 @@ pub struct NewDownloadableFileRow {
     pub filename: String,
     pub username: String,
     pub size: i32,
+    pub bitrate: Option<i32>,
+    pub duration_secs: Option<i32>,
+    pub sample_rate: Option<i32>,
+    pub bit_depth: Option<i32>,
+    pub vbr: Option<bool>,
+    pub upload_speed: Option<i32>,
+    pub free_slot: Option<bool>,
 }
 
 impl From<&RuntimeDownloadableFile> for NewDownloadableFileRow {
@@ -89,6 +130,13 @@ impl From<&RuntimeDownloadableFile> for NewDownloadableFileRow {
             filename: value.filename.clone(),
             username: value.username.clone(),
             size: value.size,
+            bitrate: value.bitrate,
+            duration_secs: value.duration_secs,
+            sample_rate: value.sample_rate,
+            bit_depth: value.bit_depth,
+            vbr: value.vbr,
+            upload_speed: value.upload_speed,
+            free_slot: value.free_slot,
         }
     }
 }
@@ -105,6 +153,13 @@ impl From<DownloadableFileRow> for RuntimeDownloadableFile {
             filename: value.filename,
             username: value.username,
             size: value.size,
+            bitrate: value.bitrate,
+            duration_secs: value.duration_secs,
+            sample_rate: value.sample_rate,
+            bit_depth: value.bit_depth,
+            vbr: value.vbr,
+            upload_speed: value.upload_speed,
+            free_slot: value.free_slot,
         }
     }
 }
@@ -147,32 +202,21 @@ impl From<JudgeSubmissionJoined> for RuntimeJudgeSubmission {
 pub struct DownloadedFileRow {
     pub id: i32,
     pub filename: String,
+    pub submission: Option<i32>,
 }
 
 #[derive(Debug, Clone, Insertable)]
 #[diesel(table_name = schema::downloaded_file)]
 pub struct NewDownloadedFileRow {
     pub filename: String,
+    pub submission: Option<i32>,
 }
 
-impl From<&DownloadedFile> for NewDownloadedFileRow {
-    fn from(value: &DownloadedFile) -> Self {
+impl NewDownloadedFileRow {
+    pub fn from_runtime(submission: i32, value: &DownloadedFile) -> Self {
         Self {
             filename: value.filename.clone(),
-        }
-    }
-}
-
-impl From<DownloadedFile> for NewDownloadedFileRow {
-    fn from(value: DownloadedFile) -> Self {
-        Self::from(&value)
-    }
-}
-
-impl From<DownloadedFileRow> for DownloadedFile {
-    fn from(value: DownloadedFileRow) -> Self {
-        Self {
-            filename: value.filename,
+            submission: Some(submission),
         }
     }
 }
@@ -186,6 +230,8 @@ pub struct RetryRequestRow {
     pub request: i32,
     pub retry_attempts: i32,
     pub failed_download_result: i32,
+    pub next_candidate: Option<i32>,
+    pub next: RetryNextRow,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -194,6 +240,8 @@ pub struct NewRetryRequestRow {
     pub request: i32,
     pub retry_attempts: i32,
     pub failed_download_result: i32,
+    pub next_candidate: Option<i32>,
+    pub next: RetryNextRow,
 }
 
 #[derive(Debug, Clone)]
@@ -201,14 +249,73 @@ pub struct RetryRequestJoined {
     pub row: RetryRequestRow,
     pub request: JudgeSubmissionJoined,
     pub failed_download_result: DownloadableFileRow,
+    pub next_candidate: Option<DownloadableFileRow>,
 }
 
 impl From<RetryRequestJoined> for RetryRequest {
     fn from(value: RetryRequestJoined) -> Self {
+        let request: RuntimeJudgeSubmission = value.request.into();
+        let next = match (value.row.next, value.next_candidate) {
+            (RetryNextRow::Fallback, Some(file)) => {
+                RetryNext::Fallback(Box::new(RuntimeJudgeSubmission {
+                    track: request.track.clone(),
+                    query: file.into(),
+                }))
+            }
+            (RetryNextRow::Abandon, _) => RetryNext::Abandon,
+            // A fallback whose candidate is gone can only be searched again.
+            (RetryNextRow::Fallback | RetryNextRow::Search, _) => RetryNext::Search,
+        };
         Self {
-            request: value.request.into(),
+            request,
             retry_attempts: value.row.retry_attempts as u8,
             failed_download_result: value.failed_download_result.into(),
+            next,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = sql_types::RetryNext)]
+pub enum RetryNextRow {
+    Fallback,
+    Search,
+    Abandon,
+}
+
+impl From<&RetryNext> for RetryNextRow {
+    fn from(value: &RetryNext) -> Self {
+        match value {
+            RetryNext::Fallback(_) => Self::Fallback,
+            RetryNext::Search => Self::Search,
+            RetryNext::Abandon => Self::Abandon,
+        }
+    }
+}
+
+impl ToSql<sql_types::RetryNext, Pg> for RetryNextRow {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let value = match self {
+            RetryNextRow::Fallback => b"fallback".as_slice(),
+            RetryNextRow::Search => b"search".as_slice(),
+            RetryNextRow::Abandon => b"abandon".as_slice(),
+        };
+        out.write_all(value)?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<sql_types::RetryNext, Pg> for RetryNextRow {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"fallback" => Ok(Self::Fallback),
+            b"search" => Ok(Self::Search),
+            b"abandon" => Ok(Self::Abandon),
+            unknown => Err(format!(
+                "Unrecognized retry_next value: {}",
+                String::from_utf8_lossy(unknown)
+            )
+            .into()),
         }
     }
 }
@@ -220,6 +327,8 @@ pub enum RejectReasonRow {
     LowScore,
     NotMusic,
     AbandonedAttemptingSearch,
+    VersionMismatch,
+    JudgeFailed,
 }
 
 impl From<&RuntimeRejectReason> for RejectReasonRow {
@@ -229,6 +338,8 @@ impl From<&RuntimeRejectReason> for RejectReasonRow {
             RuntimeRejectReason::LowScore(_) => Self::LowScore,
             RuntimeRejectReason::NotMusic(_) => Self::NotMusic,
             RuntimeRejectReason::AbandonedAttemptingSearch => Self::AbandonedAttemptingSearch,
+            RuntimeRejectReason::VersionMismatch(_) => Self::VersionMismatch,
+            RuntimeRejectReason::JudgeFailed(_) => Self::JudgeFailed,
         }
     }
 }
@@ -244,11 +355,41 @@ impl From<RejectReasonRow> for RuntimeRejectReason {
         match value {
             RejectReasonRow::AlreadyDownloaded => RuntimeRejectReason::AlreadyDownloaded,
             // Database enum intentionally drops payload details.
-            RejectReasonRow::LowScore => RuntimeRejectReason::LowScore(0.0),
+            RejectReasonRow::LowScore => RuntimeRejectReason::LowScore(JudgeDecision {
+                judge: String::new(),
+                score: 0.0,
+                threshold: 0.0,
+                accepted: false,
+                explanation: Default::default(),
+                parts: vec![],
+            }),
             RejectReasonRow::NotMusic => RuntimeRejectReason::NotMusic(String::new()),
             RejectReasonRow::AbandonedAttemptingSearch => {
                 RuntimeRejectReason::AbandonedAttemptingSearch
             }
+            RejectReasonRow::VersionMismatch => {
+                RuntimeRejectReason::VersionMismatch(VersionMismatch {
+                    qualifier: String::new(),
+                    in_file: true,
+                })
+            }
+            RejectReasonRow::JudgeFailed => RuntimeRejectReason::JudgeFailed(String::new()),
+        }
+    }
+}
+
+impl RejectReasonRow {
+    /// The reason with the payload stored in `rejected_track.verdict`.
+    pub fn with_verdict(self, verdict: Option<serde_json::Value>) -> Option<RuntimeRejectReason> {
+        let verdict = verdict?;
+        match self {
+            RejectReasonRow::LowScore => serde_json::from_value(verdict)
+                .ok()
+                .map(RuntimeRejectReason::LowScore),
+            RejectReasonRow::VersionMismatch => serde_json::from_value(verdict)
+                .ok()
+                .map(RuntimeRejectReason::VersionMismatch),
+            _ => None,
         }
     }
 }
@@ -260,6 +401,8 @@ impl ToSql<sql_types::RejectReason, Pg> for RejectReasonRow {
             RejectReasonRow::LowScore => b"low_score".as_slice(),
             RejectReasonRow::NotMusic => b"not_music".as_slice(),
             RejectReasonRow::AbandonedAttemptingSearch => b"abandoned_attempting_search".as_slice(),
+            RejectReasonRow::VersionMismatch => b"version_mismatch".as_slice(),
+            RejectReasonRow::JudgeFailed => b"judge_failed".as_slice(),
         };
         out.write_all(value)?;
         Ok(IsNull::No)
@@ -273,6 +416,8 @@ impl FromSql<sql_types::RejectReason, Pg> for RejectReasonRow {
             b"low_score" => Ok(Self::LowScore),
             b"not_music" => Ok(Self::NotMusic),
             b"abandoned_attempting_search" => Ok(Self::AbandonedAttemptingSearch),
+            b"version_mismatch" => Ok(Self::VersionMismatch),
+            b"judge_failed" => Ok(Self::JudgeFailed),
             unknown => Err(format!(
                 "Unrecognized reject_reason value: {}",
                 String::from_utf8_lossy(unknown)
@@ -289,6 +434,12 @@ pub struct RejectedTrackRow {
     pub id: i32,
     pub track: i32,
     pub reason: RejectReasonRow,
+    pub value: Option<String>,
+    pub judge: Option<String>,
+    pub score: Option<f32>,
+    pub threshold: Option<f32>,
+    /// The judge's decision or the version mismatch, as JSON.
+    pub verdict: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Insertable)]
@@ -297,19 +448,47 @@ pub struct NewRejectedTrackRow {
     pub track: i32,
     pub reason: RejectReasonRow,
     pub value: Option<String>,
+    pub judge: Option<String>,
+    pub score: Option<f32>,
+    pub threshold: Option<f32>,
+    /// The judge's decision or the version mismatch, as JSON.
+    pub verdict: Option<serde_json::Value>,
 }
 
 impl NewRejectedTrackRow {
     pub fn from_runtime(track_id: i32, value: &RejectedTrack) -> Self {
         let (_, reason) = value.parts();
+        let decision = match reason {
+            RuntimeRejectReason::LowScore(decision) => Some(decision),
+            _ => None,
+        };
         Self {
             track: track_id,
             reason: reason.into(),
             value: match reason {
                 RuntimeRejectReason::AlreadyDownloaded
                 | RuntimeRejectReason::AbandonedAttemptingSearch => None,
-                RuntimeRejectReason::LowScore(score) => Some(format!("{score}")),
+                RuntimeRejectReason::LowScore(decision) => Some(format!("{}", decision.score)),
                 RuntimeRejectReason::NotMusic(filename) => Some(filename.to_owned()),
+                RuntimeRejectReason::JudgeFailed(error) => Some(error.to_owned()),
+                RuntimeRejectReason::VersionMismatch(mismatch) => Some(format!(
+                    "{}:{}",
+                    if mismatch.in_file { "file" } else { "query" },
+                    mismatch.qualifier
+                )),
+            },
+            judge: match reason {
+                RuntimeRejectReason::VersionMismatch(_) => Some("version".to_string()),
+                _ => decision.map(|decision| decision.judge.clone()),
+            },
+            score: decision.map(|decision| decision.score),
+            threshold: decision.map(|decision| decision.threshold),
+            verdict: match reason {
+                RuntimeRejectReason::LowScore(decision) => serde_json::to_value(decision).ok(),
+                RuntimeRejectReason::VersionMismatch(mismatch) => {
+                    serde_json::to_value(mismatch).ok()
+                }
+                _ => None,
             },
         }
     }
@@ -323,6 +502,174 @@ pub struct RejectedTrackJoined {
 
 impl From<RejectedTrackJoined> for RejectedTrack {
     fn from(value: RejectedTrackJoined) -> Self {
-        RejectedTrack::new(value.track.into(), value.row.reason.into())
+        let row = value.row;
+        let reason = match (row.reason, row.score) {
+            _ if let Some(reason) = row.reason.with_verdict(row.verdict.clone()) => reason,
+            // Rows from before verdicts were stored.
+            (RejectReasonRow::LowScore, Some(score)) => {
+                let threshold = row.threshold.unwrap_or_default();
+                RuntimeRejectReason::LowScore(JudgeDecision {
+                    judge: row.judge.unwrap_or_default(),
+                    score,
+                    threshold,
+                    accepted: false,
+                    explanation: Default::default(),
+                    parts: vec![],
+                })
+            }
+            (RejectReasonRow::VersionMismatch, _) => {
+                let value = row.value.unwrap_or_default();
+                let (side, qualifier) = value.split_once(':').unwrap_or(("file", &value));
+                RuntimeRejectReason::VersionMismatch(VersionMismatch {
+                    qualifier: qualifier.to_string(),
+                    in_file: side != "query",
+                })
+            }
+            (RejectReasonRow::JudgeFailed, _) => {
+                RuntimeRejectReason::JudgeFailed(row.value.unwrap_or_default())
+            }
+            (reason, _) => reason.into(),
+        };
+        RejectedTrack::new(value.track.into(), reason)
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Identifiable, Serialize)]
+#[diesel(table_name = schema::runs)]
+pub struct RunRow {
+    pub id: i32,
+    pub run_id: String,
+    pub command: String,
+    pub started_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::runs)]
+pub struct NewRunRow {
+    pub run_id: String,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::run_tracks)]
+pub struct NewRunTrackRow {
+    pub run: i32,
+    pub track: i32,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::search_attempts)]
+pub struct NewSearchAttemptRow<'a> {
+    pub track: i32,
+    pub run: Option<i32>,
+    pub position: i32,
+    pub strategy: &'a str,
+    pub query: &'a str,
+    pub accepted: bool,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::judge_cache)]
+pub struct NewJudgeCacheRow<'a> {
+    pub track_id: &'a str,
+    pub filename: &'a str,
+    pub judge: &'a str,
+    pub fingerprint: &'a str,
+    pub score: f32,
+    pub decision: serde_json::Value,
+}
+
+/// Outcome counts for the tracks queued by one run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub run: RunRow,
+    pub tracks: usize,
+    pub downloaded: usize,
+    pub rejected: usize,
+    pub retried: usize,
+    /// Tracks found per query strategy, most first.
+    pub found_by: Vec<(String, i64)>,
+}
+
+/// A queued track and, when it finished, the file it was downloaded as.
+#[derive(Debug, Clone)]
+pub struct TrackHistory {
+    pub item: RuntimeSearchItem,
+    pub downloaded_file: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(id: i32, filename: &str) -> DownloadableFileRow {
+        DownloadableFileRow {
+            id,
+            filename: filename.to_string(),
+            username: "peer".to_string(),
+            size: 1,
+            bitrate: None,
+            duration_secs: None,
+            sample_rate: None,
+            bit_depth: None,
+            vbr: None,
+            upload_speed: None,
+            free_slot: None,
+        }
+    }
+
+    fn retry(next: RetryNextRow, next_candidate: Option<DownloadableFileRow>) -> RetryNext {
+        let track = SearchItemRow {
+            id: 1,
+            track_id: "spotify:1".to_string(),
+            track: "Creep".to_string(),
+            artist: "Radiohead".to_string(),
+            album: "Pablo Honey".to_string(),
+            artists: vec![],
+            duration_ms: None,
+            isrc: None,
+            track_number: None,
+            disc_number: None,
+            spotify_id: None,
+        };
+        let joined = RetryRequestJoined {
+            row: RetryRequestRow {
+                id: 1,
+                request: 1,
+                retry_attempts: 2,
+                failed_download_result: 1,
+                next_candidate: next_candidate.as_ref().map(|file| file.id),
+                next,
+            },
+            request: JudgeSubmissionJoined {
+                row: JudgeSubmissionRow {
+                    id: 1,
+                    track: 1,
+                    query: 1,
+                },
+                track,
+                query: file(1, "failed.mp3"),
+            },
+            failed_download_result: file(1, "failed.mp3"),
+            next_candidate,
+        };
+        RetryRequest::from(joined).next
+    }
+
+    #[test]
+    fn reads_back_what_a_failure_led_to() {
+        assert!(matches!(
+            retry(RetryNextRow::Abandon, None),
+            RetryNext::Abandon
+        ));
+        assert!(matches!(
+            retry(RetryNextRow::Search, None),
+            RetryNext::Search
+        ));
+        match retry(RetryNextRow::Fallback, Some(file(2, "next.flac"))) {
+            RetryNext::Fallback(fallback) => assert_eq!(fallback.query.filename, "next.flac"),
+            other => panic!("expected a fallback, got {other:?}"),
+        }
     }
 }
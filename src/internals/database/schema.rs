@@ -4,6 +4,10 @@ pub mod sql_types {
     #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "reject_reason"))]
     pub struct RejectReason;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "retry_next"))]
+    pub struct RetryNext;
 }
 
 diesel::table! {
@@ -12,6 +16,13 @@ diesel::table! {
         filename -> Varchar,
         username -> Varchar,
         size -> Int4,
+        bitrate -> Nullable<Int4>,
+        duration_secs -> Nullable<Int4>,
+        sample_rate -> Nullable<Int4>,
+        bit_depth -> Nullable<Int4>,
+        vbr -> Nullable<Bool>,
+        upload_speed -> Nullable<Int4>,
+        free_slot -> Nullable<Bool>,
     }
 }
 
@@ -19,6 +30,19 @@ diesel::table! {
     downloaded_file (id) {
         id -> Int4,
         filename -> Varchar,
+        submission -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    judge_cache (track_id, filename, judge, fingerprint) {
+        track_id -> Text,
+        filename -> Text,
+        judge -> Text,
+        fingerprint -> Text,
+        score -> Float4,
+        decision -> Jsonb,
+        created_at -> Timestamp,
     }
 }
 
@@ -39,39 +63,101 @@ diesel::table! {
         track -> Int4,
         reason -> RejectReason,
         value -> Nullable<Varchar>,
+        judge -> Nullable<Varchar>,
+        score -> Nullable<Float4>,
+        threshold -> Nullable<Float4>,
+        verdict -> Nullable<Jsonb>,
     }
 }
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::RetryNext;
+
     retry_request (id) {
         id -> Int4,
         request -> Int4,
         retry_attempts -> Int4,
         failed_download_result -> Int4,
+        next_candidate -> Nullable<Int4>,
+        next -> RetryNext,
+    }
+}
+
+diesel::table! {
+    run_tracks (run, track) {
+        run -> Int4,
+        track -> Int4,
+    }
+}
+
+diesel::table! {
+    runs (id) {
+        id -> Int4,
+        run_id -> Varchar,
+        command -> Varchar,
+        started_at -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    search_attempts (id) {
+        id -> Int4,
+        track -> Int4,
+        run -> Nullable<Int4>,
+        position -> Int4,
+        strategy -> Varchar,
+        query -> Varchar,
+        accepted -> Bool,
+        attempted_at -> Timestamp,
     }
 }
 
 diesel::table! {
     search_items (id) {
         id -> Int4,
-        track_id -> Int4,
+        track_id -> Varchar,
         track -> Varchar,
         artist -> Varchar,
         album -> Varchar,
+        artists -> Array<Nullable<Text>>,
+        duration_ms -> Nullable<Int4>,
+        isrc -> Nullable<Varchar>,
+        track_number -> Nullable<Int4>,
+        disc_number -> Nullable<Int4>,
+        spotify_id -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    source_tracks (source, track_id) {
+        source -> Varchar,
+        track_id -> Varchar,
     }
 }
 
+diesel::joinable!(downloaded_file -> judge_submissions (submission));
 diesel::joinable!(judge_submissions -> downloadable_files (query));
 diesel::joinable!(judge_submissions -> search_items (track));
 diesel::joinable!(rejected_track -> judge_submissions (track));
 diesel::joinable!(retry_request -> downloadable_files (failed_download_result));
 diesel::joinable!(retry_request -> judge_submissions (request));
+diesel::joinable!(run_tracks -> runs (run));
+diesel::joinable!(run_tracks -> search_items (track));
+diesel::joinable!(search_attempts -> runs (run));
+diesel::joinable!(search_attempts -> search_items (track));
 
 diesel::allow_tables_to_appear_in_same_query!(
     downloadable_files,
     downloaded_file,
+    judge_cache,
     judge_submissions,
     rejected_track,
     retry_request,
+    run_tracks,
+    runs,
+    search_attempts,
     search_items,
+    source_tracks,
 );
@@ -1,11 +1,10 @@
 use crate::internals::{
-    context::context_manager::{
-        DownloadedFile, RejectReason, RejectedTrack, RetryRequest, Track, send,
-    },
+    context::context_manager::{DownloadedFile, RejectReason, RejectedTrack, Track, send},
+    network::network_manager::TransferBackend,
     search::search_manager::JudgeSubmission,
 };
 use anyhow::Context;
-use soulseek_rs::{Client, DownloadStatus};
+use soulseek_rs::DownloadStatus;
 use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 use tokio::{
     sync::{Semaphore, mpsc::Sender},
@@ -18,31 +17,43 @@ fn is_audio_file(filename: String) -> bool {
 }
 
 pub struct DownloadManager {
-    client: Arc<Client>,
+    backend: Arc<dyn TransferBackend>,
     root_location: PathBuf,
+    download_timeout: Duration,
 }
 
 impl DownloadManager {
-    pub fn new(client: Arc<Client>, root_location: PathBuf) -> Self {
+    pub fn new(backend: Arc<dyn TransferBackend>, root_location: PathBuf) -> Self {
         DownloadManager {
-            client,
+            backend,
             root_location,
+            download_timeout: Duration::from_secs(60),
         }
     }
+    /// How long a transfer may go without a status update before it is retried.
+    pub fn with_download_timeout(mut self, download_timeout: Duration) -> Self {
+        self.download_timeout = download_timeout;
+        self
+    }
     pub async fn run(
         &self,
         track: JudgeSubmission,
         semaphore: Arc<Semaphore>,
         sender: Arc<Sender<Track>>,
     ) -> anyhow::Result<()> {
-        let client = Arc::clone(&self.client);
+        let backend = Arc::clone(&self.backend);
         let download_location = self.root_location.clone();
         if is_audio_file(track.query.filename.clone()) {
             let _permit = semaphore.acquire().await.context("acquiring semaphore")?;
             tracing::info!(track.query.filename, "send to download");
-            let track = download_track(track, download_location.clone(), client)
-                .await
-                .context("Downloading track")?;
+            let track = download_track(
+                track,
+                download_location.clone(),
+                backend,
+                self.download_timeout,
+            )
+            .await
+            .context("Downloading track")?;
             send(track, &sender).await.context("Sending to finish")?;
         } else {
             let reject = RejectedTrack::new(
@@ -61,31 +72,34 @@ impl DownloadManager {
     }
 }
 
-#[tracing::instrument(name = "DownloadManager::download_track", skip(song, path, client), fields(
+#[tracing::instrument(name = "DownloadManager::download_track", skip(song, path, backend), fields(
     id = song.track.track_id,
+    isrc = song.track.isrc,
+    duration_ms = song.track.duration_ms,
     song_name = song.query.filename,
     user_name = song.query.username,
 ))]
 async fn download_track(
     song: JudgeSubmission,
     path: PathBuf,
-    client: Arc<Client>,
+    backend: Arc<dyn TransferBackend>,
+    download_timeout: Duration,
 ) -> anyhow::Result<Track> {
     let song_path = PathBuf::from_str(&song.query.filename).context("Can't parse filename")?;
     let path = path.join(song_path.file_name().context("Cannot create file")?);
     let path_str = path.as_path().to_str().context("Non valid path")?;
-    let rec = client.download(
-        song.query.filename.clone(),
-        song.query.username.clone(),
+    let rec = backend.download(
+        &song.query.filename,
+        &song.query.username,
         song.query.size as u64,
-        path_str.to_string(),
+        path_str,
     )?;
     let span = tracing::info_span!("download_thread_inner");
     let download_handle: JoinHandle<anyhow::Result<Track>> =
         tokio::task::spawn_blocking(move || {
             let track = span.in_scope(|| {
                 loop {
-                    let status = rec.recv_timeout(Duration::from_secs(60));
+                    let status = rec.recv_timeout(download_timeout);
                     match status {
                         Ok(DownloadStatus::Queued) => continue,
                         Ok(DownloadStatus::InProgress {
@@ -104,16 +118,13 @@ async fn download_track(
                         }
                         Ok(DownloadStatus::Completed) => {
                             return Track::File(DownloadedFile {
-                                filename: song.query.filename,
+                                filename: song.query.filename.clone(),
+                                submission: Box::new(song),
                             });
                         }
                         Ok(DownloadStatus::Failed | DownloadStatus::TimedOut) | Err(_) => {
                             tracing::error!(?song, "Error descargando, se salio del loop");
-                            return Track::Retry(RetryRequest {
-                                request: song.clone(),
-                                retry_attempts: 0,
-                                failed_download_result: song.query,
-                            });
+                            return Track::Failed(song);
                         }
                         _ => continue,
                     }
@@ -0,0 +1,455 @@
+use std::{collections::BTreeMap, io::Read, path::Path};
+
+use anyhow::{Context, bail, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::internals::{
+    context::context_manager::RejectReason,
+    judge::judge_manager::{JudgeDecision, JudgeManager},
+    search::search_manager::{DownloadableFile, JudgeSubmission, SearchItem},
+};
+
+/// One candidate of a labeled dataset, flat so it reads the same from CSV
+/// and JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabeledRow {
+    pub track: String,
+    pub artist: String,
+    #[serde(default)]
+    pub album: String,
+    #[serde(default)]
+    pub duration_ms: Option<i32>,
+    /// Soulseek path of the candidate.
+    pub filename: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub size: Option<i32>,
+    #[serde(default)]
+    pub bitrate: Option<i32>,
+    #[serde(default)]
+    pub duration_secs: Option<i32>,
+    /// Whether the file is the track. Rows left empty are skipped.
+    #[serde(default)]
+    pub label: Option<bool>,
+}
+
+impl LabeledRow {
+    pub fn from_submission(submission: &JudgeSubmission, label: Option<bool>) -> Self {
+        let (track, file) = (&submission.track, &submission.query);
+        LabeledRow {
+            track: track.track.clone(),
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            duration_ms: track.duration_ms,
+            filename: file.filename.clone(),
+            username: Some(file.username.clone()),
+            size: Some(file.size),
+            bitrate: file.bitrate,
+            duration_secs: file.duration_secs,
+            label,
+        }
+    }
+
+    pub fn submission(&self) -> JudgeSubmission {
+        let track = SearchItem::new(self.track.clone(), self.album.clone(), self.artist.clone())
+            .with_duration_ms(self.duration_ms);
+        let username = self.username.clone().unwrap_or_default();
+        JudgeSubmission {
+            track,
+            query: DownloadableFile {
+                bitrate: self.bitrate,
+                duration_secs: self.duration_secs,
+                ..DownloadableFile::new(
+                    self.filename.clone(),
+                    username,
+                    self.size.unwrap_or_default(),
+                )
+            },
+        }
+    }
+}
+
+pub fn read_csv(reader: impl Read) -> anyhow::Result<Vec<LabeledRow>> {
+    csv::Reader::from_reader(reader)
+        .deserialize()
+        .enumerate()
+        .map(|(index, row)| row.with_context(|| format!("Reading csv row {}", index + 1)))
+        .collect()
+}
+
+pub fn read_json(reader: impl Read) -> anyhow::Result<Vec<LabeledRow>> {
+    serde_json::from_reader(reader).context("Reading json dataset")
+}
+
+/// Reads a `.csv` or `.json` dataset.
+pub fn load_dataset(path: &Path) -> anyhow::Result<Vec<LabeledRow>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => read_csv(file),
+        Some("json") => read_json(file),
+        _ => bail!("{} is neither .csv nor .json", path.display()),
+    }
+}
+
+/// Outcomes of accepting or rejecting labeled rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Confusion {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub true_negatives: usize,
+    pub false_negatives: usize,
+}
+
+impl Confusion {
+    /// `outcomes` pairs whether a row was accepted with its label.
+    pub fn of(outcomes: &[(bool, bool)]) -> Self {
+        let mut confusion = Confusion::default();
+        for &outcome in outcomes {
+            match outcome {
+                (true, true) => confusion.true_positives += 1,
+                (true, false) => confusion.false_positives += 1,
+                (false, false) => confusion.true_negatives += 1,
+                (false, true) => confusion.false_negatives += 1,
+            }
+        }
+        confusion
+    }
+
+    /// Accepting scores at or above `threshold`; `scored` pairs a score with
+    /// its label.
+    pub fn at(scored: &[(f32, bool)], threshold: f32) -> Self {
+        let outcomes: Vec<(bool, bool)> = scored
+            .iter()
+            .map(|&(score, label)| (score >= threshold, label))
+            .collect();
+        Self::of(&outcomes)
+    }
+
+    fn ratio(numerator: usize, denominator: usize) -> f32 {
+        if denominator == 0 {
+            0.0
+        } else {
+            numerator as f32 / denominator as f32
+        }
+    }
+    pub fn precision(&self) -> f32 {
+        Self::ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+    /// Also the true positive rate.
+    pub fn recall(&self) -> f32 {
+        Self::ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+    pub fn false_positive_rate(&self) -> f32 {
+        Self::ratio(
+            self.false_positives,
+            self.false_positives + self.true_negatives,
+        )
+    }
+    pub fn f1(&self) -> f32 {
+        let (precision, recall) = (self.precision(), self.recall());
+        if precision + recall == 0.0 {
+            0.0
+        } else {
+            2.0 * precision * recall / (precision + recall)
+        }
+    }
+}
+
+/// One threshold of the ROC sweep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RocPoint {
+    pub threshold: f32,
+    pub confusion: Confusion,
+}
+
+/// Thresholds from 0 to 1 in `steps` equal steps.
+pub fn roc(scored: &[(f32, bool)], steps: usize) -> Vec<RocPoint> {
+    let steps = steps.max(1);
+    (0..=steps)
+        .map(|step| {
+            let threshold = step as f32 / steps as f32;
+            RocPoint {
+                threshold,
+                confusion: Confusion::at(scored, threshold),
+            }
+        })
+        .collect()
+}
+
+/// Area under the ROC curve: how often a positive outscores a negative, ties
+/// counting half. `None` without both classes.
+pub fn auc(scored: &[(f32, bool)]) -> Option<f32> {
+    let positives: Vec<f32> = scored.iter().filter(|(_, l)| *l).map(|(s, _)| *s).collect();
+    let negatives: Vec<f32> = scored.iter().filter(|(_, l)| !l).map(|(s, _)| *s).collect();
+    if positives.is_empty() || negatives.is_empty() {
+        return None;
+    }
+    let mut wins = 0.0;
+    for positive in &positives {
+        for negative in &negatives {
+            wins += match positive.total_cmp(negative) {
+                std::cmp::Ordering::Greater => 1.0,
+                std::cmp::Ordering::Equal => 0.5,
+                std::cmp::Ordering::Less => 0.0,
+            };
+        }
+    }
+    Some(wins / (positives.len() * negatives.len()) as f32)
+}
+
+/// A judge's decisions over a dataset.
+#[derive(Debug)]
+pub struct JudgeReport {
+    pub judge: String,
+    /// The judge's own threshold, which composites may not decide by.
+    pub threshold: f32,
+    /// Index into the dataset and decision, for rows the judge decided.
+    pub decisions: Vec<(usize, JudgeDecision)>,
+    /// Rows the version or plausibility checks rejected before the judge.
+    pub prefiltered: Vec<(usize, RejectReason)>,
+    /// Rows the judge failed on, with the error.
+    pub errors: Vec<(usize, String)>,
+}
+
+impl JudgeReport {
+    /// Whether each labeled row was accepted, paired with its label.
+    pub fn outcomes(&self, rows: &[LabeledRow]) -> Vec<(bool, bool)> {
+        let decided = self
+            .decisions
+            .iter()
+            .map(|(index, decision)| (*index, decision.accepted));
+        let prefiltered = self.prefiltered.iter().map(|(index, _)| (*index, false));
+        decided
+            .chain(prefiltered)
+            .filter_map(|(index, accepted)| rows[index].label.map(|label| (accepted, label)))
+            .collect()
+    }
+
+    /// Raw scores for sweeping thresholds. Prefiltered rows score negative
+    /// infinity, since no threshold lets them through.
+    pub fn scored(&self, rows: &[LabeledRow]) -> Vec<(f32, bool)> {
+        let decided = self
+            .decisions
+            .iter()
+            .map(|(index, decision)| (*index, decision.score));
+        let prefiltered = self
+            .prefiltered
+            .iter()
+            .map(|(index, _)| (*index, f32::NEG_INFINITY));
+        decided
+            .chain(prefiltered)
+            .filter_map(|(index, score)| rows[index].label.map(|label| (score, label)))
+            .collect()
+    }
+
+    /// Rows decided against their label, prefiltered ones first and then
+    /// the most confidently wrong. Prefiltered rows have no decision.
+    pub fn misclassified<'a>(
+        &'a self,
+        rows: &'a [LabeledRow],
+    ) -> Vec<(&'a LabeledRow, Option<&'a JudgeDecision>)> {
+        let prefiltered = self
+            .prefiltered
+            .iter()
+            .filter(|(index, _)| rows[*index].label == Some(true))
+            .map(|(index, _)| (&rows[*index], None));
+        let mut wrong: Vec<(&LabeledRow, &JudgeDecision)> = self
+            .decisions
+            .iter()
+            .filter(|(index, decision)| rows[*index].label != Some(decision.accepted))
+            .map(|(index, decision)| (&rows[*index], decision))
+            .collect();
+        let confidence = |decision: &JudgeDecision| (decision.score - decision.threshold).abs();
+        wrong.sort_by(|(_, a), (_, b)| confidence(b).total_cmp(&confidence(a)));
+        prefiltered
+            .chain(
+                wrong
+                    .into_iter()
+                    .map(|(row, decision)| (row, Some(decision))),
+            )
+            .collect()
+    }
+}
+
+/// Decides the labeled rows the way a run does: the version and
+/// plausibility checks first, then each track's remaining candidates in one
+/// batch so judges that batch, like the LLM, are used as they are in a run.
+/// Rows the checks or the judge fail on are recorded as errors.
+pub async fn evaluate(manager: &JudgeManager, rows: &[LabeledRow]) -> JudgeReport {
+    let mut tracks: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let submissions: Vec<JudgeSubmission> = rows.iter().map(LabeledRow::submission).collect();
+    for (index, submission) in submissions.iter().enumerate() {
+        if rows[index].label.is_some() {
+            tracks
+                .entry(submission.track.track_id.clone())
+                .or_default()
+                .push(index);
+        }
+    }
+    let mut report = JudgeReport {
+        judge: manager.method.name().to_string(),
+        threshold: manager.method.policy().threshold,
+        decisions: vec![],
+        prefiltered: vec![],
+        errors: vec![],
+    };
+    for indices in tracks.into_values() {
+        let mut pending = vec![];
+        for index in indices {
+            match manager.prefilter(&submissions[index]).await {
+                Ok(Some(reason)) => report.prefiltered.push((index, reason)),
+                Ok(None) => pending.push(index),
+                Err(err) => report.errors.push((index, format!("{err:#}"))),
+            }
+        }
+        if pending.is_empty() {
+            continue;
+        }
+        let batch = pending.iter().map(|&i| submissions[i].clone()).collect();
+        let decisions = manager
+            .method
+            .decide_batch(batch)
+            .await
+            .and_then(|decisions| {
+                ensure!(
+                    decisions.len() == pending.len(),
+                    "judge returned {} decisions for {} candidates",
+                    decisions.len(),
+                    pending.len()
+                );
+                Ok(decisions)
+            });
+        match decisions {
+            Ok(decisions) => report.decisions.extend(pending.into_iter().zip(decisions)),
+            Err(err) => {
+                tracing::warn!(error = ?err, "judging evaluation batch");
+                let error = format!("{err:#}");
+                report
+                    .errors
+                    .extend(pending.into_iter().map(|index| (index, error.clone())));
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internals::judge::{
+        judge_manager::{AcceptancePolicy, Judge},
+        judges::{
+            composite::{CompositeJudge, CompositeMember, CompositeStrategy},
+            version::{VersionFilter, default_keywords},
+        },
+    };
+
+    const SCORED: [(f32, bool); 6] = [
+        (0.9, true),
+        (0.8, true),
+        (0.4, true),
+        (0.7, false),
+        (0.2, false),
+        (0.1, false),
+    ];
+
+    #[test]
+    fn confusion_at_threshold() {
+        let confusion = Confusion::at(&SCORED, 0.5);
+        assert_eq!(
+            confusion,
+            Confusion {
+                true_positives: 2,
+                false_positives: 1,
+                true_negatives: 2,
+                false_negatives: 1,
+            }
+        );
+        assert!((confusion.precision() - 2.0 / 3.0).abs() < 1e-6);
+        assert!((confusion.recall() - 2.0 / 3.0).abs() < 1e-6);
+        assert!((confusion.f1() - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn roc_spans_accept_all_to_reject_all() {
+        let points = roc(&SCORED, 10);
+        assert_eq!(points.len(), 11);
+        assert_eq!(points[0].confusion.recall(), 1.0);
+        assert_eq!(points[0].confusion.false_positive_rate(), 1.0);
+        assert_eq!(points[10].confusion.recall(), 0.0);
+    }
+
+    #[test]
+    fn auc_counts_ordered_pairs() {
+        // 8 of the 9 positive/negative pairs are ordered correctly.
+        assert!((auc(&SCORED).unwrap() - 8.0 / 9.0).abs() < 1e-6);
+        assert_eq!(auc(&[(0.5, true)]), None);
+    }
+
+    #[test]
+    fn reads_csv_and_json_rows() {
+        let csv = "track,artist,album,duration_ms,filename,username,size,bitrate,duration_secs,label\n\
+                   Creep,Radiohead,Pablo Honey,238000,a\\Creep.mp3,,,320,,true\n\
+                   Creep,Radiohead,,,b\\Creep (live).mp3,,,,,\n";
+        let rows = read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].label, Some(true));
+        assert_eq!(rows[0].bitrate, Some(320));
+        assert_eq!(rows[1].label, None);
+
+        let json = r#"[{"track": "Creep", "artist": "Radiohead", "filename": "a\\Creep.mp3", "label": false}]"#;
+        let rows = read_json(json.as_bytes()).unwrap();
+        assert_eq!(rows[0].label, Some(false));
+        assert_eq!(rows[0].submission().query.size, 0);
+    }
+
+    /// Scores every file 0.6 and accepts from 0.5.
+    struct Lenient;
+
+    #[async_trait::async_trait]
+    impl Judge for Lenient {
+        fn name(&self) -> &'static str {
+            "lenient"
+        }
+        fn policy(&self) -> AcceptancePolicy {
+            AcceptancePolicy::new(0.5)
+        }
+        async fn judge_score(&self, _submission: JudgeSubmission) -> anyhow::Result<f32> {
+            Ok(0.6)
+        }
+    }
+
+    #[tokio::test]
+    async fn evaluates_as_a_run_decides() {
+        // The cascade accepts by its member's policy, below its own 0.75.
+        let composite = CompositeJudge::new(
+            vec![CompositeMember::new(Box::new(Lenient))],
+            CompositeStrategy::Cascade,
+            0.75,
+        )
+        .unwrap();
+        let manager = JudgeManager::new(Box::new(composite))
+            .with_version_filter(Some(VersionFilter::new(&default_keywords())));
+        let row = |filename: &str, label: bool| LabeledRow {
+            label: Some(label),
+            ..LabeledRow::from_submission(&JudgeSubmission::creep(filename), None)
+        };
+        let rows = [row("a\\Creep.mp3", true), row("b\\Creep (Live).mp3", true)];
+
+        let report = evaluate(&manager, &rows).await;
+        assert_eq!(report.prefiltered.len(), 1);
+        assert_eq!(report.outcomes(&rows), [(true, true), (false, true)]);
+        assert_eq!(
+            report.scored(&rows),
+            [(0.6, true), (f32::NEG_INFINITY, true)]
+        );
+        assert_eq!(report.misclassified(&rows).len(), 1);
+    }
+}
@@ -0,0 +1 @@
+pub mod eval_manager;
@@ -1,56 +1,649 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{Context, ensure};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Sender;
+use tokio::{
+    sync::{
+        Semaphore,
+        mpsc::{Receiver, Sender},
+    },
+    task::{JoinError, JoinSet},
+    time::Instant,
+};
+use tracing::instrument;
 
 use crate::internals::{
-    context::context_manager::{RejectReason, RejectedTrack, Track, send},
+    context::context_manager::{Candidate, RejectReason, RejectedTrack, Track, WorkToken, send},
+    judge::judges::{
+        cache::{CachedJudge, JudgeCache},
+        plausibility::PlausibilityJudge,
+        version::VersionFilter,
+    },
     search::search_manager::JudgeSubmission,
 };
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct ResponseFormat {
-    pub score: Option<f32>,
-    pub query_song: Option<String>,
-    pub filename: Option<String>,
+/// When a judge's score is good enough to download the file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AcceptancePolicy {
+    /// Scores at or above this are accepted.
+    pub threshold: f32,
+}
+
+impl AcceptancePolicy {
+    pub fn new(threshold: f32) -> Self {
+        AcceptancePolicy { threshold }
+    }
+    pub fn accepts(&self, score: f32) -> bool {
+        score >= self.threshold
+    }
+}
+
+/// What a score is made of, as far as the judge can tell.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Explanation {
+    /// Score of each aspect in `[0, 1]`, e.g. `title`, `artist`, `album`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, f32>,
+    /// Query words found in the file's path.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matched: Vec<String>,
+    /// Query words that were not.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    /// Rules that lowered or settled the score, in words.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+}
+
+impl Explanation {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+            && self.matched.is_empty()
+            && self.missing.is_empty()
+            && self.rules.is_empty()
+    }
+    pub fn with_field(mut self, field: &str, score: f32) -> Self {
+        self.fields.insert(field.to_string(), score);
+        self
+    }
+    pub fn with_rule(mut self, rule: impl Into<String>) -> Self {
+        self.rules.push(rule.into());
+        self
+    }
+}
+
+/// A score and its explanation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Verdict {
+    pub score: f32,
+    pub explanation: Explanation,
+}
+
+impl Verdict {
+    pub fn new(score: f32) -> Self {
+        Verdict {
+            score,
+            explanation: Explanation::default(),
+        }
+    }
+    pub fn with_explanation(mut self, explanation: Explanation) -> Self {
+        self.explanation = explanation;
+        self
+    }
+}
+
+/// A judge's verdict on one submission, kept on rejections for tuning.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JudgeDecision {
+    pub judge: String,
+    pub score: f32,
+    pub threshold: f32,
+    pub accepted: bool,
+    /// Why the judge gave this score.
+    #[serde(default, skip_serializing_if = "Explanation::is_empty")]
+    pub explanation: Explanation,
+    /// Decisions of the judges this one combined, in the order they ran.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<JudgeDecision>,
+}
+
+impl JudgeDecision {
+    pub fn from_score(judge: &str, score: f32, policy: AcceptancePolicy) -> Self {
+        Self::from_verdict(judge, Verdict::new(score), policy)
+    }
+    pub fn from_verdict(judge: &str, verdict: Verdict, policy: AcceptancePolicy) -> Self {
+        JudgeDecision {
+            judge: judge.to_string(),
+            score: verdict.score,
+            threshold: policy.threshold,
+            accepted: policy.accepts(verdict.score),
+            explanation: verdict.explanation,
+            parts: vec![],
+        }
+    }
 }
 
 #[async_trait]
 pub trait Judge: Send + Sync {
-    async fn judge(&self, submission: JudgeSubmission) -> anyhow::Result<bool>;
+    /// Short name recorded next to each decision.
+    fn name(&self) -> &'static str;
+    /// The threshold `decide` holds this judge's scores to. Composite judges
+    /// may settle candidates otherwise, so callers go by
+    /// [`JudgeDecision::accepted`] rather than comparing scores with it.
+    fn policy(&self) -> AcceptancePolicy;
     async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32>;
+
+    /// One score per submission, in order. Judges that can share work across
+    /// candidates, like the LLM, override this; by default each is scored alone.
+    async fn judge_batch(&self, submissions: Vec<JudgeSubmission>) -> anyhow::Result<Vec<f32>> {
+        let mut scores = Vec::with_capacity(submissions.len());
+        for submission in submissions {
+            scores.push(self.judge_score(submission).await?);
+        }
+        Ok(scores)
+    }
+
+    /// The score with what it is made of. Judges that can tell override this.
+    async fn explain(&self, submission: JudgeSubmission) -> anyhow::Result<Verdict> {
+        Ok(Verdict::new(self.judge_score(submission).await?))
+    }
+    /// One verdict per submission, in order. By default each is explained
+    /// alone; judges overriding `judge_batch` override this too.
+    async fn explain_batch(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<Verdict>> {
+        let mut verdicts = Vec::with_capacity(submissions.len());
+        for submission in submissions {
+            verdicts.push(self.explain(submission).await?);
+        }
+        Ok(verdicts)
+    }
+
+    async fn decide(&self, submission: JudgeSubmission) -> anyhow::Result<JudgeDecision> {
+        let verdict = self.explain(submission).await?;
+        Ok(JudgeDecision::from_verdict(
+            self.name(),
+            verdict,
+            self.policy(),
+        ))
+    }
+    async fn decide_batch(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<JudgeDecision>> {
+        let verdicts = self.explain_batch(submissions).await?;
+        let policy = self.policy();
+        Ok(verdicts
+            .into_iter()
+            .map(|verdict| JudgeDecision::from_verdict(self.name(), verdict, policy))
+            .collect())
+    }
+    async fn judge(&self, submission: JudgeSubmission) -> anyhow::Result<bool> {
+        Ok(self.decide(submission).await?.accepted)
+    }
+}
+
+/// Buffering of candidates so they are judged together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchSettings {
+    /// A track's buffer is judged once it holds this many candidates.
+    pub size: usize,
+    /// Or once its oldest candidate waited this long.
+    pub window: Duration,
+    /// Batches judged at the same time.
+    pub concurrency: usize,
 }
 
+#[derive(Clone)]
 pub struct JudgeManager {
-    pub method: Box<dyn Judge>,
+    pub method: Arc<dyn Judge>,
+    /// Checked before `method`, so mismatched versions never reach it.
+    pub version_filter: Option<VersionFilter>,
+    /// Also checked before `method`, rejecting previews and mislabeled files.
+    pub plausibility: Option<PlausibilityJudge>,
+    /// Judge candidates per track in batches instead of one by one.
+    pub batch: Option<BatchSettings>,
 }
 impl JudgeManager {
     pub fn new(method: Box<dyn Judge>) -> JudgeManager {
-        JudgeManager { method }
+        JudgeManager {
+            method: Arc::from(method),
+            version_filter: None,
+            plausibility: None,
+            batch: None,
+        }
     }
+    pub fn with_version_filter(mut self, version_filter: Option<VersionFilter>) -> Self {
+        self.version_filter = version_filter;
+        self
+    }
+    pub fn with_plausibility(mut self, plausibility: Option<PlausibilityJudge>) -> Self {
+        self.plausibility = plausibility;
+        self
+    }
+    pub fn with_batch(mut self, batch: Option<BatchSettings>) -> Self {
+        self.batch = batch;
+        self
+    }
+    /// Wraps `method` so scores it gave before are reused.
+    pub fn with_cache(self, cache: Option<JudgeCache>) -> Self {
+        match cache {
+            Some(cache) => JudgeManager {
+                method: Arc::new(CachedJudge::new(self.method, cache)),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Rejections that do not need `method`.
+    pub async fn prefilter(&self, track: &JudgeSubmission) -> anyhow::Result<Option<RejectReason>> {
+        if let Some(mismatch) = self
+            .version_filter
+            .as_ref()
+            .and_then(|filter| filter.check(track))
+        {
+            tracing::info!(?mismatch, "version mismatch");
+            return Ok(Some(RejectReason::VersionMismatch(mismatch)));
+        }
+        if let Some(plausibility) = &self.plausibility {
+            let decision = plausibility
+                .decide(track.clone())
+                .await
+                .context("checking plausibility")?;
+            if !decision.accepted {
+                tracing::info!(score = decision.score, "implausible file");
+                return Ok(Some(RejectReason::LowScore(decision)));
+            }
+        }
+        Ok(None)
+    }
+
+    #[instrument(name = "JudgeManager::send_decision", skip_all, fields(file_q = track.query.filename, verdict))]
+    async fn send_decision(
+        track: JudgeSubmission,
+        decision: JudgeDecision,
+        sender: &Sender<Track>,
+    ) -> anyhow::Result<()> {
+        if let Ok(verdict) = serde_json::to_string(&decision) {
+            tracing::Span::current().record("verdict", verdict);
+        }
+        tracing::info!(
+            judge = decision.judge,
+            score = decision.score,
+            threshold = decision.threshold,
+            accepted = decision.accepted,
+            file_q = track.query.filename,
+            "judged"
+        );
+        if decision.accepted {
+            let candidate = Candidate {
+                submission: track,
+                decision,
+            };
+            send(Track::Candidate(candidate), sender)
+                .await
+                .context("sending judgement")?;
+        } else {
+            let reject = RejectedTrack::new(track, RejectReason::LowScore(decision));
+            send(Track::Reject(reject), sender)
+                .await
+                .context("sending reject")?;
+        }
+        Ok(())
+    }
+
+    /// Rejects candidates the judge failed on, so the run goes on without
+    /// them and the failure is kept next to other rejections.
+    async fn send_failure(
+        tracks: Vec<JudgeSubmission>,
+        err: anyhow::Error,
+        sender: &Sender<Track>,
+    ) -> anyhow::Result<()> {
+        tracing::error!(error = ?err, candidates = tracks.len(), "judging failed");
+        let error = format!("{err:#}");
+        for track in tracks {
+            let reject = RejectedTrack::new(track, RejectReason::JudgeFailed(error.clone()));
+            send(Track::Reject(reject), sender)
+                .await
+                .context("sending reject")?;
+        }
+        Ok(())
+    }
+
     pub async fn run(
         &self,
         track: JudgeSubmission,
         sender: Arc<Sender<Track>>,
     ) -> anyhow::Result<()> {
         tracing::info!("received in judge manager = {:?}", track);
-        let response = self
-            .method
-            .judge_score(track.clone())
-            .await
-            .context("awaiting judge response")?;
-        if response > 0.75 {
-            send(Track::Downloadable(track), &sender)
-                .await
-                .context("sending judgement")?;
-        } else {
-            let reject = RejectedTrack::new(track, RejectReason::LowScore(response));
+        let reason = match self.prefilter(&track).await {
+            Ok(reason) => reason,
+            Err(err) => return Self::send_failure(vec![track], err, &sender).await,
+        };
+        if let Some(reason) = reason {
+            let reject = RejectedTrack::new(track, reason);
             send(Track::Reject(reject), &sender)
                 .await
                 .context("sending reject")?;
+            return Ok(());
+        }
+        match self
+            .method
+            .decide(track.clone())
+            .await
+            .context("awaiting judge response")
+        {
+            Ok(decision) => Self::send_decision(track, decision, &sender).await,
+            Err(err) => Self::send_failure(vec![track], err, &sender).await,
+        }
+    }
+
+    /// Judges candidates that all belong to the same track in one go.
+    #[instrument(name = "JudgeManager::run_batch", skip(self, tracks, sender), fields(candidates = tracks.len()))]
+    pub async fn run_batch(
+        &self,
+        tracks: Vec<JudgeSubmission>,
+        sender: &Sender<Track>,
+    ) -> anyhow::Result<()> {
+        let mut pending = vec![];
+        for track in tracks {
+            match self.prefilter(&track).await {
+                Ok(Some(reason)) => {
+                    let reject = RejectedTrack::new(track, reason);
+                    send(Track::Reject(reject), sender)
+                        .await
+                        .context("sending reject")?;
+                }
+                Ok(None) => pending.push(track),
+                Err(err) => Self::send_failure(vec![track], err, sender).await?,
+            }
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let decisions = self
+            .method
+            .decide_batch(pending.clone())
+            .await
+            .context("awaiting judge response")
+            .and_then(|decisions| {
+                ensure!(
+                    decisions.len() == pending.len(),
+                    "judge returned {} decisions for {} candidates",
+                    decisions.len(),
+                    pending.len()
+                );
+                Ok(decisions)
+            });
+        match decisions {
+            Ok(decisions) => {
+                for (track, decision) in pending.into_iter().zip(decisions) {
+                    Self::send_decision(track, decision, sender).await?;
+                }
+                Ok(())
+            }
+            Err(err) => Self::send_failure(pending, err, sender).await,
+        }
+    }
+
+    /// Buffers submissions per track and judges each buffer once it is full
+    /// or its window elapsed, up to `settings.concurrency` at a time. The
+    /// candidates of a batch the judge fails on are rejected as
+    /// [`RejectReason::JudgeFailed`]. Returns when `receiver` closes, after
+    /// judging what is left.
+    pub async fn run_batches(
+        self: Arc<Self>,
+        settings: BatchSettings,
+        mut receiver: Receiver<(JudgeSubmission, WorkToken)>,
+        sender: Arc<Sender<Track>>,
+    ) -> anyhow::Result<()> {
+        let semaphore = Arc::new(Semaphore::new(settings.concurrency));
+        let mut running = JoinSet::new();
+        let mut buffers: HashMap<String, (Instant, Vec<(JudgeSubmission, WorkToken)>)> =
+            HashMap::new();
+        loop {
+            while let Some(joined) = running.try_join_next() {
+                log_panic(joined);
+            }
+            let deadline = buffers
+                .values()
+                .map(|(started, _)| *started + settings.window)
+                .min();
+            let window_elapsed = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                received = receiver.recv() => {
+                    let Some((submission, work)) = received else { break };
+                    let track_id = submission.track.track_id.clone();
+                    let (_, buffer) = buffers
+                        .entry(track_id.clone())
+                        .or_insert_with(|| (Instant::now(), vec![]));
+                    buffer.push((submission, work));
+                    if buffer.len() >= settings.size
+                        && let Some((_, batch)) = buffers.remove(&track_id)
+                    {
+                        self.spawn_batch(batch, &semaphore, &sender, &mut running);
+                    }
+                }
+                _ = window_elapsed => {
+                    let now = Instant::now();
+                    let expired: Vec<String> = buffers
+                        .iter()
+                        .filter(|(_, (started, _))| *started + settings.window <= now)
+                        .map(|(track_id, _)| track_id.clone())
+                        .collect();
+                    for track_id in expired {
+                        if let Some((_, batch)) = buffers.remove(&track_id) {
+                            self.spawn_batch(batch, &semaphore, &sender, &mut running);
+                        }
+                    }
+                }
+            }
+        }
+        for (_, batch) in buffers.into_values() {
+            self.spawn_batch(batch, &semaphore, &sender, &mut running);
+        }
+        while let Some(joined) = running.join_next().await {
+            log_panic(joined);
         }
         Ok(())
     }
+
+    /// Judges a buffered batch once a permit frees up, settling its work
+    /// when its decisions are sent or it failed.
+    fn spawn_batch(
+        self: &Arc<Self>,
+        batch: Vec<(JudgeSubmission, WorkToken)>,
+        semaphore: &Arc<Semaphore>,
+        sender: &Arc<Sender<Track>>,
+        running: &mut JoinSet<()>,
+    ) {
+        let manager = Arc::clone(self);
+        let semaphore = Arc::clone(semaphore);
+        let sender = Arc::clone(sender);
+        running.spawn(async move {
+            let (batch, _work): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            let candidates = batch.len();
+            let result = match semaphore.acquire().await {
+                Ok(_permit) => manager.run_batch(batch, &sender).await,
+                Err(err) => Err(err).context("Getting permit"),
+            };
+            if let Err(err) = result {
+                tracing::error!(error = ?err, candidates, "sending batch decisions failed");
+            }
+        });
+    }
+}
+
+fn log_panic(joined: Result<(), JoinError>) {
+    if let Err(err) = joined {
+        tracing::error!(error = ?err, "judging batch panicked");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::internals::context::context_manager::PendingWork;
+
+    /// Accepts everything and records the size of each batch, failing on
+    /// files called `broken`.
+    struct Recorder(Arc<Mutex<Vec<usize>>>);
+
+    #[async_trait]
+    impl Judge for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+        fn policy(&self) -> AcceptancePolicy {
+            AcceptancePolicy::new(0.5)
+        }
+        async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+            ensure!(submission.query.filename != "broken", "broken file");
+            Ok(1.0)
+        }
+        async fn decide_batch(
+            &self,
+            submissions: Vec<JudgeSubmission>,
+        ) -> anyhow::Result<Vec<JudgeDecision>> {
+            self.0.lock().unwrap().push(submissions.len());
+            ensure!(
+                submissions.iter().all(|s| s.query.filename != "broken"),
+                "broken batch"
+            );
+            Ok(submissions
+                .iter()
+                .map(|_| JudgeDecision::from_score(self.name(), 1.0, self.policy()))
+                .collect())
+        }
+    }
+
+    fn submission(track_id: &str, filename: &str) -> JudgeSubmission {
+        let mut submission = JudgeSubmission::creep(filename);
+        submission.track.track_id = track_id.to_string();
+        submission
+    }
+
+    async fn next_candidate(receiver: &mut Receiver<Track>) -> String {
+        match receiver.recv().await {
+            Some(Track::Candidate(candidate)) => candidate.submission.query.filename,
+            other => panic!("expected a candidate, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn flushes_full_buffers_at_once_and_the_rest_after_the_window() {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let manager = Arc::new(JudgeManager::new(Box::new(Recorder(Arc::clone(&batches)))));
+        let settings = BatchSettings {
+            size: 2,
+            window: Duration::from_millis(300),
+            concurrency: 1,
+        };
+        let (queue, queued) = tokio::sync::mpsc::channel(10);
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let batcher = tokio::spawn(manager.run_batches(settings, queued, Arc::new(sender)));
+        let pending = PendingWork::new();
+        let started = Instant::now();
+        for (track_id, filename) in [("lone", "c.mp3"), ("full", "a.mp3"), ("full", "b.mp3")] {
+            let work = pending.start();
+            queue
+                .send((submission(track_id, filename), work))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(next_candidate(&mut receiver).await, "a.mp3");
+        assert_eq!(next_candidate(&mut receiver).await, "b.mp3");
+        assert!(started.elapsed() < settings.window);
+        // The lone candidate waits for its window while the queue is open.
+        assert_eq!(next_candidate(&mut receiver).await, "c.mp3");
+        assert!(started.elapsed() >= settings.window);
+        assert_eq!(*batches.lock().unwrap(), [2, 1]);
+
+        drop(queue);
+        batcher.await.unwrap().unwrap();
+        pending.settled().await;
+    }
+
+    #[tokio::test]
+    async fn rejects_a_failed_batch_and_keeps_batching() {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let manager = Arc::new(JudgeManager::new(Box::new(Recorder(Arc::clone(&batches)))));
+        let settings = BatchSettings {
+            size: 1,
+            window: Duration::from_secs(60),
+            concurrency: 2,
+        };
+        let (queue, queued) = tokio::sync::mpsc::channel(10);
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let batcher = tokio::spawn(manager.run_batches(settings, queued, Arc::new(sender)));
+        let pending = PendingWork::new();
+        queue
+            .send((submission("first", "broken"), pending.start()))
+            .await
+            .unwrap();
+        queue
+            .send((submission("second", "fine.mp3"), pending.start()))
+            .await
+            .unwrap();
+
+        drop(queue);
+        batcher.await.unwrap().unwrap();
+        // The failed batch still settled its work.
+        pending.settled().await;
+        assert_eq!(batches.lock().unwrap().len(), 2);
+        let mut outcomes = vec![];
+        while let Ok(track) = receiver.try_recv() {
+            outcomes.push(match track {
+                Track::Candidate(candidate) => {
+                    format!("accept {}", candidate.submission.query.filename)
+                }
+                Track::Reject(reject) => match reject.parts() {
+                    (submission, RejectReason::JudgeFailed(error)) => {
+                        format!("failed {}: {error}", submission.query.filename)
+                    }
+                    other => panic!("expected a failure, got {other:?}"),
+                },
+                other => panic!("expected a decision, got {other:?}"),
+            });
+        }
+        outcomes.sort();
+        assert_eq!(
+            outcomes,
+            [
+                "accept fine.mp3",
+                "failed broken: awaiting judge response: broken batch"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_file_the_judge_failed_on() {
+        let manager = JudgeManager::new(Box::new(Recorder(Arc::default())));
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        manager
+            .run(submission("only", "broken"), Arc::new(sender))
+            .await
+            .unwrap();
+        match receiver.recv().await {
+            Some(Track::Reject(reject)) => {
+                assert!(matches!(reject.parts().1, RejectReason::JudgeFailed(_)))
+            }
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+    }
 }
@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, ensure};
+use async_trait::async_trait;
+
+use crate::internals::{
+    judge::judge_manager::{AcceptancePolicy, Judge, JudgeDecision},
+    search::search_manager::JudgeSubmission,
+};
+
+/// What a cached decision is stored under.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// `SearchItem::track_id`.
+    pub track_id: String,
+    /// Lowercased path with `/` separators. The folders are kept since the
+    /// Levenshtein, token and LLM judges read them. Followed by `#size` for
+    /// judges that look at more than names.
+    pub filename: String,
+    pub judge: String,
+    /// Fingerprint of the settings the decision was made with.
+    pub fingerprint: String,
+}
+
+impl CacheKey {
+    pub fn new(submission: &JudgeSubmission, judge: &str, fingerprint: &str) -> Self {
+        let filename = submission.query.filename.replace('\\', "/");
+        CacheKey {
+            track_id: submission.track.track_id.clone(),
+            filename: filename.trim().to_lowercase(),
+            judge: judge.to_string(),
+            fingerprint: fingerprint.to_string(),
+        }
+    }
+}
+
+/// Storage for judge decisions. Calls may block, `CachedJudge` makes them on
+/// tokio's blocking threads.
+pub trait DecisionStore: Send + Sync {
+    fn get(&self, key: &CacheKey) -> anyhow::Result<Option<JudgeDecision>>;
+    fn put(&self, key: &CacheKey, decision: &JudgeDecision) -> anyhow::Result<()>;
+    /// Drops the decisions `judge` made with any other fingerprint, returning
+    /// how many were dropped.
+    fn invalidate(&self, judge: &str, fingerprint: &str) -> anyhow::Result<usize>;
+}
+
+/// Decisions kept for the life of the process.
+#[derive(Debug, Default)]
+pub struct MemoryDecisionStore {
+    decisions: Mutex<HashMap<CacheKey, JudgeDecision>>,
+}
+
+impl DecisionStore for MemoryDecisionStore {
+    fn get(&self, key: &CacheKey) -> anyhow::Result<Option<JudgeDecision>> {
+        Ok(self.decisions.lock().expect("poisoned").get(key).cloned())
+    }
+    fn put(&self, key: &CacheKey, decision: &JudgeDecision) -> anyhow::Result<()> {
+        self.decisions
+            .lock()
+            .expect("poisoned")
+            .insert(key.clone(), decision.clone());
+        Ok(())
+    }
+    fn invalidate(&self, judge: &str, fingerprint: &str) -> anyhow::Result<usize> {
+        let mut decisions = self.decisions.lock().expect("poisoned");
+        let before = decisions.len();
+        decisions.retain(|key, _| key.judge != judge || key.fingerprint == fingerprint);
+        Ok(before - decisions.len())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// A decision store with the fingerprint of the current judge settings,
+/// shared by every judge built for a run. Decisions are keyed on the whole
+/// path, so a file is only reused from peers sharing it under the same
+/// folders.
+#[derive(Clone)]
+pub struct JudgeCache {
+    pub store: Arc<dyn DecisionStore>,
+    pub fingerprint: String,
+    /// Key on the file size too, for judges whose decisions depend on it.
+    pub by_size: bool,
+    pub stats: Arc<CacheStats>,
+}
+
+impl JudgeCache {
+    pub fn new(store: Arc<dyn DecisionStore>, fingerprint: String) -> Self {
+        JudgeCache {
+            store,
+            fingerprint,
+            by_size: false,
+            stats: Arc::new(CacheStats::default()),
+        }
+    }
+    pub fn with_by_size(mut self, by_size: bool) -> Self {
+        self.by_size = by_size;
+        self
+    }
+}
+
+/// Wraps a judge, reusing the decisions it made before for the same track
+/// and file name. Hits return the stored decision unchanged, explanation and
+/// parts included. Store failures are logged and count as misses, so a broken
+/// cache only costs time.
+pub struct CachedJudge {
+    pub inner: Arc<dyn Judge>,
+    pub cache: JudgeCache,
+}
+
+impl CachedJudge {
+    /// Drops the decisions `inner` made with other settings.
+    pub fn new(inner: Arc<dyn Judge>, cache: JudgeCache) -> Self {
+        match cache.store.invalidate(inner.name(), &cache.fingerprint) {
+            Ok(0) => {}
+            Ok(dropped) => tracing::info!(judge = inner.name(), dropped, "judge cache invalidated"),
+            Err(err) => tracing::warn!(error = ?err, "invalidating judge cache"),
+        }
+        CachedJudge { inner, cache }
+    }
+
+    fn key(&self, submission: &JudgeSubmission) -> CacheKey {
+        let mut key = CacheKey::new(submission, self.inner.name(), &self.cache.fingerprint);
+        if self.cache.by_size {
+            key.filename = format!("{}#{}", key.filename, submission.query.size);
+        }
+        key
+    }
+
+    /// Stores may block, as the Postgres one does, so they are called off
+    /// the async workers.
+    async fn lookup(&self, keys: Vec<CacheKey>) -> Vec<Option<JudgeDecision>> {
+        let store = Arc::clone(&self.cache.store);
+        let count = keys.len();
+        let decisions = tokio::task::spawn_blocking(move || {
+            keys.iter()
+                .map(|key| {
+                    store.get(key).unwrap_or_else(|err| {
+                        tracing::warn!(error = ?err, "reading judge cache");
+                        None
+                    })
+                })
+                .collect()
+        })
+        .await
+        .unwrap_or_else(|err| {
+            tracing::warn!(error = ?err, "reading judge cache");
+            vec![None; count]
+        });
+        for decision in &decisions {
+            let counter = match decision {
+                Some(_) => &self.cache.stats.hits,
+                None => &self.cache.stats.misses,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        decisions
+    }
+
+    async fn store(&self, decisions: Vec<(CacheKey, JudgeDecision)>) {
+        let store = Arc::clone(&self.cache.store);
+        let stored = tokio::task::spawn_blocking(move || {
+            for (key, decision) in &decisions {
+                if let Err(err) = store.put(key, decision) {
+                    tracing::warn!(error = ?err, "writing judge cache");
+                }
+            }
+        })
+        .await;
+        if let Err(err) = stored {
+            tracing::warn!(error = ?err, "writing judge cache");
+        }
+    }
+
+    fn log_stats(&self) {
+        tracing::info!(
+            judge = self.inner.name(),
+            hits = self.cache.stats.hits(),
+            misses = self.cache.stats.misses(),
+            "judge cache"
+        );
+    }
+}
+
+#[async_trait]
+impl Judge for CachedJudge {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+    fn policy(&self) -> AcceptancePolicy {
+        self.inner.policy()
+    }
+    async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+        Ok(self.decide(submission).await?.score)
+    }
+    async fn judge_batch(&self, submissions: Vec<JudgeSubmission>) -> anyhow::Result<Vec<f32>> {
+        let decisions = self.decide_batch(submissions).await?;
+        Ok(decisions
+            .into_iter()
+            .map(|decision| decision.score)
+            .collect())
+    }
+    async fn decide(&self, submission: JudgeSubmission) -> anyhow::Result<JudgeDecision> {
+        self.decide_batch(vec![submission])
+            .await?
+            .pop()
+            .context("no decision")
+    }
+    async fn decide_batch(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<JudgeDecision>> {
+        let keys: Vec<CacheKey> = submissions.iter().map(|s| self.key(s)).collect();
+        let mut decisions = self.lookup(keys.clone()).await;
+        // The same file from several peers is judged once.
+        let mut misses: Vec<usize> = vec![];
+        let mut first_of: HashMap<&CacheKey, usize> = HashMap::new();
+        let mut pending = vec![];
+        for (index, submission) in submissions.iter().enumerate() {
+            if decisions[index].is_some() {
+                continue;
+            }
+            misses.push(index);
+            if !first_of.contains_key(&keys[index]) {
+                first_of.insert(&keys[index], pending.len());
+                pending.push(submission.clone());
+            }
+        }
+        if !pending.is_empty() {
+            let expected = pending.len();
+            let judged = self.inner.decide_batch(pending).await?;
+            ensure!(
+                judged.len() == expected,
+                "{} returned {} decisions for {} candidates",
+                self.inner.name(),
+                judged.len(),
+                expected
+            );
+            let fresh = first_of
+                .iter()
+                .map(|(key, position)| ((*key).clone(), judged[*position].clone()))
+                .collect();
+            self.store(fresh).await;
+            for index in misses {
+                decisions[index] = Some(judged[first_of[&keys[index]]].clone());
+            }
+        }
+        self.log_stats();
+        Ok(decisions.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internals::judge::judges::composite::{
+        CompositeJudge, CompositeMember, CompositeStrategy,
+    };
+    use std::sync::atomic::AtomicUsize;
+
+    /// Scores by file name length, counting what it was asked.
+    struct CountingJudge {
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Judge for CountingJudge {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+        fn policy(&self) -> AcceptancePolicy {
+            AcceptancePolicy::new(0.5)
+        }
+        async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(1.0 / submission.query.filename.len() as f32)
+        }
+    }
+
+    fn submission(filename: &str, username: &str) -> JudgeSubmission {
+        let mut submission = JudgeSubmission::creep(filename);
+        submission.query.username = username.to_string();
+        submission
+    }
+
+    fn judge(store: Arc<dyn DecisionStore>, fingerprint: &str) -> (CachedJudge, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner = Arc::new(CountingJudge {
+            calls: Arc::clone(&calls),
+        });
+        let cache = JudgeCache::new(store, fingerprint.to_string());
+        (CachedJudge::new(inner, cache), calls)
+    }
+
+    #[test]
+    fn keys_keep_folders_but_ignore_case_and_separators() {
+        let key = |filename: &str| CacheKey::new(&submission(filename, "peer"), "j", "f");
+        assert_eq!(
+            key("Music\\Radiohead\\Creep.mp3"),
+            key("music/radiohead/CREEP.mp3")
+        );
+        assert_ne!(
+            key("Music\\Radiohead\\Creep.mp3"),
+            key("Music\\Pablo Honey\\Creep.mp3")
+        );
+    }
+
+    #[tokio::test]
+    async fn scores_each_file_once_across_peers_and_batches() {
+        let store: Arc<dyn DecisionStore> = Arc::new(MemoryDecisionStore::default());
+        let (judge, calls) = judge(Arc::clone(&store), "v1");
+        let scores = judge
+            .judge_batch(vec![
+                submission("Radiohead\\Creep.mp3", "alice"),
+                submission("Radiohead\\Creep.mp3", "bob"),
+                submission("Radiohead\\Creep (live).mp3", "bob"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(scores[0], scores[1]);
+
+        let again = judge
+            .judge_score(submission("radiohead/creep.mp3", "carol"))
+            .await
+            .unwrap();
+        assert_eq!(again, scores[0]);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(judge.cache.stats.hits(), 1);
+        assert_eq!(judge.cache.stats.misses(), 3);
+    }
+
+    #[tokio::test]
+    async fn settings_changes_invalidate() {
+        let store: Arc<dyn DecisionStore> = Arc::new(MemoryDecisionStore::default());
+        let (first, _) = judge(Arc::clone(&store), "v1");
+        first
+            .judge_score(submission("Creep.mp3", "a"))
+            .await
+            .unwrap();
+
+        let (second, calls) = judge(Arc::clone(&store), "v2");
+        second
+            .judge_score(submission("Creep.mp3", "a"))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        let v1 = CacheKey::new(&submission("Creep.mp3", "a"), "counting", "v1");
+        assert_eq!(store.get(&v1).unwrap(), None);
+    }
+
+    /// Loses every decision of a batch.
+    struct ForgetfulJudge;
+
+    #[async_trait]
+    impl Judge for ForgetfulJudge {
+        fn name(&self) -> &'static str {
+            "forgetful"
+        }
+        fn policy(&self) -> AcceptancePolicy {
+            AcceptancePolicy::new(0.5)
+        }
+        async fn judge_score(&self, _submission: JudgeSubmission) -> anyhow::Result<f32> {
+            Ok(1.0)
+        }
+        async fn decide_batch(
+            &self,
+            _submissions: Vec<JudgeSubmission>,
+        ) -> anyhow::Result<Vec<JudgeDecision>> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_when_the_judge_returns_too_few_decisions() {
+        let store: Arc<dyn DecisionStore> = Arc::new(MemoryDecisionStore::default());
+        let cache = JudgeCache::new(Arc::clone(&store), "v1".to_string());
+        let judge = CachedJudge::new(Arc::new(ForgetfulJudge), cache);
+        let err = judge
+            .decide_batch(vec![submission("Creep.mp3", "a")])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("0 decisions for 1"), "{err:#}");
+    }
+
+    /// Scores every file 0.6 and accepts from 0.5, like a lenient LLM.
+    struct Lenient;
+
+    #[async_trait]
+    impl Judge for Lenient {
+        fn name(&self) -> &'static str {
+            "lenient"
+        }
+        fn policy(&self) -> AcceptancePolicy {
+            AcceptancePolicy::new(0.5)
+        }
+        async fn judge_score(&self, _submission: JudgeSubmission) -> anyhow::Result<f32> {
+            Ok(0.6)
+        }
+    }
+
+    #[tokio::test]
+    async fn hits_return_the_decision_as_it_was_made() {
+        // The cascade accepts by its member's policy, below its own 0.75.
+        let composite = CompositeJudge::new(
+            vec![CompositeMember::new(Box::new(Lenient))],
+            CompositeStrategy::Cascade,
+            0.75,
+        )
+        .unwrap();
+        let store: Arc<dyn DecisionStore> = Arc::new(MemoryDecisionStore::default());
+        let cache = JudgeCache::new(store, "v1".to_string());
+        let judge = CachedJudge::new(Arc::new(composite), cache);
+
+        let fresh = judge.decide(submission("Creep.mp3", "a")).await.unwrap();
+        let cached = judge.decide(submission("Creep.mp3", "b")).await.unwrap();
+        assert_eq!(judge.cache.stats.hits(), 1);
+        assert!(fresh.accepted);
+        assert_eq!(cached, fresh);
+    }
+}
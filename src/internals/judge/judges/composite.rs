@@ -0,0 +1,503 @@
+use anyhow::{Context, ensure};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::internals::{
+    judge::judge_manager::{AcceptancePolicy, Explanation, Judge, JudgeDecision},
+    search::search_manager::JudgeSubmission,
+};
+
+/// How the scores of the member judges are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompositeStrategy {
+    /// Weighted mean of every score, compared against the composite threshold.
+    WeightedAverage,
+    /// Every judge must accept under its own policy. Stops at the first reject.
+    AllMustPass,
+    /// Judges run in order. A score above a member's ambiguous band accepts,
+    /// below it rejects, and only scores inside it reach the next judge. The
+    /// last judge decides with its own policy.
+    #[default]
+    Cascade,
+}
+
+/// Scores in `[low, high)` are not conclusive for a cascade member.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AmbiguousBand {
+    pub low: f32,
+    pub high: f32,
+}
+
+pub struct CompositeMember {
+    pub judge: Box<dyn Judge>,
+    pub weight: f32,
+    /// Defaults to the judge's own threshold, which makes it conclusive.
+    pub ambiguous: Option<AmbiguousBand>,
+}
+
+impl CompositeMember {
+    pub fn new(judge: Box<dyn Judge>) -> Self {
+        CompositeMember {
+            judge,
+            weight: 1.0,
+            ambiguous: None,
+        }
+    }
+    pub fn with_weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        self
+    }
+    pub fn with_ambiguous(mut self, ambiguous: Option<AmbiguousBand>) -> Self {
+        self.ambiguous = ambiguous;
+        self
+    }
+}
+
+pub struct CompositeJudge {
+    pub members: Vec<CompositeMember>,
+    pub strategy: CompositeStrategy,
+    /// Only used by [`CompositeStrategy::WeightedAverage`].
+    pub threshold: f32,
+}
+
+impl CompositeJudge {
+    pub fn new(
+        members: Vec<CompositeMember>,
+        strategy: CompositeStrategy,
+        threshold: f32,
+    ) -> anyhow::Result<Self> {
+        ensure!(
+            !members.is_empty(),
+            "composite judge needs at least one judge"
+        );
+        ensure!(
+            members.iter().all(|member| member.weight > 0.0),
+            "composite judge weights must be positive"
+        );
+        Ok(CompositeJudge {
+            members,
+            strategy,
+            threshold,
+        })
+    }
+
+    async fn weighted_average(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<JudgeDecision>> {
+        let mut parts: Vec<Vec<JudgeDecision>> = vec![vec![]; submissions.len()];
+        for member in &self.members {
+            let decisions = member.judge.decide_batch(submissions.clone()).await?;
+            for (index, part) in decisions.into_iter().enumerate() {
+                parts[index].push(part);
+            }
+        }
+        let weights: f32 = self.members.iter().map(|member| member.weight).sum();
+        let rule = format!(
+            "weighted mean of {}",
+            self.members
+                .iter()
+                .map(|member| format!("{} ×{}", member.judge.name(), member.weight))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(parts
+            .into_iter()
+            .map(|parts| {
+                let total: f32 = parts
+                    .iter()
+                    .zip(&self.members)
+                    .map(|(part, member)| part.score * member.weight)
+                    .sum();
+                let score = total / weights;
+                let accepted = self.policy().accepts(score);
+                self.decision(score, self.threshold, accepted, parts, rule.clone())
+            })
+            .collect())
+    }
+
+    async fn all_must_pass(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<JudgeDecision>> {
+        let mut parts: Vec<Vec<JudgeDecision>> = vec![vec![]; submissions.len()];
+        // Only candidates every judge so far accepted reach the next one.
+        let mut pending: Vec<usize> = (0..submissions.len()).collect();
+        for member in &self.members {
+            if pending.is_empty() {
+                break;
+            }
+            let batch = pending
+                .iter()
+                .map(|index| submissions[*index].clone())
+                .collect();
+            let decisions = member.judge.decide_batch(batch).await?;
+            let mut still_pending = vec![];
+            for (index, part) in pending.into_iter().zip(decisions) {
+                if part.accepted {
+                    still_pending.push(index);
+                }
+                parts[index].push(part);
+            }
+            pending = still_pending;
+        }
+        parts
+            .into_iter()
+            .map(|parts| {
+                let weakest = parts
+                    .iter()
+                    .min_by(|a, b| a.score.total_cmp(&b.score))
+                    .context("no judge ran")?;
+                let (score, threshold) = (weakest.score, weakest.threshold);
+                let rule = match parts.iter().find(|part| !part.accepted) {
+                    Some(rejecting) => format!("rejected by {}", rejecting.judge),
+                    None => "accepted by every judge".to_string(),
+                };
+                let accepted = parts.iter().all(|part| part.accepted);
+                Ok(self.decision(score, threshold, accepted, parts, rule))
+            })
+            .collect()
+    }
+
+    async fn cascade(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<JudgeDecision>> {
+        let mut parts: Vec<Vec<JudgeDecision>> = vec![vec![]; submissions.len()];
+        let mut decided: Vec<Option<JudgeDecision>> = vec![None; submissions.len()];
+        let mut pending: Vec<usize> = (0..submissions.len()).collect();
+        let last = self.members.len() - 1;
+        for (position, member) in self.members.iter().enumerate() {
+            if pending.is_empty() {
+                break;
+            }
+            let batch = pending
+                .iter()
+                .map(|index| submissions[*index].clone())
+                .collect();
+            let decisions = member.judge.decide_batch(batch).await?;
+            let mut ambiguous = vec![];
+            for (index, part) in pending.into_iter().zip(decisions) {
+                let (score, threshold) = (part.score, part.threshold);
+                let band = member.ambiguous.unwrap_or(AmbiguousBand {
+                    low: threshold,
+                    high: threshold,
+                });
+                parts[index].push(part);
+                let name = member.judge.name();
+                if score >= band.high {
+                    let parts = std::mem::take(&mut parts[index]);
+                    let rule = format!("{name} accepted at or above {:.2}", band.high);
+                    decided[index] = Some(self.decision(score, band.high, true, parts, rule));
+                } else if score < band.low {
+                    let parts = std::mem::take(&mut parts[index]);
+                    let rule = format!("{name} rejected below {:.2}", band.low);
+                    decided[index] = Some(self.decision(score, band.low, false, parts, rule));
+                } else if position == last {
+                    // Nobody is left to ask, so its own policy settles it.
+                    let accepted = parts[index].last().is_some_and(|part| part.accepted);
+                    let parts = std::mem::take(&mut parts[index]);
+                    let rule = format!(
+                        "{name} was last and unsure, {} at {threshold:.2}",
+                        if accepted { "accepted" } else { "rejected" }
+                    );
+                    decided[index] = Some(self.decision(score, threshold, accepted, parts, rule));
+                } else {
+                    ambiguous.push(index);
+                }
+            }
+            if !ambiguous.is_empty() {
+                tracing::info!(
+                    judge = member.judge.name(),
+                    candidates = ambiguous.len(),
+                    "ambiguous scores, asking the next judge"
+                );
+            }
+            pending = ambiguous;
+        }
+        decided
+            .into_iter()
+            .map(|decision| decision.context("the last cascade member always decides"))
+            .collect()
+    }
+
+    fn decision(
+        &self,
+        score: f32,
+        threshold: f32,
+        accepted: bool,
+        parts: Vec<JudgeDecision>,
+        rule: String,
+    ) -> JudgeDecision {
+        JudgeDecision {
+            judge: self.name().to_string(),
+            score,
+            threshold,
+            accepted,
+            explanation: Explanation::default().with_rule(rule),
+            parts,
+        }
+    }
+}
+
+#[async_trait]
+impl Judge for CompositeJudge {
+    fn name(&self) -> &'static str {
+        "composite"
+    }
+    /// The weighted average's threshold. The other strategies settle each
+    /// candidate through their members' policies, see
+    /// [`JudgeDecision::accepted`].
+    fn policy(&self) -> AcceptancePolicy {
+        AcceptancePolicy::new(self.threshold)
+    }
+    async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+        Ok(self.decide(submission).await?.score)
+    }
+    async fn judge_batch(&self, submissions: Vec<JudgeSubmission>) -> anyhow::Result<Vec<f32>> {
+        let decisions = self.decide_batch(submissions).await?;
+        Ok(decisions
+            .into_iter()
+            .map(|decision| decision.score)
+            .collect())
+    }
+
+    async fn decide(&self, submission: JudgeSubmission) -> anyhow::Result<JudgeDecision> {
+        self.decide_batch(vec![submission])
+            .await?
+            .pop()
+            .context("no decision")
+    }
+
+    #[instrument(name = "CompositeJudge::decide_batch", skip(self, submissions), fields(strategy = ?self.strategy, candidates = submissions.len()))]
+    async fn decide_batch(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<JudgeDecision>> {
+        let decisions = match self.strategy {
+            CompositeStrategy::WeightedAverage => self.weighted_average(submissions).await,
+            CompositeStrategy::AllMustPass => self.all_must_pass(submissions).await,
+            CompositeStrategy::Cascade => self.cascade(submissions).await,
+        }?;
+        for decision in &decisions {
+            for part in &decision.parts {
+                tracing::info!(
+                    judge = part.judge,
+                    score = part.score,
+                    threshold = part.threshold,
+                    accepted = part.accepted,
+                    "sub-judge decision"
+                );
+            }
+        }
+        Ok(decisions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// Scores files by name and records which it was asked about.
+    struct Stub {
+        name: &'static str,
+        threshold: f32,
+        scores: HashMap<&'static str, f32>,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Stub {
+        fn new(name: &'static str, threshold: f32, scores: &[(&'static str, f32)]) -> Self {
+            Stub {
+                name,
+                threshold,
+                scores: scores.iter().copied().collect(),
+                seen: Arc::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Judge for Stub {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+        fn policy(&self) -> AcceptancePolicy {
+            AcceptancePolicy::new(self.threshold)
+        }
+        async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+            let filename = submission.query.filename;
+            let score = self.scores[filename.as_str()];
+            self.seen.lock().unwrap().push(filename);
+            Ok(score)
+        }
+    }
+
+    async fn decide(judge: &CompositeJudge, filenames: &[&str]) -> Vec<(f32, bool)> {
+        let submissions = filenames
+            .iter()
+            .map(|name| JudgeSubmission::creep(name))
+            .collect();
+        judge
+            .decide_batch(submissions)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|decision| (decision.score, decision.accepted))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cascade_decides_outside_the_band_and_passes_the_rest_on() {
+        let scores = [
+            ("low", 0.49),
+            ("at_low", 0.5),
+            ("below_high", 0.89),
+            ("at_high", 0.9),
+        ];
+        let first = Stub::new("first", 0.75, &scores);
+        let last = Stub::new(
+            "last",
+            0.5,
+            &[
+                ("at_low", 0.2),
+                ("below_high", 0.8),
+                ("low", 1.0),
+                ("at_high", 0.0),
+            ],
+        );
+        let asked_last = Arc::clone(&last.seen);
+        let band = AmbiguousBand {
+            low: 0.5,
+            high: 0.9,
+        };
+        let judge = CompositeJudge::new(
+            vec![
+                CompositeMember::new(Box::new(first)).with_ambiguous(Some(band)),
+                CompositeMember::new(Box::new(last)),
+            ],
+            CompositeStrategy::Cascade,
+            0.75,
+        )
+        .unwrap();
+
+        let decisions = decide(&judge, &["low", "at_low", "below_high", "at_high"]).await;
+
+        assert_eq!(
+            decisions,
+            [(0.49, false), (0.2, false), (0.8, true), (0.9, true)]
+        );
+        assert_eq!(*asked_last.lock().unwrap(), ["at_low", "below_high"]);
+    }
+
+    #[tokio::test]
+    async fn the_last_cascade_member_decides_with_its_own_policy() {
+        let band = AmbiguousBand {
+            low: 0.2,
+            high: 0.9,
+        };
+        let only = Stub::new("only", 0.5, &[("unsure", 0.6), ("doubtful", 0.3)]);
+        let judge = CompositeJudge::new(
+            vec![CompositeMember::new(Box::new(only)).with_ambiguous(Some(band))],
+            CompositeStrategy::Cascade,
+            0.75,
+        )
+        .unwrap();
+        // Inside its band the last member has nobody to ask, so its own
+        // threshold decides.
+        assert_eq!(
+            decide(&judge, &["unsure", "doubtful"]).await,
+            [(0.6, true), (0.3, false)]
+        );
+
+        let first = Stub::new("first", 0.75, &[("a", 0.7), ("b", 0.7)]);
+        let last = Stub::new("last", 0.5, &[("a", 0.5), ("b", 0.49)]);
+        let judge = CompositeJudge::new(
+            vec![
+                CompositeMember::new(Box::new(first)).with_ambiguous(Some(band)),
+                CompositeMember::new(Box::new(last)),
+            ],
+            CompositeStrategy::Cascade,
+            0.75,
+        )
+        .unwrap();
+        assert_eq!(
+            decide(&judge, &["a", "b"]).await,
+            [(0.5, true), (0.49, false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn all_must_pass_stops_at_the_first_reject() {
+        let first = Stub::new("first", 0.5, &[("good", 0.9), ("bad", 0.1)]);
+        let second = Stub::new("second", 0.5, &[("good", 0.6), ("bad", 1.0)]);
+        let third = Stub::new("third", 0.5, &[("good", 0.4), ("bad", 1.0)]);
+        let asked_second = Arc::clone(&second.seen);
+        let asked_third = Arc::clone(&third.seen);
+        let judge = CompositeJudge::new(
+            vec![
+                CompositeMember::new(Box::new(first)),
+                CompositeMember::new(Box::new(second)),
+                CompositeMember::new(Box::new(third)),
+            ],
+            CompositeStrategy::AllMustPass,
+            0.75,
+        )
+        .unwrap();
+
+        let decisions = judge
+            .decide_batch(vec![
+                JudgeSubmission::creep("good"),
+                JudgeSubmission::creep("bad"),
+            ])
+            .await
+            .unwrap();
+
+        // Both were rejected, each with the weakest score it was given.
+        assert_eq!(
+            decisions
+                .iter()
+                .map(|decision| (decision.score, decision.accepted, decision.parts.len()))
+                .collect::<Vec<_>>(),
+            [(0.4, false, 3), (0.1, false, 1)]
+        );
+        assert_eq!(*asked_second.lock().unwrap(), ["good"]);
+        assert_eq!(*asked_third.lock().unwrap(), ["good"]);
+    }
+
+    #[tokio::test]
+    async fn weighted_average_weighs_every_member() {
+        let light = Stub::new("light", 0.5, &[("a", 0.0), ("b", 1.0)]);
+        let heavy = Stub::new("heavy", 0.5, &[("a", 1.0), ("b", 0.5)]);
+        let judge = CompositeJudge::new(
+            vec![
+                CompositeMember::new(Box::new(light)),
+                CompositeMember::new(Box::new(heavy)).with_weight(3.0),
+            ],
+            CompositeStrategy::WeightedAverage,
+            0.75,
+        )
+        .unwrap();
+
+        // (0 + 3) / 4 and (1 + 1.5) / 4.
+        assert_eq!(
+            decide(&judge, &["a", "b"]).await,
+            [(0.75, true), (0.625, false)]
+        );
+    }
+
+    #[test]
+    fn rejects_empty_or_unweighted_members() {
+        assert!(CompositeJudge::new(vec![], CompositeStrategy::Cascade, 0.75).is_err());
+        let stub = Stub::new("stub", 0.5, &[]);
+        let member = CompositeMember::new(Box::new(stub)).with_weight(0.0);
+        assert!(CompositeJudge::new(vec![member], CompositeStrategy::Cascade, 0.75).is_err());
+    }
+}
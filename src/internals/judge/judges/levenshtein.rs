@@ -1,7 +1,10 @@
 use async_trait::async_trait;
 use tracing::instrument;
 
-use crate::internals::{judge::judge_manager::Judge, search::search_manager::JudgeSubmission};
+use crate::internals::{
+    judge::judge_manager::{AcceptancePolicy, Explanation, Judge, Verdict},
+    search::search_manager::JudgeSubmission,
+};
 
 #[derive(Clone)]
 pub struct Levenshtein {
@@ -15,23 +18,49 @@ impl Levenshtein {
 
 #[async_trait]
 impl Judge for Levenshtein {
-    #[instrument(name = "Levenshtein::judge", skip(self, submission), fields(id=submission.track.track_id,username = submission.query.username , query_song = submission.track.track, file_q = submission.query.filename))]
-    async fn judge(&self, submission: JudgeSubmission) -> anyhow::Result<bool> {
-        let distance = str_distance::Levenshtein::default();
-        let a = format!("{}", submission.track);
-        let b = submission.query.filename;
-        let distance_val = str_distance::str_distance_normalized(a, b, distance);
-        tracing::info!("score = {}", distance_val);
-        let val = distance_val > self.score_cutoff as f64;
-        Ok(val)
+    fn name(&self) -> &'static str {
+        "levenshtein"
+    }
+    fn policy(&self) -> AcceptancePolicy {
+        AcceptancePolicy::new(self.score_cutoff)
     }
     #[instrument(name = "Levenshtein::judge_score", skip(self,submission), fields(id=submission.track.track_id,username = submission.query.username , query_song = submission.track.track, file_q = submission.query.filename))]
     async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
         let distance = str_distance::Levenshtein::default();
         let a = format!("{}", submission.track);
         let b = submission.query.filename;
-        let distance_val = str_distance::str_distance_normalized(a, b, distance);
-        tracing::info!("score = {}", distance_val);
-        Ok(distance_val as f32)
+        // The normalized distance is 0 for equal strings, so similar paths
+        // score close to 1 like they do with the other judges.
+        let score = 1.0 - str_distance::str_distance_normalized(a, b, distance) as f32;
+        tracing::info!("score = {}", score);
+        Ok(score)
+    }
+    async fn explain(&self, submission: JudgeSubmission) -> anyhow::Result<Verdict> {
+        let rule = format!("whole path against \"{}\"", submission.track);
+        let score = self.judge_score(submission).await?;
+        Ok(Verdict::new(score).with_explanation(Explanation::default().with_rule(rule)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn score(filename: &str) -> f32 {
+        Levenshtein::new(0.75)
+            .judge_score(JudgeSubmission::creep(filename))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn scores_similar_names_higher() {
+        let exact = score("Creep - Radiohead - Pablo Honey").await;
+        let close = score("Creep - Radiohead - Pablo Honey.flac").await;
+        let unrelated = score("Misc\\holiday voicemail.mp3").await;
+        assert_eq!(exact, 1.0);
+        assert!(close > 0.75, "{close}");
+        assert!(unrelated < close, "{unrelated}");
+        assert!(!Levenshtein::new(0.75).policy().accepts(unrelated));
     }
 }
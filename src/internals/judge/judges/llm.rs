@@ -1,60 +1,543 @@
+use std::time::Duration;
+
 use anyhow::Context;
 use async_trait::async_trait;
-use reqwest::Url;
+use itertools::Itertools;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tracing::instrument;
 
 use crate::internals::{
-    judge::judge_manager::{Judge, ResponseFormat},
+    judge::judge_manager::{AcceptancePolicy, Explanation, Judge, Verdict},
     search::search_manager::JudgeSubmission,
 };
 
+/// System prompt used when no template file is configured.
+pub const DEFAULT_PROMPT: &str = include_str!("../../../../prompts/llm_judge.txt");
+
+/// Why a chat completion did not produce a score.
+#[derive(Debug, thiserror::Error)]
+pub enum LlmError {
+    #[error("invalid LLM endpoint {0}")]
+    InvalidEndpoint(String),
+    #[error("LLM request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("LLM request failed: {0}")]
+    Transport(#[source] reqwest::Error),
+    #[error("LLM server answered {status}: {body}")]
+    Status { status: StatusCode, body: String },
+    #[error("LLM refused to answer: {0}")]
+    Refused(String),
+    #[error("malformed LLM response ({reason}): {content}")]
+    Malformed { reason: String, content: String },
+    #[error("LLM score {0} is outside [0, 1]")]
+    ScoreOutOfRange(f32),
+}
+
+impl LlmError {
+    /// Worth trying again: the server may answer differently next time.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LlmError::Timeout(_) | LlmError::Transport(_) => true,
+            LlmError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            LlmError::Malformed { .. } | LlmError::ScoreOutOfRange(_) => true,
+            LlmError::InvalidEndpoint(_) | LlmError::Refused(_) => false,
+        }
+    }
+
+    fn malformed(reason: impl Into<String>, content: impl Into<String>) -> Self {
+        LlmError::Malformed {
+            reason: reason.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// Appended to the system prompt when several candidates are sent at once.
+const BATCH_INSTRUCTIONS: &str = "\n\nIn this conversation `track` is replaced by `tracks`, a list of candidate files for the same query, each with an `index`. Score every candidate on its own, as if it were the only one, and answer with a JSON object `{\"scores\": [{\"index\": number, \"score\": number}]}` holding exactly one entry per candidate.";
+
+/// Strips the markdown fences some local models put around JSON even in
+/// structured output mode.
+fn unfence(content: &str) -> &str {
+    let trimmed = content.trim();
+    trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(trimmed)
+        .trim()
+}
+
+fn check_score(score: f32) -> Result<f32, LlmError> {
+    if (0.0..=1.0).contains(&score) {
+        Ok(score)
+    } else {
+        Err(LlmError::ScoreOutOfRange(score))
+    }
+}
+
+/// The structured output the model is held to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LlmVerdict {
+    pub score: f32,
+}
+
+impl LlmVerdict {
+    fn schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": { "score": { "type": "number" } },
+            "required": ["score"],
+            "additionalProperties": false,
+        })
+    }
+
+    pub fn parse(content: &str) -> Result<Self, LlmError> {
+        let verdict: LlmVerdict = serde_json::from_str(unfence(content))
+            .map_err(|err| LlmError::malformed(err.to_string(), content))?;
+        check_score(verdict.score)?;
+        Ok(verdict)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexedScore {
+    pub index: usize,
+    pub score: f32,
+}
+
+/// The structured output for a batch of candidates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LlmBatchVerdict {
+    pub scores: Vec<IndexedScore>,
+}
+
+impl LlmBatchVerdict {
+    fn schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "scores": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "index": { "type": "integer" },
+                            "score": { "type": "number" },
+                        },
+                        "required": ["index", "score"],
+                        "additionalProperties": false,
+                    },
+                },
+            },
+            "required": ["scores"],
+            "additionalProperties": false,
+        })
+    }
+
+    /// Scores ordered by index, requiring exactly one per candidate.
+    pub fn parse(content: &str, candidates: usize) -> Result<Vec<f32>, LlmError> {
+        let verdict: LlmBatchVerdict = serde_json::from_str(unfence(content))
+            .map_err(|err| LlmError::malformed(err.to_string(), content))?;
+        let mut scores = vec![None; candidates];
+        for IndexedScore { index, score } in verdict.scores {
+            let slot = scores.get_mut(index).ok_or_else(|| {
+                LlmError::malformed(format!("unknown candidate index {index}"), content)
+            })?;
+            if slot.replace(check_score(score)?).is_some() {
+                return Err(LlmError::malformed(
+                    format!("candidate {index} scored twice"),
+                    content,
+                ));
+            }
+        }
+        scores
+            .into_iter()
+            .enumerate()
+            .map(|(index, score)| {
+                score.ok_or_else(|| {
+                    LlmError::malformed(format!("candidate {index} has no score"), content)
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletion {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    content: Option<String>,
+    refusal: Option<String>,
+}
+
+/// Fills `{{track}}`, `{{artist}}`, `{{album}}` and `{{filename}}` in a
+/// prompt template.
+pub fn render_prompt(template: &str, submission: &JudgeSubmission) -> String {
+    let track = &submission.track;
+    template
+        .replace("{{track}}", &track.track)
+        .replace("{{artist}}", &track.artist)
+        .replace("{{album}}", &track.album)
+        .replace("{{filename}}", &submission.query.filename)
+}
+
+/// Scores submissions with any OpenAI-compatible chat completions endpoint,
+/// from the OpenAI API to a local llama.cpp or ollama server.
 #[derive(Clone)]
-pub struct LocalLLM {
-    pub address: String,
-    pub port: i32,
+pub struct LlmJudge {
+    /// Base URL up to the API version, e.g. `http://localhost:8080/v1`.
+    pub base_url: String,
+    pub model: String,
+    pub api_key: Option<String>,
+    pub prompt: String,
+    pub timeout: Duration,
+    /// Attempts after the first for retryable errors.
+    pub max_retries: u32,
     pub score_cutoff: f32,
+    client: reqwest::Client,
 }
-impl LocalLLM {
-    pub fn new(address: String, port: i32, score_cutoff: f32) -> Self {
-        LocalLLM {
-            address,
-            port,
+
+impl LlmJudge {
+    pub fn new(base_url: String, model: String, score_cutoff: f32) -> Self {
+        LlmJudge {
+            base_url,
+            model,
+            api_key: None,
+            prompt: DEFAULT_PROMPT.to_string(),
+            timeout: Duration::from_secs(30),
+            max_retries: 2,
             score_cutoff,
+            client: reqwest::Client::new(),
         }
     }
-    #[instrument(name = "LocalLLM::get_score", skip(self, submission))]
-    async fn get_score(&self, submission: JudgeSubmission) -> anyhow::Result<ResponseFormat> {
-        let url = Url::parse(&format!("{}:{}/score", self.address, self.port)).unwrap();
-        let client = reqwest::Client::new();
-        let str_val = serde_json::to_string(&submission).context("Parsing own track")?;
-        let res = client
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+    pub fn with_prompt(mut self, prompt: String) -> Self {
+        self.prompt = prompt;
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    fn endpoint(&self) -> Result<Url, LlmError> {
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+        Url::parse(&url).map_err(|_| LlmError::InvalidEndpoint(url))
+    }
+
+    fn query_input(submission: &JudgeSubmission) -> serde_json::Value {
+        let track = &submission.track;
+        json!({
+            "track": track.track,
+            "album": track.album,
+            "artist": track.artist,
+            "duration_ms": track.duration_ms,
+        })
+    }
+
+    fn file_input(submission: &JudgeSubmission) -> serde_json::Value {
+        let file = &submission.query;
+        json!({
+            "filename": file.filename,
+            "username": file.username,
+            "size": file.size,
+            "bitrate": file.bitrate,
+            "duration_secs": file.duration_secs,
+        })
+    }
+
+    fn chat_body(
+        &self,
+        system: String,
+        input: serde_json::Value,
+        name: &str,
+        schema: serde_json::Value,
+    ) -> serde_json::Value {
+        json!({
+            "model": self.model,
+            "temperature": 0.0,
+            "messages": [
+                { "role": "system", "content": system },
+                { "role": "user", "content": input.to_string() },
+            ],
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": { "name": name, "strict": true, "schema": schema },
+            },
+        })
+    }
+
+    fn request_body(&self, submission: &JudgeSubmission) -> serde_json::Value {
+        let input = json!({
+            "query": Self::query_input(submission),
+            "track": Self::file_input(submission),
+        });
+        self.chat_body(
+            render_prompt(&self.prompt, submission),
+            input,
+            "match_score",
+            LlmVerdict::schema(),
+        )
+    }
+
+    /// Candidates must share the query, the first one fills the template.
+    fn batch_request_body(&self, submissions: &[JudgeSubmission]) -> serde_json::Value {
+        let tracks: Vec<serde_json::Value> = submissions
+            .iter()
+            .enumerate()
+            .map(|(index, submission)| {
+                let mut file = Self::file_input(submission);
+                file["index"] = json!(index);
+                file
+            })
+            .collect();
+        let input = json!({
+            "query": Self::query_input(&submissions[0]),
+            "tracks": tracks,
+        });
+        self.chat_body(
+            render_prompt(&self.prompt, &submissions[0]) + BATCH_INSTRUCTIONS,
+            input,
+            "match_scores",
+            LlmBatchVerdict::schema(),
+        )
+    }
+
+    /// The content of the first choice.
+    async fn complete(&self, url: Url, body: &serde_json::Value) -> Result<String, LlmError> {
+        let mut request = self
+            .client
             .post(url)
+            .timeout(self.timeout)
             .header("Content-Type", "application/json")
-            .body(str_val)
-            .send()
+            .body(body.to_string());
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let transport = |err: reqwest::Error| {
+            if err.is_timeout() {
+                LlmError::Timeout(self.timeout)
+            } else {
+                LlmError::Transport(err)
+            }
+        };
+        let response = request.send().await.map_err(transport)?;
+        let status = response.status();
+        let text = response.text().await.map_err(transport)?;
+        if !status.is_success() {
+            return Err(LlmError::Status { status, body: text });
+        }
+        let completion: ChatCompletion = serde_json::from_str(&text)
+            .map_err(|err| LlmError::malformed(err.to_string(), &text))?;
+        let message = completion
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| LlmError::malformed("no choices", &text))?
+            .message;
+        if let Some(refusal) = message.refusal {
+            return Err(LlmError::Refused(refusal));
+        }
+        message
+            .content
+            .ok_or_else(|| LlmError::malformed("no message content", &text))
+    }
+
+    /// Sends `body` and parses the answer, retrying retryable errors with
+    /// exponential backoff.
+    async fn request<T>(
+        &self,
+        body: &serde_json::Value,
+        parse: impl Fn(&str) -> Result<T, LlmError>,
+    ) -> Result<T, LlmError> {
+        let url = self.endpoint()?;
+        let mut attempt = 0;
+        loop {
+            let result = match self.complete(url.clone(), body).await {
+                Ok(content) => parse(&content),
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(err) if err.is_retryable() && attempt < self.max_retries => {
+                    attempt += 1;
+                    let backoff = Duration::from_millis(500 * 2u64.pow(attempt - 1));
+                    tracing::warn!(%err, attempt, ?backoff, "retrying LLM request");
+                    tokio::time::sleep(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[instrument(name = "LlmJudge::verdict", skip(self, submission), fields(model = self.model))]
+    pub async fn verdict(&self, submission: &JudgeSubmission) -> Result<LlmVerdict, LlmError> {
+        self.request(&self.request_body(submission), LlmVerdict::parse)
             .await
-            .context("Response from llm server")?;
-        let text = res.text().await.context("Error reading response")?;
-        let response: ResponseFormat = serde_json::from_str(&text).context("parsing response")?;
-        Ok(response)
+    }
+
+    /// Scores candidates for one query with a single request.
+    #[instrument(name = "LlmJudge::batch_verdict", skip(self, submissions), fields(model = self.model, candidates = submissions.len()))]
+    pub async fn batch_verdict(
+        &self,
+        submissions: &[JudgeSubmission],
+    ) -> Result<Vec<f32>, LlmError> {
+        let candidates = submissions.len();
+        self.request(&self.batch_request_body(submissions), |content| {
+            LlmBatchVerdict::parse(content, candidates)
+        })
+        .await
     }
 }
 
 #[async_trait]
-impl Judge for LocalLLM {
-    #[instrument(name = "LocalLLM::judge", skip(self), fields(username = submission.query.username , query_song = submission.track.track, file_q = submission.query.filename))]
-    async fn judge(&self, submission: JudgeSubmission) -> anyhow::Result<bool> {
-        let response = self.get_score(submission).await.context("Getting score")?;
-        tracing::info!("{:?}", response);
-        match response.score {
-            x if x.unwrap_or_default() > self.score_cutoff => Ok(true),
-            _ => Ok(false),
+impl Judge for LlmJudge {
+    fn name(&self) -> &'static str {
+        "llm"
+    }
+    fn policy(&self) -> AcceptancePolicy {
+        AcceptancePolicy::new(self.score_cutoff)
+    }
+    #[instrument(name = "LlmJudge::judge_score", skip(self), fields(username = submission.query.username , query_song = submission.track.track, file_q = submission.query.filename))]
+    async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+        Ok(self.verdict(&submission).await?.score)
+    }
+
+    /// Groups candidates by track and sends each group in one request, so
+    /// `judge_batch_size` bounds the size of a request.
+    async fn judge_batch(&self, submissions: Vec<JudgeSubmission>) -> anyhow::Result<Vec<f32>> {
+        let mut scores = vec![0.0; submissions.len()];
+        let groups = submissions
+            .into_iter()
+            .enumerate()
+            .into_group_map_by(|(_, submission)| submission.track.track_id.clone());
+        for group in groups.into_values() {
+            let (indices, group): (Vec<usize>, Vec<JudgeSubmission>) = group.into_iter().unzip();
+            let group_scores = match group.as_slice() {
+                [submission] => vec![self.verdict(submission).await?.score],
+                group => self.batch_verdict(group).await?,
+            };
+            for (index, score) in indices.into_iter().zip(group_scores) {
+                scores[index] = score;
+            }
         }
+        Ok(scores)
     }
 
-    #[instrument(name = "LocalLLM::judge", skip(self), fields(username = submission.query.username , query_song = submission.track.track, file_q = submission.query.filename))]
-    async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
-        let response = self.get_score(submission).await.context("Getting score")?;
-        Ok(response.score.unwrap_or_default())
+    async fn explain(&self, submission: JudgeSubmission) -> anyhow::Result<Verdict> {
+        let verdicts = self.explain_batch(vec![submission]).await?;
+        verdicts.into_iter().next().context("no verdict")
+    }
+    async fn explain_batch(
+        &self,
+        submissions: Vec<JudgeSubmission>,
+    ) -> anyhow::Result<Vec<Verdict>> {
+        let explanation = Explanation::default().with_rule(format!("scored by {}", self.model));
+        Ok(self
+            .judge_batch(submissions)
+            .await?
+            .into_iter()
+            .map(|score| Verdict::new(score).with_explanation(explanation.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internals::search::search_manager::{DownloadableFile, SearchItem};
+
+    #[test]
+    fn parses_plain_and_fenced_json() {
+        assert_eq!(LlmVerdict::parse(r#"{"score": 0.9}"#).unwrap().score, 0.9);
+        assert_eq!(
+            LlmVerdict::parse("```json\n{\"score\": 0.25}\n```")
+                .unwrap()
+                .score,
+            0.25
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_and_out_of_range_scores() {
+        assert!(matches!(
+            LlmVerdict::parse("The score is 0.9"),
+            Err(LlmError::Malformed { .. })
+        ));
+        assert!(matches!(
+            LlmVerdict::parse(r#"{"score": 0.9, "filename": "x"}"#),
+            Err(LlmError::Malformed { .. })
+        ));
+        assert!(matches!(
+            LlmVerdict::parse(r#"{"score": 87}"#),
+            Err(LlmError::ScoreOutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn parses_batch_scores_by_index() {
+        let scores = LlmBatchVerdict::parse(
+            r#"{"scores": [{"index": 1, "score": 0.2}, {"index": 0, "score": 0.8}]}"#,
+            2,
+        )
+        .unwrap();
+        assert_eq!(scores, [0.8, 0.2]);
+    }
+
+    #[test]
+    fn rejects_incomplete_batches() {
+        for content in [
+            r#"{"scores": [{"index": 0, "score": 0.8}]}"#,
+            r#"{"scores": [{"index": 0, "score": 0.8}, {"index": 0, "score": 0.1}]}"#,
+            r#"{"scores": [{"index": 0, "score": 0.8}, {"index": 2, "score": 0.1}]}"#,
+        ] {
+            assert!(
+                matches!(
+                    LlmBatchVerdict::parse(content, 2),
+                    Err(LlmError::Malformed { .. })
+                ),
+                "{content}"
+            );
+        }
+    }
+
+    #[test]
+    fn renders_template_placeholders() {
+        let submission = JudgeSubmission {
+            track: SearchItem::new(
+                "Creep".to_string(),
+                "Pablo Honey".to_string(),
+                "Radiohead".to_string(),
+            ),
+            query: DownloadableFile::new("Creep.mp3".to_string(), "peer".to_string(), 0),
+        };
+        assert_eq!(
+            render_prompt(
+                "{{artist}} - {{track}} ({{album}}): {{filename}}",
+                &submission
+            ),
+            "Radiohead - Creep (Pablo Honey): Creep.mp3"
+        );
     }
 }
@@ -1,2 +1,7 @@
+pub mod cache;
+pub mod composite;
 pub mod levenshtein;
 pub mod llm;
+pub mod plausibility;
+pub mod token;
+pub mod version;
@@ -0,0 +1,258 @@
+use async_trait::async_trait;
+use tracing::instrument;
+
+use crate::internals::{
+    judge::judge_manager::{AcceptancePolicy, Explanation, Judge, Verdict},
+    search::search_manager::{DownloadableFile, JudgeSubmission},
+};
+
+/// Size over what bitrate × duration predicts. Container overhead, cover art
+/// and VBR averaging keep honest files inside this range.
+const SIZE_RATIO: (f32, f32) = (0.75, 1.35);
+
+/// Checks, each in `[0, 1]`, that could be computed from what the peer
+/// advertised. `None` when the data for a check is missing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlausibilityScores {
+    /// Advertised duration against the track's.
+    pub duration: Option<f32>,
+    /// Size against advertised bitrate × duration.
+    pub size: Option<f32>,
+    /// Bitrate implied by size and the track's duration against the minimum.
+    pub bitrate: Option<f32>,
+}
+
+impl PlausibilityScores {
+    /// The weakest check. Files nothing could be checked on pass.
+    pub fn total(&self) -> f32 {
+        [self.duration, self.size, self.bitrate]
+            .into_iter()
+            .flatten()
+            .fold(1.0, f32::min)
+    }
+}
+
+/// Rejects truncated previews and files whose advertised attributes do not
+/// add up, using the Soulseek attributes and the Spotify duration. It does not
+/// look at names, so it is meant to run next to another judge.
+#[derive(Debug, Clone)]
+pub struct PlausibilityJudge {
+    pub score_cutoff: f32,
+    /// Duration differences up to this many seconds are not penalized.
+    pub duration_tolerance_secs: u32,
+    pub min_bitrate_kbps: u32,
+}
+
+impl PlausibilityJudge {
+    pub fn new(score_cutoff: f32) -> Self {
+        PlausibilityJudge {
+            score_cutoff,
+            duration_tolerance_secs: 5,
+            min_bitrate_kbps: 64,
+        }
+    }
+    pub fn with_duration_tolerance_secs(mut self, duration_tolerance_secs: u32) -> Self {
+        self.duration_tolerance_secs = duration_tolerance_secs;
+        self
+    }
+    pub fn with_min_bitrate_kbps(mut self, min_bitrate_kbps: u32) -> Self {
+        self.min_bitrate_kbps = min_bitrate_kbps;
+        self
+    }
+
+    pub fn scores(&self, submission: &JudgeSubmission) -> PlausibilityScores {
+        let file = &submission.query;
+        let expected_secs = submission
+            .track
+            .duration_ms
+            .filter(|ms| *ms > 0)
+            .map(|ms| ms as f32 / 1000.0);
+        let advertised_secs = file
+            .duration_secs
+            .filter(|secs| *secs > 0)
+            .map(|secs| secs as f32);
+        PlausibilityScores {
+            duration: expected_secs
+                .zip(advertised_secs)
+                .map(|(expected, advertised)| self.duration_score(expected, advertised)),
+            size: advertised_secs
+                .or(expected_secs)
+                .and_then(|secs| Self::size_score(file, secs)),
+            bitrate: expected_secs.and_then(|secs| self.bitrate_score(file, secs)),
+        }
+    }
+
+    /// 1 within the tolerance, then down to 0 once the difference reaches a
+    /// quarter of the track, so a 30 second preview of a 4 minute song is 0.
+    fn duration_score(&self, expected: f32, advertised: f32) -> f32 {
+        let excess = (advertised - expected).abs() - self.duration_tolerance_secs as f32;
+        if excess <= 0.0 {
+            return 1.0;
+        }
+        (1.0 - excess / (expected * 0.25)).max(0.0)
+    }
+
+    fn size_score(file: &DownloadableFile, secs: f32) -> Option<f32> {
+        let bitrate = file.bitrate.filter(|kbps| *kbps > 0)?;
+        if file.size <= 0 {
+            return None;
+        }
+        let expected_bytes = bitrate as f32 * 1000.0 / 8.0 * secs;
+        let ratio = file.size as f32 / expected_bytes;
+        let (low, high) = SIZE_RATIO;
+        Some(if ratio < low {
+            (ratio / low).powi(2)
+        } else if ratio > high {
+            (high / ratio).powi(2)
+        } else {
+            1.0
+        })
+    }
+
+    /// The checks as fields, with a rule for each that failed outright.
+    pub fn explanation(
+        &self,
+        submission: &JudgeSubmission,
+        scores: &PlausibilityScores,
+    ) -> Explanation {
+        let file = &submission.query;
+        let mut explanation = Explanation::default();
+        for (field, score) in [
+            ("duration", scores.duration),
+            ("size", scores.size),
+            ("bitrate", scores.bitrate),
+        ] {
+            if let Some(score) = score {
+                explanation = explanation.with_field(field, score);
+            }
+        }
+        let expected_secs = submission.track.duration_ms.map(|ms| ms / 1000);
+        if scores.duration.is_some_and(|score| score < 1.0) {
+            explanation = explanation.with_rule(format!(
+                "advertised {}s for a {}s track",
+                file.duration_secs.unwrap_or_default(),
+                expected_secs.unwrap_or_default()
+            ));
+        }
+        if scores.size.is_some_and(|score| score < 1.0) {
+            explanation = explanation.with_rule(format!(
+                "{} bytes do not fit {} kbps",
+                file.size,
+                file.bitrate.unwrap_or_default()
+            ));
+        }
+        if scores.bitrate.is_some_and(|score| score < 1.0) {
+            explanation = explanation.with_rule(format!(
+                "size implies less than {} kbps",
+                self.min_bitrate_kbps
+            ));
+        }
+        explanation
+    }
+
+    fn bitrate_score(&self, file: &DownloadableFile, expected_secs: f32) -> Option<f32> {
+        if file.size <= 0 || self.min_bitrate_kbps == 0 {
+            return None;
+        }
+        let implied_kbps = file.size as f32 * 8.0 / 1000.0 / expected_secs;
+        Some(
+            (implied_kbps / self.min_bitrate_kbps as f32)
+                .min(1.0)
+                .powi(2),
+        )
+    }
+}
+
+#[async_trait]
+impl Judge for PlausibilityJudge {
+    fn name(&self) -> &'static str {
+        "plausibility"
+    }
+    fn policy(&self) -> AcceptancePolicy {
+        AcceptancePolicy::new(self.score_cutoff)
+    }
+    #[instrument(name = "PlausibilityJudge::judge_score", skip(self, submission), fields(id = submission.track.track_id, username = submission.query.username, file_q = submission.query.filename))]
+    async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+        Ok(self.explain(submission).await?.score)
+    }
+    async fn explain(&self, submission: JudgeSubmission) -> anyhow::Result<Verdict> {
+        let scores = self.scores(&submission);
+        tracing::info!(
+            duration = scores.duration,
+            size = scores.size,
+            bitrate = scores.bitrate,
+            "plausibility scores"
+        );
+        let explanation = self.explanation(&submission, &scores);
+        Ok(Verdict::new(scores.total()).with_explanation(explanation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FOUR_MINUTES_MS: i32 = 240_000;
+
+    fn submission(duration_ms: Option<i32>, file: DownloadableFile) -> JudgeSubmission {
+        let mut submission = JudgeSubmission::creep(&file.filename);
+        submission.track = submission.track.with_duration_ms(duration_ms);
+        submission.query = file;
+        submission
+    }
+
+    fn mp3(size: i32, bitrate: Option<i32>, duration_secs: Option<i32>) -> DownloadableFile {
+        DownloadableFile {
+            bitrate,
+            duration_secs,
+            ..DownloadableFile::new("song.mp3".to_string(), "peer".to_string(), size)
+        }
+    }
+
+    fn score(duration_ms: Option<i32>, file: DownloadableFile) -> f32 {
+        PlausibilityJudge::new(0.5)
+            .scores(&submission(duration_ms, file))
+            .total()
+    }
+
+    #[test]
+    fn accepts_consistent_files() {
+        // 320 kbps for four minutes is 9.6 MB.
+        let total = score(Some(FOUR_MINUTES_MS), mp3(9_800_000, Some(320), Some(241)));
+        assert_eq!(total, 1.0);
+    }
+
+    #[test]
+    fn passes_files_without_attributes() {
+        assert_eq!(score(None, mp3(0, None, None)), 1.0);
+        assert_eq!(score(Some(FOUR_MINUTES_MS), mp3(0, None, None)), 1.0);
+    }
+
+    #[test]
+    fn rejects_previews_by_advertised_duration() {
+        let total = score(Some(FOUR_MINUTES_MS), mp3(1_200_000, Some(320), Some(30)));
+        assert_eq!(total, 0.0);
+    }
+
+    #[test]
+    fn rejects_previews_by_size_without_attributes() {
+        // A 30 second 320 kbps clip spread over four minutes is 40 kbps.
+        let total = score(Some(FOUR_MINUTES_MS), mp3(1_200_000, None, None));
+        assert!(total < 0.5, "{total}");
+    }
+
+    #[test]
+    fn rejects_bitrates_the_size_does_not_support() {
+        // Advertised as 320 kbps but sized like a 128 kbps file.
+        let total = score(Some(FOUR_MINUTES_MS), mp3(3_840_000, Some(320), Some(240)));
+        assert!(total < 0.5, "{total}");
+    }
+
+    #[test]
+    fn tolerates_small_duration_differences() {
+        let judge = PlausibilityJudge::new(0.5);
+        assert_eq!(judge.duration_score(240.0, 244.0), 1.0);
+        let edit = judge.duration_score(240.0, 255.0);
+        assert!(edit > 0.5 && edit < 1.0, "{edit}");
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::{BTreeSet, HashSet};
+
+use async_trait::async_trait;
+use tracing::instrument;
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+use crate::internals::{
+    judge::judge_manager::{AcceptancePolicy, Explanation, Judge, Verdict},
+    search::search_manager::JudgeSubmission,
+};
+
+const TITLE_WEIGHT: f32 = 0.6;
+const ARTIST_WEIGHT: f32 = 0.3;
+const ALBUM_WEIGHT: f32 = 0.1;
+const IGNORED_TOKENS: [&str; 5] = ["the", "feat", "ft", "featuring", "and"];
+/// Reported as the `format` field, which does not count towards the score.
+const AUDIO_EXTENSIONS: [&str; 10] = [
+    "mp3", "flac", "aiff", "aif", "wav", "m4a", "ogg", "opus", "aac", "wma",
+];
+
+/// A Soulseek path split into the pieces a query is matched against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedPath {
+    /// Directories, outermost first, without the `@@share` root.
+    pub folders: Vec<String>,
+    /// File name without directories and extension.
+    pub stem: String,
+    pub extension: Option<String>,
+}
+
+impl ParsedPath {
+    pub fn parse(path: &str) -> Self {
+        let mut segments: Vec<&str> = path
+            .split(['\\', '/'])
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let filename = segments.pop().unwrap_or_default();
+        if segments.first().is_some_and(|root| root.starts_with("@@")) {
+            segments.remove(0);
+        }
+        let (stem, extension) = match filename.rsplit_once('.') {
+            Some((stem, extension))
+                if !stem.is_empty()
+                    && (1..=5).contains(&extension.len())
+                    && extension.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                (stem, Some(extension.to_lowercase()))
+            }
+            _ => (filename, None),
+        };
+        ParsedPath {
+            folders: segments.into_iter().map(str::to_string).collect(),
+            stem: stem.to_string(),
+            extension,
+        }
+    }
+}
+
+/// Lowercases, strips diacritics and splits on anything but letters and digits.
+pub fn tokenize(value: &str) -> Vec<String> {
+    let folded: String = value
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .filter(|c| !matches!(c, '\'' | '’'))
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    folded.split_whitespace().map(str::to_string).collect()
+}
+
+fn significant(value: &str) -> HashSet<String> {
+    tokenize(value)
+        .into_iter()
+        .filter(|token| !IGNORED_TOKENS.contains(&token.as_str()))
+        .collect()
+}
+
+/// Track numbers like `01`, `1-07` or vinyl sides like `a1`, unless the
+/// title itself starts with them, as in "99 Luftballons".
+fn is_track_number(token: &str, title: &HashSet<String>) -> bool {
+    if title.contains(token) {
+        return false;
+    }
+    let digits = token.trim_start_matches(|c: char| matches!(c, 'a'..='d'));
+    !digits.is_empty()
+        && digits.len() <= 3
+        && digits.len() + 1 >= token.len()
+        && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Share of `wanted` found in `found`. Empty `wanted` counts as found.
+fn containment(wanted: &HashSet<String>, found: &HashSet<String>) -> f32 {
+    if wanted.is_empty() {
+        return 1.0;
+    }
+    wanted.intersection(found).count() as f32 / wanted.len() as f32
+}
+
+/// Per field scores, each in `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenScores {
+    pub title: f32,
+    pub artist: f32,
+    pub album: Option<f32>,
+}
+
+impl TokenScores {
+    pub fn compute(track: &str, artist: &str, album: &str, path: &ParsedPath) -> Self {
+        let title = significant(track);
+        let artist = significant(artist);
+        let album = significant(album);
+
+        let mut stem: Vec<String> = tokenize(&path.stem);
+        let leading = stem
+            .iter()
+            .take_while(|token| is_track_number(token, &title))
+            .count();
+        stem.drain(..leading);
+        let stem: HashSet<String> = stem.into_iter().collect();
+        let folders: Vec<HashSet<String>> = path
+            .folders
+            .iter()
+            .map(|folder| significant(folder))
+            .collect();
+
+        let recall = containment(&title, &stem);
+        // Words in the file name that belong to neither title, artist nor album.
+        let rest: HashSet<String> = stem
+            .iter()
+            .filter(|token| !artist.contains(*token) && !album.contains(*token))
+            .filter(|token| !IGNORED_TOKENS.contains(&token.as_str()))
+            .cloned()
+            .collect();
+        let precision = if rest.is_empty() {
+            1.0
+        } else {
+            title.intersection(&rest).count() as f32 / rest.len() as f32
+        };
+        let title_score = 0.8 * recall + 0.2 * precision;
+
+        let artist_score = std::iter::once(&stem)
+            .chain(folders.iter())
+            .map(|segment| containment(&artist, segment))
+            .fold(0.0, f32::max);
+        let album_score = (!album.is_empty()).then(|| {
+            folders
+                .iter()
+                .map(|segment| containment(&album, segment))
+                .fold(0.0, f32::max)
+        });
+        TokenScores {
+            title: title_score,
+            artist: artist_score,
+            album: album_score,
+        }
+    }
+
+    /// Weighted sum. Without an album its weight goes to title and artist.
+    pub fn total(&self) -> f32 {
+        match self.album {
+            Some(album) => {
+                TITLE_WEIGHT * self.title + ARTIST_WEIGHT * self.artist + ALBUM_WEIGHT * album
+            }
+            None => {
+                (TITLE_WEIGHT * self.title + ARTIST_WEIGHT * self.artist)
+                    / (TITLE_WEIGHT + ARTIST_WEIGHT)
+            }
+        }
+    }
+}
+
+/// Matches query fields against the parts of a P2P path: the title against
+/// the file name, the artist against any segment, the album against folders.
+#[derive(Clone)]
+pub struct TokenJudge {
+    pub score_cutoff: f32,
+}
+
+impl TokenJudge {
+    pub fn new(score_cutoff: f32) -> Self {
+        TokenJudge { score_cutoff }
+    }
+
+    pub fn scores(submission: &JudgeSubmission) -> TokenScores {
+        let path = ParsedPath::parse(&submission.query.filename);
+        let track = &submission.track;
+        TokenScores::compute(&track.track, &track.artist, &track.album, &path)
+    }
+
+    /// Field scores, plus which query words appear anywhere in the path.
+    pub fn explanation(submission: &JudgeSubmission, scores: &TokenScores) -> Explanation {
+        let path = ParsedPath::parse(&submission.query.filename);
+        let found: HashSet<String> = std::iter::once(&path.stem)
+            .chain(path.folders.iter())
+            .flat_map(|segment| tokenize(segment))
+            .collect();
+        let track = &submission.track;
+        let wanted: BTreeSet<String> = [&track.track, &track.artist, &track.album]
+            .into_iter()
+            .flat_map(|field| significant(field))
+            .collect();
+        let (matched, missing): (Vec<String>, Vec<String>) =
+            wanted.into_iter().partition(|token| found.contains(token));
+        let audio = path
+            .extension
+            .as_deref()
+            .is_some_and(|extension| AUDIO_EXTENSIONS.contains(&extension));
+        let mut explanation = Explanation {
+            matched,
+            missing,
+            ..Explanation::default()
+        }
+        .with_field("title", scores.title)
+        .with_field("artist", scores.artist)
+        .with_field("format", if audio { 1.0 } else { 0.0 });
+        if let Some(album) = scores.album {
+            explanation = explanation.with_field("album", album);
+        }
+        if !audio {
+            let extension = path.extension.as_deref().unwrap_or("none");
+            explanation = explanation.with_rule(format!("extension {extension} is not audio"));
+        }
+        explanation
+    }
+}
+
+#[async_trait]
+impl Judge for TokenJudge {
+    fn name(&self) -> &'static str {
+        "token"
+    }
+    fn policy(&self) -> AcceptancePolicy {
+        AcceptancePolicy::new(self.score_cutoff)
+    }
+    #[instrument(name = "TokenJudge::judge_score", skip(self, submission), fields(id = submission.track.track_id, username = submission.query.username, query_song = submission.track.track, file_q = submission.query.filename))]
+    async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+        Ok(self.explain(submission).await?.score)
+    }
+    async fn explain(&self, submission: JudgeSubmission) -> anyhow::Result<Verdict> {
+        let scores = Self::scores(&submission);
+        tracing::info!(
+            title = scores.title,
+            artist = scores.artist,
+            album = scores.album,
+            "token scores"
+        );
+        let explanation = Self::explanation(&submission, &scores);
+        Ok(Verdict::new(scores.total()).with_explanation(explanation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internals::search::search_manager::{DownloadableFile, SearchItem};
+
+    fn score(track: &str, artist: &str, album: &str, filename: &str) -> f32 {
+        TokenScores::compute(track, artist, album, &ParsedPath::parse(filename)).total()
+    }
+
+    #[test]
+    fn parses_windows_share_paths() {
+        let path = ParsedPath::parse(
+            r"@@share\Music\Radiohead\OK Computer (1997)\02 - Paranoid Android.flac",
+        );
+        assert_eq!(path.folders, ["Music", "Radiohead", "OK Computer (1997)"]);
+        assert_eq!(path.stem, "02 - Paranoid Android");
+        assert_eq!(path.extension.as_deref(), Some("flac"));
+    }
+
+    #[test]
+    fn parses_unix_paths_and_names_without_extension() {
+        let path = ParsedPath::parse("music/Björk/Homogenic/Jóga");
+        assert_eq!(path.folders, ["music", "Björk", "Homogenic"]);
+        assert_eq!(path.stem, "Jóga");
+        assert_eq!(path.extension, None);
+    }
+
+    #[test]
+    fn tokenize_folds_case_diacritics_and_punctuation() {
+        assert_eq!(
+            tokenize("Sigur Rós - Ágætis byrjun"),
+            ["sigur", "ros", "agætis", "byrjun"]
+        );
+        assert_eq!(
+            tokenize("Don't Stop Me Now!"),
+            ["dont", "stop", "me", "now"]
+        );
+        assert_eq!(tokenize("Beyoncé_-_Halo"), ["beyonce", "halo"]);
+    }
+
+    #[test]
+    fn strips_track_numbers_but_keeps_numeric_titles() {
+        let title = significant("99 Luftballons");
+        assert!(!is_track_number("99", &title));
+        assert!(is_track_number("01", &title));
+        assert!(is_track_number("a1", &title));
+        assert!(!is_track_number("2001", &title));
+        assert_eq!(
+            score(
+                "99 Luftballons",
+                "Nena",
+                "99 Luftballons",
+                r"@@m\Nena\99 Luftballons\01 - 99 Luftballons.mp3"
+            ),
+            1.0
+        );
+    }
+
+    #[test]
+    fn accepts_exact_matches_in_album_folders() {
+        let total = score(
+            "Paranoid Android",
+            "Radiohead",
+            "OK Computer",
+            r"@@share\Music\Radiohead\OK Computer (1997)\02 - Paranoid Android.flac",
+        );
+        assert_eq!(total, 1.0);
+    }
+
+    #[test]
+    fn finds_artist_in_file_name() {
+        let total = score(
+            "One More Time",
+            "Daft Punk",
+            "Discovery",
+            r"@@xyz\Soulseek Downloads\complete\Daft Punk - Discovery [2001] FLAC\01. Daft Punk - One More Time.flac",
+        );
+        assert_eq!(total, 1.0);
+    }
+
+    #[test]
+    fn handles_disc_track_prefixes_and_compilations() {
+        let total = score(
+            "Halo",
+            "Beyoncé",
+            "I Am... Sasha Fierce",
+            r"music\mp3\Various\Now 73\1-07 Beyonce - Halo.mp3",
+        );
+        assert!(total > 0.85, "{total}");
+    }
+
+    #[test]
+    fn rejects_other_songs_from_the_right_album() {
+        let total = score(
+            "Paranoid Android",
+            "Radiohead",
+            "OK Computer",
+            r"@@share\Music\Radiohead\OK Computer\03 - Subterranean Homesick Alien.flac",
+        );
+        assert!(total < 0.5, "{total}");
+    }
+
+    #[test]
+    fn penalizes_wrong_artist() {
+        let right = score(
+            "Hurt",
+            "Johnny Cash",
+            "",
+            r"@@a\Johnny Cash\American IV\02 Hurt.mp3",
+        );
+        let wrong = score(
+            "Hurt",
+            "Johnny Cash",
+            "",
+            r"@@a\Nine Inch Nails\The Downward Spiral\14 Hurt.mp3",
+        );
+        assert_eq!(right, 1.0);
+        assert!(wrong < 0.7, "{wrong}");
+    }
+
+    #[test]
+    fn penalizes_extra_words_in_file_name() {
+        let plain = score("Creep", "Radiohead", "", r"Radiohead\Creep.mp3");
+        let extra = score(
+            "Creep",
+            "Radiohead",
+            "",
+            r"Radiohead\Creep (Acoustic Live at KROQ).mp3",
+        );
+        assert!(extra < plain, "{extra} >= {plain}");
+    }
+
+    #[tokio::test]
+    async fn explains_fields_and_missing_words() {
+        let submission = JudgeSubmission {
+            track: SearchItem::new(
+                "Paranoid Android".to_string(),
+                "OK Computer".to_string(),
+                "Radiohead".to_string(),
+            ),
+            query: DownloadableFile::new(
+                r"@@share\Radiohead\02 - Paranoid.flac".to_string(),
+                "peer".to_string(),
+                0,
+            ),
+        };
+        let verdict = TokenJudge::new(0.8).explain(submission).await.unwrap();
+        let explanation = verdict.explanation;
+        assert_eq!(explanation.fields["artist"], 1.0);
+        assert_eq!(explanation.fields["format"], 1.0);
+        assert!(explanation.fields["title"] < 1.0);
+        assert_eq!(explanation.matched, ["paranoid", "radiohead"]);
+        assert_eq!(explanation.missing, ["android", "computer", "ok"]);
+    }
+}
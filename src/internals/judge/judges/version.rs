@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::internals::{
+    judge::{
+        judge_manager::{AcceptancePolicy, Judge},
+        judges::token::{ParsedPath, tokenize},
+    },
+    search::search_manager::JudgeSubmission,
+};
+
+/// Words that mark a recording as something other than the studio version,
+/// keyed by language.
+pub fn default_keywords() -> BTreeMap<String, Vec<String>> {
+    let keywords = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+    BTreeMap::from([
+        (
+            "en".to_string(),
+            keywords(&[
+                "remix",
+                "live",
+                "karaoke",
+                "instrumental",
+                "cover",
+                "acoustic",
+                "demo",
+                "acapella",
+                "a cappella",
+                "nightcore",
+                "sped up",
+                "slowed",
+            ]),
+        ),
+        (
+            "es".to_string(),
+            keywords(&[
+                "en vivo",
+                "en directo",
+                "remezcla",
+                "acustico",
+                "version karaoke",
+            ]),
+        ),
+        ("pt".to_string(), keywords(&["ao vivo", "acustico"])),
+        ("fr".to_string(), keywords(&["en direct", "en concert"])),
+        ("de".to_string(), keywords(&["unplugged"])),
+    ])
+}
+
+/// A qualifier present on one side only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionMismatch {
+    pub qualifier: String,
+    /// True when the file has the qualifier and the query does not.
+    pub in_file: bool,
+}
+
+/// Rejects files whose version qualifiers (live, remix, karaoke...) differ
+/// from the query's. Only the file name is checked, so a band called "Live"
+/// in a folder name does not count.
+#[derive(Debug, Clone)]
+pub struct VersionFilter {
+    keywords: Vec<Vec<String>>,
+}
+
+impl VersionFilter {
+    pub fn new(keywords: &BTreeMap<String, Vec<String>>) -> Self {
+        let mut keywords: Vec<Vec<String>> = keywords
+            .values()
+            .flatten()
+            .map(|keyword| tokenize(keyword))
+            .filter(|tokens| !tokens.is_empty())
+            .collect();
+        keywords.sort();
+        keywords.dedup();
+        VersionFilter { keywords }
+    }
+
+    fn qualifiers(&self, tokens: &[String]) -> Vec<String> {
+        self.keywords
+            .iter()
+            .filter(|keyword| {
+                tokens
+                    .windows(keyword.len())
+                    .any(|window| window == *keyword)
+            })
+            .map(|keyword| keyword.join(" "))
+            .collect()
+    }
+
+    pub fn check(&self, submission: &JudgeSubmission) -> Option<VersionMismatch> {
+        let track = &submission.track;
+        let query = tokenize(&track.track);
+        let context = tokenize(&format!("{} {}", track.artist, track.album));
+        let file = tokenize(&ParsedPath::parse(&submission.query.filename).stem);
+
+        let in_query = self.qualifiers(&query);
+        let in_context = self.qualifiers(&context);
+        let in_file = self.qualifiers(&file);
+        if let Some(qualifier) = in_file
+            .iter()
+            .find(|qualifier| !in_query.contains(qualifier) && !in_context.contains(qualifier))
+        {
+            return Some(VersionMismatch {
+                qualifier: qualifier.clone(),
+                in_file: true,
+            });
+        }
+        in_query
+            .into_iter()
+            .find(|qualifier| !in_file.contains(qualifier))
+            .map(|qualifier| VersionMismatch {
+                qualifier,
+                in_file: false,
+            })
+    }
+}
+
+impl Default for VersionFilter {
+    fn default() -> Self {
+        VersionFilter::new(&default_keywords())
+    }
+}
+
+/// As a judge it scores 1 for matching versions and 0 otherwise, for library
+/// users building their own `CompositeJudge`.
+#[async_trait]
+impl Judge for VersionFilter {
+    fn name(&self) -> &'static str {
+        "version"
+    }
+    fn policy(&self) -> AcceptancePolicy {
+        AcceptancePolicy::new(1.0)
+    }
+    async fn judge_score(&self, submission: JudgeSubmission) -> anyhow::Result<f32> {
+        Ok(match self.check(&submission) {
+            Some(_) => 0.0,
+            None => 1.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internals::search::search_manager::{DownloadableFile, SearchItem};
+
+    fn check(track: &str, album: &str, filename: &str) -> Option<VersionMismatch> {
+        let submission = JudgeSubmission {
+            track: SearchItem::new(track.to_string(), album.to_string(), "Nirvana".to_string()),
+            query: DownloadableFile::new(filename.to_string(), "peer".to_string(), 0),
+        };
+        VersionFilter::default().check(&submission)
+    }
+
+    fn mismatch(qualifier: &str, in_file: bool) -> Option<VersionMismatch> {
+        Some(VersionMismatch {
+            qualifier: qualifier.to_string(),
+            in_file,
+        })
+    }
+
+    #[test]
+    fn rejects_a_qualifier_only_the_file_has() {
+        assert_eq!(
+            check(
+                "Lithium",
+                "Nevermind",
+                "Nirvana\\Lithium (Live at Reading).mp3"
+            ),
+            mismatch("live", true)
+        );
+        assert_eq!(
+            check(
+                "Lithium",
+                "Nevermind",
+                "Nirvana\\Lithium (Karaoke Version).mp3"
+            ),
+            mismatch("karaoke", true)
+        );
+        assert_eq!(
+            check(
+                "Lithium",
+                "Nevermind",
+                "Nirvana\\Nevermind\\03 Lithium.flac"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn requires_a_qualifier_the_query_has() {
+        let track = "Lithium - Live";
+        assert_eq!(
+            check(track, "Nevermind", "Nirvana\\Lithium (Live at Reading).mp3"),
+            None
+        );
+        assert_eq!(
+            check(track, "Nevermind", "Nirvana\\Nevermind\\03 Lithium.flac"),
+            mismatch("live", false)
+        );
+    }
+
+    #[test]
+    fn ignores_qualifiers_in_folders_and_counts_them_in_the_album() {
+        // Only the file name is checked.
+        assert_eq!(
+            check(
+                "Lithium",
+                "Nevermind",
+                "Live Bootlegs\\Nirvana - Lithium.mp3"
+            ),
+            None
+        );
+        // A live album makes its tracks' live files expected.
+        assert_eq!(
+            check("Lithium", "Live at Reading", "Nirvana\\Lithium (Live).mp3"),
+            None
+        );
+    }
+
+    #[test]
+    fn matches_whole_words_only() {
+        assert_eq!(check("Alive", "Ten", "Pearl Jam\\Ten\\03 Alive.flac"), None);
+        assert_eq!(
+            check("Alive", "Ten", "Pearl Jam\\Alive (Live).flac"),
+            mismatch("live", true)
+        );
+    }
+}
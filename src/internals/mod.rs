@@ -1,8 +1,12 @@
 pub mod context;
 pub mod database;
 pub mod download;
+pub mod eval;
 pub mod judge;
+pub mod network;
 pub mod parsing;
 pub mod query;
 pub mod search;
+pub mod selection;
+pub mod sync;
 pub mod utils;
@@ -0,0 +1,332 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use soulseek_rs::{DownloadStatus, File, SearchResult};
+
+use crate::internals::network::network_manager::{SearchBackend, TransferBackend};
+
+/// Soulseek file attribute codes, as `DownloadableFile` reads them.
+const ATTRIBUTE_BITRATE: u32 = 0;
+const ATTRIBUTE_DURATION: u32 = 1;
+
+/// How a download from the fake network ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FakeTransfer {
+    /// Writes the whole file.
+    Complete,
+    /// The peer refuses, nothing is written.
+    Fail,
+    /// Writes this many bytes, then fails.
+    Partial(usize),
+    /// Reports nothing after queueing for this long, so the download times
+    /// out first.
+    Stall(Duration),
+}
+
+/// A file shared by a fake peer.
+#[derive(Debug, Clone)]
+pub struct FakeFile {
+    pub filename: String,
+    pub contents: Vec<u8>,
+    pub bitrate: Option<u32>,
+    pub duration_secs: Option<u32>,
+    pub transfer: FakeTransfer,
+}
+
+impl FakeFile {
+    pub fn new(filename: &str, contents: &[u8]) -> Self {
+        FakeFile {
+            filename: filename.to_string(),
+            contents: contents.to_vec(),
+            bitrate: None,
+            duration_secs: None,
+            transfer: FakeTransfer::Complete,
+        }
+    }
+    pub fn with_bitrate(mut self, bitrate: u32) -> Self {
+        self.bitrate = Some(bitrate);
+        self
+    }
+    pub fn with_duration_secs(mut self, duration_secs: u32) -> Self {
+        self.duration_secs = Some(duration_secs);
+        self
+    }
+    pub fn with_transfer(mut self, transfer: FakeTransfer) -> Self {
+        self.transfer = transfer;
+        self
+    }
+
+    fn shared_by(&self, username: &str) -> File {
+        let attribs = [
+            (ATTRIBUTE_BITRATE, self.bitrate),
+            (ATTRIBUTE_DURATION, self.duration_secs),
+        ]
+        .into_iter()
+        .filter_map(|(code, value)| value.map(|value| (code, value)))
+        .collect();
+        File {
+            username: username.to_string(),
+            name: self.filename.clone(),
+            size: self.contents.len() as u64,
+            attribs,
+        }
+    }
+}
+
+/// A peer answering a query.
+#[derive(Debug, Clone)]
+pub struct FakePeer {
+    pub username: String,
+    pub files: Vec<FakeFile>,
+    pub slots: u8,
+    /// Upload speed in bytes per second.
+    pub speed: u32,
+    /// How long after the search starts the peer answers.
+    pub latency: Duration,
+}
+
+impl FakePeer {
+    pub fn new(username: &str) -> Self {
+        FakePeer {
+            username: username.to_string(),
+            files: vec![],
+            slots: 1,
+            speed: 1_000_000,
+            latency: Duration::ZERO,
+        }
+    }
+    pub fn with_file(mut self, file: FakeFile) -> Self {
+        self.files.push(file);
+        self
+    }
+    pub fn with_slots(mut self, slots: u8) -> Self {
+        self.slots = slots;
+        self
+    }
+    pub fn with_speed(mut self, speed: u32) -> Self {
+        self.speed = speed;
+        self
+    }
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    fn result(&self) -> SearchResult {
+        SearchResult {
+            token: 0,
+            files: self
+                .files
+                .iter()
+                .map(|file| file.shared_by(&self.username))
+                .collect(),
+            slots: self.slots,
+            speed: self.speed,
+            username: self.username.clone(),
+        }
+    }
+}
+
+/// An in-process Soulseek network for tests. Peers answer exact queries with
+/// canned results once their latency passed, and downloads write the files'
+/// contents to disk. Searches and downloads are recorded in order.
+#[derive(Debug, Default)]
+pub struct FakeNetwork {
+    peers: HashMap<String, Vec<FakePeer>>,
+    /// Delay before a transfer reports anything.
+    pub transfer_latency: Duration,
+    started: Mutex<HashMap<String, Instant>>,
+    searched: Mutex<Vec<String>>,
+    downloads: Mutex<Vec<(String, String)>>,
+}
+
+impl FakeNetwork {
+    pub fn new() -> Self {
+        FakeNetwork::default()
+    }
+    /// `peer` answers searches for exactly `query`.
+    pub fn respond(mut self, query: &str, peer: FakePeer) -> Self {
+        self.peers.entry(query.to_string()).or_default().push(peer);
+        self
+    }
+    pub fn with_transfer_latency(mut self, transfer_latency: Duration) -> Self {
+        self.transfer_latency = transfer_latency;
+        self
+    }
+
+    /// Queries searched so far, in order.
+    pub fn searched(&self) -> Vec<String> {
+        self.searched.lock().expect("poisoned").clone()
+    }
+    /// Username and filename of every download started, in order.
+    pub fn downloads(&self) -> Vec<(String, String)> {
+        self.downloads.lock().expect("poisoned").clone()
+    }
+
+    fn file(&self, username: &str, filename: &str) -> Option<FakeFile> {
+        self.peers
+            .values()
+            .flatten()
+            .filter(|peer| peer.username == username)
+            .flat_map(|peer| &peer.files)
+            .find(|file| file.filename == filename)
+            .cloned()
+    }
+}
+
+impl SearchBackend for FakeNetwork {
+    fn login(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    fn search_with_cancel(
+        &self,
+        query: &str,
+        timeout: Duration,
+        cancel: Arc<AtomicBool>,
+    ) -> anyhow::Result<()> {
+        let start = Instant::now();
+        self.searched
+            .lock()
+            .expect("poisoned")
+            .push(query.to_string());
+        self.started
+            .lock()
+            .expect("poisoned")
+            .insert(query.to_string(), start);
+        while !cancel.load(Ordering::Relaxed) && start.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+    /// In the order the peers responded, so results are only ever appended
+    /// like the client's.
+    fn get_search_results(&self, query: &str) -> Vec<SearchResult> {
+        let Some(start) = self.started.lock().expect("poisoned").get(query).copied() else {
+            return vec![];
+        };
+        self.peers
+            .get(query)
+            .into_iter()
+            .flatten()
+            .filter(|peer| start.elapsed() >= peer.latency)
+            .sorted_by_key(|peer| peer.latency)
+            .map(FakePeer::result)
+            .collect()
+    }
+    fn get_search_results_count(&self, query: &str) -> usize {
+        let Some(start) = self.started.lock().expect("poisoned").get(query).copied() else {
+            return 0;
+        };
+        self.peers
+            .get(query)
+            .into_iter()
+            .flatten()
+            .filter(|peer| start.elapsed() >= peer.latency)
+            .count()
+    }
+}
+
+/// Plays a transfer out on `sender`, from another thread like the client.
+fn transfer(file: FakeFile, path: String, latency: Duration, sender: Sender<DownloadStatus>) {
+    thread::sleep(latency);
+    let _ = sender.send(DownloadStatus::Queued);
+    let total_bytes = file.contents.len() as u64;
+    let written = match file.transfer {
+        FakeTransfer::Complete => file.contents.len(),
+        FakeTransfer::Partial(bytes) => bytes.min(file.contents.len()),
+        FakeTransfer::Fail => {
+            let _ = sender.send(DownloadStatus::Failed);
+            return;
+        }
+        FakeTransfer::Stall(stall) => {
+            thread::sleep(stall);
+            return;
+        }
+    };
+    if std::fs::write(&path, &file.contents[..written]).is_err() {
+        let _ = sender.send(DownloadStatus::Failed);
+        return;
+    }
+    let _ = sender.send(DownloadStatus::InProgress {
+        bytes_downloaded: written as u64,
+        total_bytes,
+        speed_bytes_per_sec: written as f64,
+    });
+    let _ = sender.send(if written as u64 == total_bytes {
+        DownloadStatus::Completed
+    } else {
+        DownloadStatus::Failed
+    });
+}
+
+impl TransferBackend for FakeNetwork {
+    fn download(
+        &self,
+        filename: &str,
+        username: &str,
+        _size: u64,
+        path: &str,
+    ) -> anyhow::Result<Receiver<DownloadStatus>> {
+        self.downloads
+            .lock()
+            .expect("poisoned")
+            .push((username.to_string(), filename.to_string()));
+        let (sender, receiver) = mpsc::channel();
+        match self.file(username, filename) {
+            Some(file) => {
+                let (path, latency) = (path.to_string(), self.transfer_latency);
+                thread::spawn(move || transfer(file, path, latency, sender));
+            }
+            None => {
+                let _ = sender.send(DownloadStatus::Failed);
+            }
+        }
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_after_latency_and_writes_partial_downloads() {
+        let network = FakeNetwork::new().respond(
+            "Creep - Radiohead",
+            FakePeer::new("alice")
+                .with_latency(Duration::from_millis(50))
+                .with_file(
+                    FakeFile::new("a\\Creep.mp3", b"creep")
+                        .with_bitrate(320)
+                        .with_transfer(FakeTransfer::Partial(2)),
+                ),
+        );
+        let cancel = Arc::new(AtomicBool::new(false));
+        network
+            .search_with_cancel("Creep - Radiohead", Duration::ZERO, cancel)
+            .unwrap();
+        assert!(network.get_search_results("Creep - Radiohead").is_empty());
+        thread::sleep(Duration::from_millis(60));
+        let results = network.get_search_results("Creep - Radiohead");
+        assert_eq!(results[0].files[0].size, 5);
+        assert_eq!(results[0].files[0].attribs[&ATTRIBUTE_BITRATE], 320);
+
+        let path = std::env::temp_dir().join(format!("fake-network-{}", std::process::id()));
+        let receiver = network
+            .download("a\\Creep.mp3", "alice", 5, path.to_str().unwrap())
+            .unwrap();
+        let statuses: Vec<DownloadStatus> = receiver.iter().collect();
+        assert!(matches!(statuses.last(), Some(DownloadStatus::Failed)));
+        assert_eq!(std::fs::read(&path).unwrap(), b"cr");
+        std::fs::remove_file(path).unwrap();
+    }
+}
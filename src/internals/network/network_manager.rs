@@ -0,0 +1,231 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, atomic::AtomicBool, mpsc::Receiver},
+    time::Duration,
+};
+
+use anyhow::Context;
+use soulseek_rs::{Client, DownloadStatus, SearchResult};
+use tokio::sync::mpsc;
+
+/// How often backends are checked for new results. The Soulseek client only
+/// collects responses, so they are picked up at this rate.
+pub const RESULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Where searches are sent. Blocking like the Soulseek client, so called from
+/// `spawn_blocking`.
+pub trait SearchBackend: Send + Sync {
+    /// Signs in, before the first search.
+    fn login(&self) -> anyhow::Result<()>;
+    /// Searches for `query` until `timeout` passes or `cancel` is set.
+    /// Results arrive meanwhile, see [`SearchBackend::get_search_results`].
+    fn search_with_cancel(
+        &self,
+        query: &str,
+        timeout: Duration,
+        cancel: Arc<AtomicBool>,
+    ) -> anyhow::Result<()>;
+    /// Every result received so far for `query`, in the order they arrived.
+    fn get_search_results(&self, query: &str) -> Vec<SearchResult>;
+    /// How many results `get_search_results` would return, without copying
+    /// them.
+    fn get_search_results_count(&self, query: &str) -> usize;
+}
+
+/// The files answering one query, each delivered once as its peer responds.
+/// The backend is polled on a blocking thread, which stops once these are
+/// dropped.
+pub struct SearchResults {
+    receiver: mpsc::UnboundedReceiver<SearchResult>,
+}
+
+impl SearchResults {
+    pub fn new(backend: Arc<dyn SearchBackend>, query: &str) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let query = query.to_string();
+        tokio::task::spawn_blocking(move || poll_results(backend.as_ref(), &query, &sender));
+        SearchResults { receiver }
+    }
+
+    /// A peer's files not delivered before, or `None` once `idle_timeout`
+    /// passed without any.
+    pub async fn next(&mut self, idle_timeout: Duration) -> Option<SearchResult> {
+        tokio::time::timeout(idle_timeout, self.receiver.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+/// Sends the files not delivered yet, one result per peer, until `sender`
+/// closes. The results are only fetched when more have arrived, as they are
+/// copied out of the client whole.
+fn poll_results(
+    backend: &dyn SearchBackend,
+    query: &str,
+    sender: &mpsc::UnboundedSender<SearchResult>,
+) {
+    let mut seen: HashSet<(String, String)> = HashSet::new();
+    let mut fetched = 0;
+    while !sender.is_closed() {
+        if backend.get_search_results_count(query) != fetched {
+            let results = backend.get_search_results(query);
+            fetched = results.len();
+            for mut result in results {
+                let username = &result.username;
+                result
+                    .files
+                    .retain(|file| seen.insert((username.clone(), file.name.clone())));
+                if !result.files.is_empty() && sender.send(result).is_err() {
+                    return;
+                }
+            }
+        }
+        std::thread::sleep(RESULT_POLL_INTERVAL);
+    }
+}
+
+/// Where files are downloaded from.
+pub trait TransferBackend: Send + Sync {
+    /// Starts downloading `filename` from `username` into the file at `path`,
+    /// reporting progress on the returned channel.
+    fn download(
+        &self,
+        filename: &str,
+        username: &str,
+        size: u64,
+        path: &str,
+    ) -> anyhow::Result<Receiver<DownloadStatus>>;
+}
+
+impl SearchBackend for Client {
+    fn login(&self) -> anyhow::Result<()> {
+        Client::login(self).context("Logging in")?;
+        Ok(())
+    }
+    fn search_with_cancel(
+        &self,
+        query: &str,
+        timeout: Duration,
+        cancel: Arc<AtomicBool>,
+    ) -> anyhow::Result<()> {
+        Client::search_with_cancel(self, query, timeout, Some(cancel)).context("Searching")?;
+        Ok(())
+    }
+    fn get_search_results(&self, query: &str) -> Vec<SearchResult> {
+        Client::get_search_results(self, query)
+    }
+    fn get_search_results_count(&self, query: &str) -> usize {
+        Client::get_search_results_count(self, query)
+    }
+}
+
+impl TransferBackend for Client {
+    fn download(
+        &self,
+        filename: &str,
+        username: &str,
+        size: u64,
+        path: &str,
+    ) -> anyhow::Result<Receiver<DownloadStatus>> {
+        Client::download(
+            self,
+            filename.to_string(),
+            username.to_string(),
+            size,
+            path.to_string(),
+        )
+        .context("Starting download")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::internals::network::fake::{FakeFile, FakeNetwork, FakePeer};
+    use tokio::time::Instant;
+
+    /// Counts how often the network is polled and its results copied out.
+    struct Fetches {
+        network: FakeNetwork,
+        polls: AtomicUsize,
+        fetches: AtomicUsize,
+    }
+
+    impl SearchBackend for Fetches {
+        fn login(&self) -> anyhow::Result<()> {
+            self.network.login()
+        }
+        fn search_with_cancel(
+            &self,
+            query: &str,
+            timeout: Duration,
+            cancel: Arc<AtomicBool>,
+        ) -> anyhow::Result<()> {
+            self.network.search_with_cancel(query, timeout, cancel)
+        }
+        fn get_search_results(&self, query: &str) -> Vec<SearchResult> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            self.network.get_search_results(query)
+        }
+        fn get_search_results_count(&self, query: &str) -> usize {
+            self.polls.fetch_add(1, Ordering::Relaxed);
+            self.network.get_search_results_count(query)
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_each_file_once_as_peers_respond() {
+        let network = Arc::new(Fetches {
+            network: FakeNetwork::new()
+                .respond(
+                    "Creep",
+                    FakePeer::new("fast").with_file(FakeFile::new("a\\Creep.mp3", b"a")),
+                )
+                .respond(
+                    "Creep",
+                    FakePeer::new("slow")
+                        .with_latency(Duration::from_millis(300))
+                        .with_file(FakeFile::new("b\\Creep.flac", b"b")),
+                ),
+            polls: AtomicUsize::new(0),
+            fetches: AtomicUsize::new(0),
+        });
+        network
+            .search_with_cancel("Creep", Duration::ZERO, Arc::new(AtomicBool::new(false)))
+            .unwrap();
+        let mut results = SearchResults::new(network.clone(), "Creep");
+        let idle = Duration::from_secs(1);
+
+        let start = Instant::now();
+        let fast = results.next(idle).await.unwrap();
+        assert_eq!(fast.username, "fast");
+        assert!(start.elapsed() < Duration::from_millis(100));
+        let slow = results.next(idle).await.unwrap();
+        assert_eq!(slow.username, "slow");
+        assert_eq!(slow.files.len(), 1);
+        assert!(results.next(Duration::from_millis(200)).await.is_none());
+        // Polled every 50ms, but fetched once per peer that responded.
+        assert_eq!(network.fetches.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_polling_once_dropped() {
+        let network = Arc::new(Fetches {
+            network: FakeNetwork::new(),
+            polls: AtomicUsize::new(0),
+            fetches: AtomicUsize::new(0),
+        });
+        let mut results = SearchResults::new(network.clone(), "Creep");
+        assert!(results.next(Duration::from_millis(100)).await.is_none());
+        drop(results);
+        tokio::time::sleep(RESULT_POLL_INTERVAL * 2).await;
+
+        let polls = network.polls.load(Ordering::Relaxed);
+        assert!(polls > 0);
+        tokio::time::sleep(RESULT_POLL_INTERVAL * 4).await;
+        assert_eq!(network.polls.load(Ordering::Relaxed), polls);
+    }
+}
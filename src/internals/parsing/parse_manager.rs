@@ -1,10 +1,250 @@
-#![allow(unused)]
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::internals::{
+    parsing::deserialize::{Playlist, RootStructTracks, RootStructTracksItemsItem},
+    query::sources::PlaylistSource,
+    search::search_manager::SearchItem,
+};
+
+/// Reads Spotify Web API playlist dumps from disk. `query_playlist` may point
+/// to a single dump or to a directory holding one file per page.
+#[derive(Debug, Clone)]
 pub struct ParseManager {
-    query_playlist: String,
+    query_playlist: PathBuf,
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkipReason {
+    LocalFile,
+    Episode,
+    MissingTrack,
+    MissingField(String),
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::LocalFile => write!(f, "local file"),
+            SkipReason::Episode => write!(f, "podcast episode"),
+            SkipReason::MissingTrack => write!(f, "entry has no track"),
+            SkipReason::MissingField(field) => write!(f, "missing field {field}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedEntry {
+    pub position: usize,
+    pub name: Option<String>,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseReport {
+    pub items: Vec<SearchItem>,
+    pub skipped: Vec<SkippedEntry>,
+}
+
+impl ParseReport {
+    fn extend(&mut self, other: ParseReport) {
+        let offset = self.items.len() + self.skipped.len();
+        self.items.extend(other.items);
+        self.skipped
+            .extend(other.skipped.into_iter().map(|mut skipped| {
+                skipped.position += offset;
+                skipped
+            }));
+    }
+}
+
 impl ParseManager {
-    pub fn new(query_playlist: impl Into<String>) -> Self {
+    pub fn new(query_playlist: impl Into<PathBuf>) -> Self {
         let query_playlist = query_playlist.into();
         ParseManager { query_playlist }
     }
+
+    pub async fn run(&self) -> anyhow::Result<ParseReport> {
+        let metadata = tokio::fs::metadata(&self.query_playlist)
+            .await
+            .with_context(|| format!("Reading {}", self.query_playlist.display()))?;
+        let files = if metadata.is_dir() {
+            let mut files = vec![];
+            let mut entries = tokio::fs::read_dir(&self.query_playlist)
+                .await
+                .context("Listing dump directory")?;
+            while let Some(entry) = entries.next_entry().await.context("Reading dir entry")? {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    files.push(path);
+                }
+            }
+            // Page dumps are expected to sort in page order, e.g. `page_000.json`.
+            files.sort();
+            files
+        } else {
+            vec![self.query_playlist.clone()]
+        };
+        let mut report = ParseReport::default();
+        for file in files {
+            report.extend(Self::parse_file(&file).await?);
+        }
+        for skipped in &report.skipped {
+            tracing::warn!(
+                position = skipped.position,
+                name = skipped.name,
+                reason = %skipped.reason,
+                "Skipped playlist entry"
+            );
+        }
+        tracing::info!(
+            tracks = report.items.len(),
+            skipped = report.skipped.len(),
+            "Parsed playlist dump"
+        );
+        Ok(report)
+    }
+
+    async fn parse_file(path: &Path) -> anyhow::Result<ParseReport> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Reading dump {}", path.display()))?;
+        Self::parse_str(&content).with_context(|| format!("Parsing dump {}", path.display()))
+    }
+
+    /// Accepts either a full playlist object or a bare tracks page, which is
+    /// what `/playlists/{id}/tracks` returns for every page after the first.
+    pub fn parse_str(content: &str) -> anyhow::Result<ParseReport> {
+        let value: serde_json::Value = serde_json::from_str(content).context("Invalid json")?;
+        let page = if value.get("tracks").is_some() {
+            let playlist: Playlist = serde_json::from_value(value).context("Deserializing")?;
+            playlist.tracks
+        } else {
+            Some(serde_json::from_value::<RootStructTracks>(value).context("Deserializing")?)
+        };
+        let items = page.and_then(|page| page.items).unwrap_or_default();
+        Ok(Self::parse_items(items))
+    }
+
+    pub fn parse_items(items: Vec<RootStructTracksItemsItem>) -> ParseReport {
+        let mut report = ParseReport::default();
+        for (position, item) in items.into_iter().enumerate() {
+            match Self::parse_item(item) {
+                Ok(search_item) => report.items.push(search_item),
+                Err((name, reason)) => report.skipped.push(SkippedEntry {
+                    position,
+                    name,
+                    reason,
+                }),
+            }
+        }
+        report
+    }
+
+    fn parse_item(
+        item: RootStructTracksItemsItem,
+    ) -> Result<SearchItem, (Option<String>, SkipReason)> {
+        let Some(track) = item.track else {
+            return Err((None, SkipReason::MissingTrack));
+        };
+        let name = track.name.clone();
+        if item.is_local.unwrap_or_default() || track.is_local.unwrap_or_default() {
+            return Err((name, SkipReason::LocalFile));
+        }
+        if track.episode.unwrap_or_default() || track.type_.as_deref() == Some("episode") {
+            return Err((name, SkipReason::Episode));
+        }
+        let missing = |field: &str| (name.clone(), SkipReason::MissingField(field.to_string()));
+        let title = track.name.clone().ok_or_else(|| missing("name"))?;
+        let artists: Vec<String> = track
+            .artists
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|artist| artist.name)
+            .collect();
+        let artist = artists.first().cloned().ok_or_else(|| missing("artists"))?;
+        let album = track
+            .album
+            .and_then(|album| album.name)
+            .ok_or_else(|| missing("album"))?;
+        let to_i32 = |value: Option<i64>| value.and_then(|v| i32::try_from(v).ok());
+        Ok(SearchItem::new(title, album, artist)
+            .with_artists(artists)
+            .with_duration_ms(to_i32(track.duration_ms))
+            .with_isrc(track.external_ids.and_then(|ids| ids.isrc))
+            .with_spotify_id(track.id)
+            .with_position(to_i32(track.track_number), to_i32(track.disc_number)))
+    }
+}
+
+#[async_trait]
+impl PlaylistSource for ParseManager {
+    async fn search_items(&self) -> anyhow::Result<Vec<SearchItem>> {
+        Ok(self.run().await?.items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"{
+        "items": [
+            {"track": {
+                "id": "70LcF31zb1H0PyJoS1Sx1r",
+                "name": "Creep",
+                "artists": [{"name": "Radiohead"}, {"name": "Guest"}],
+                "album": {"name": "Pablo Honey"},
+                "duration_ms": 238640,
+                "external_ids": {"isrc": "GBAYE9200070"},
+                "track_number": 2,
+                "disc_number": 1
+            }},
+            {"is_local": true, "track": {"name": "Home Demo"}},
+            {"track": null},
+            {"track": {"name": "Some Podcast", "type": "episode"}},
+            {"track": {"name": "No Album", "artists": [{"name": "Someone"}]}}
+        ]
+    }"#;
+
+    #[test]
+    fn parses_tracks_and_reports_skipped_entries() {
+        let report = ParseManager::parse_str(PAGE).unwrap();
+        assert_eq!(report.items.len(), 1);
+        let item = &report.items[0];
+        assert_eq!(item.artists, ["Radiohead", "Guest"]);
+        assert_eq!(item.duration_ms, Some(238640));
+        assert_eq!(item.isrc.as_deref(), Some("GBAYE9200070"));
+        let skipped: Vec<(usize, SkipReason)> = report
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.position, skipped.reason.clone()))
+            .collect();
+        assert_eq!(
+            skipped,
+            [
+                (1, SkipReason::LocalFile),
+                (2, SkipReason::MissingTrack),
+                (3, SkipReason::Episode),
+                (4, SkipReason::MissingField("album".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_full_playlists_and_offsets_later_pages() {
+        let playlist = format!(r#"{{"name": "Mix", "tracks": {PAGE}}}"#);
+        let mut report = ParseManager::parse_str(&playlist).unwrap();
+        report.extend(ParseManager::parse_str(PAGE).unwrap());
+        assert_eq!(report.items.len(), 2);
+        let positions: Vec<usize> = report.skipped.iter().map(|s| s.position).collect();
+        assert_eq!(positions, [1, 2, 3, 4, 6, 7, 8, 9]);
+        assert!(ParseManager::parse_str("[]").is_err());
+    }
 }
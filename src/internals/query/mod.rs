@@ -1 +1,2 @@
 pub mod query_manager;
+pub mod sources;
@@ -3,68 +3,226 @@
 use crate::internals::{
     context::context_manager::{Track, send},
     parsing::deserialize,
+    query::sources::{self, PlaylistSource},
     search::search_manager::SearchItem,
     utils::config::config_manager::Config,
 };
-use anyhow::Context;
+use anyhow::{Context, bail};
 use itertools::Itertools;
 use rand::Rng;
-use spotify_rs::{ClientCredsClient, ClientCredsFlow, Token, client::Client, model::PlayableItem};
-use std::time::Duration;
+use reqwest::{
+    StatusCode, Url,
+    header::{HeaderMap, RETRY_AFTER},
+};
+use serde::de::DeserializeOwned;
+use spotify_rs::{
+    AuthCodeClient, AuthFlow, ClientCredsClient, ClientCredsFlow, RedirectUrl, Token,
+    client::Client,
+    model::{
+        Page, PlayableItem,
+        album::AlbumGroup,
+        track::{SimplifiedTrack, Track as SpotifyTrack},
+    },
+};
+use std::{collections::HashSet, io::BufRead, path::Path, str::FromStr, time::Duration};
 use tokio::{sync::mpsc::Sender, time::sleep};
 
+const MAX_PAGE_RETRIES: u32 = 5;
+const MARKET: &str = "US";
+
+/// What a Spotify URI or URL points to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpotifySource {
+    Playlist(String),
+    Album(String),
+    ArtistTopTracks(String),
+    ArtistDiscography(String),
+    LikedSongs,
+}
+
+impl FromStr for SpotifySource {
+    type Err = anyhow::Error;
+
+    /// Accepts `spotify:<kind>:<id>` URIs, `open.spotify.com` URLs and bare
+    /// playlist IDs. Artists resolve to their top tracks unless the URI ends
+    /// in `:discography` (or the URL in `/discography`). Liked songs are
+    /// `spotify:collection` or `https://open.spotify.com/collection/tracks`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let segments: Vec<String> = if let Some(uri) = value.strip_prefix("spotify:") {
+            uri.split(':').map(str::to_string).collect()
+        } else if value.starts_with("http://") || value.starts_with("https://") {
+            let url = Url::parse(value).context("Invalid spotify url")?;
+            url.path_segments()
+                .context("Spotify url has no path")?
+                .filter(|segment| !segment.is_empty() && !segment.starts_with("intl-"))
+                .map(str::to_string)
+                .collect()
+        } else {
+            let id = value.split('?').next().unwrap_or_default();
+            if id.is_empty() {
+                bail!("Empty spotify source");
+            }
+            return Ok(SpotifySource::Playlist(id.to_string()));
+        };
+        let parts: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let strip_query = |id: &str| id.split('?').next().unwrap_or_default().to_string();
+        match parts.as_slice() {
+            ["playlist", id, ..] => Ok(SpotifySource::Playlist(strip_query(id))),
+            ["album", id, ..] => Ok(SpotifySource::Album(strip_query(id))),
+            ["artist", id, "discography", ..] => {
+                Ok(SpotifySource::ArtistDiscography(strip_query(id)))
+            }
+            ["artist", id, ..] => Ok(SpotifySource::ArtistTopTracks(strip_query(id))),
+            ["collection", ..] | ["user", _, "collection", ..] => Ok(SpotifySource::LikedSongs),
+            _ => bail!("Unsupported spotify source {value}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct QueryManager {
-    pub playlist_url: String,
-    client_id: String,
-    client_secret: String,
+    pub sources: Vec<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    redirect_uri: Option<String>,
 }
 
 impl QueryManager {
     pub fn new(
-        playlist_url: impl Into<String>,
+        sources: Vec<String>,
         client_id: Option<String>,
         client_secret: Option<String>,
     ) -> Self {
-        let playlist_url = playlist_url.into();
-        let client_id = client_id.unwrap();
-        let client_secret = client_secret.unwrap();
         QueryManager {
-            playlist_url,
+            sources,
             client_id,
             client_secret,
+            redirect_uri: None,
         }
     }
-    pub async fn fetch_playlist(self) -> anyhow::Result<Vec<Track>> {
-        let spotify =
-            spotify_rs::ClientCredsClient::authenticate(self.client_id, self.client_secret)
+    /// Redirect URL registered for the app, needed to read the user's liked songs.
+    pub fn with_redirect_uri(mut self, redirect_uri: Option<String>) -> Self {
+        self.redirect_uri = redirect_uri;
+        self
+    }
+    /// Fetches every source in order, dropping tracks already listed by an
+    /// earlier one.
+    pub async fn fetch(self) -> anyhow::Result<Vec<Track>> {
+        let items = self
+            .fetch_sources()
+            .await?
+            .into_iter()
+            .flat_map(|(_, items)| items)
+            .unique_by(|item| item.track_id.clone())
+            .collect::<Vec<_>>();
+        tracing::info!(tracks = items.len(), "Fetched all sources");
+        Ok(items.into_iter().map(Track::Query).collect())
+    }
+    /// The tracks of each source, in order.
+    pub async fn fetch_sources(&self) -> anyhow::Result<Vec<(String, Vec<SearchItem>)>> {
+        let mut sources = vec![];
+        for source in &self.sources {
+            let fetched = self
+                .fetch_one(source)
                 .await
-                .unwrap();
-
-        let playlist = spotify_rs::playlist(self.playlist_url)
-            .market("US")
+                .with_context(|| format!("Fetching source {source}"))?;
+            sources.push((source.clone(), fetched));
+        }
+        Ok(sources)
+    }
+    async fn fetch_one(&self, source: &str) -> anyhow::Result<Vec<SearchItem>> {
+        if let Some(file_source) = sources::from_path(Path::new(source)) {
+            tracing::info!(source, "Reading playlist file");
+            return file_source.search_items().await;
+        }
+        let source: SpotifySource = source.parse().context("Parsing source uri")?;
+        tracing::info!(?source, "Fetching spotify source");
+        let items = match source {
+            SpotifySource::LikedSongs => self.fetch_liked_songs().await?,
+            source => {
+                let (client_id, client_secret) = self.credentials()?;
+                let spotify = ClientCredsClient::authenticate(client_id, client_secret)
+                    .await
+                    .context("Authenticating spotify client")?;
+                match source {
+                    SpotifySource::Playlist(id) => fetch_playlist(&spotify, id).await?,
+                    SpotifySource::Album(id) => fetch_album(&spotify, id).await?,
+                    SpotifySource::ArtistTopTracks(id) => fetch_top_tracks(&spotify, id).await?,
+                    SpotifySource::ArtistDiscography(id) => fetch_discography(&spotify, id).await?,
+                    SpotifySource::LikedSongs => unreachable!(),
+                }
+            }
+        };
+        tracing::info!(tracks = items.len(), "Fetched spotify source");
+        Ok(items)
+    }
+    fn credentials(&self) -> anyhow::Result<(String, String)> {
+        let client_id = self
+            .client_id
+            .clone()
+            .context("Missing spotify client id")?;
+        let client_secret = self
+            .client_secret
+            .clone()
+            .context("Missing spotify client secret")?;
+        Ok((client_id, client_secret))
+    }
+    async fn fetch_liked_songs(&self) -> anyhow::Result<Vec<SearchItem>> {
+        let redirect_uri = self
+            .redirect_uri
+            .clone()
+            .context("Liked songs need a redirect uri for the authorization code flow")?;
+        let redirect_uri = RedirectUrl::new(redirect_uri).context("Invalid redirect uri")?;
+        let (client_id, client_secret) = self.credentials()?;
+        let (client, url) = AuthCodeClient::new(
+            client_id,
+            client_secret,
+            ["user-library-read"],
+            redirect_uri,
+            false,
+        );
+        println!(
+            "Authorize access to your liked songs at:\n{url}\nthen paste the url you were redirected to:"
+        );
+        let redirected = tokio::task::spawn_blocking(|| {
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line).map(|_| line)
+        })
+        .await
+        .context("Reading redirect url")?
+        .context("Reading redirect url")?;
+        let redirected = Url::parse(redirected.trim()).context("Invalid redirect url")?;
+        let param = |name: &str| {
+            redirected
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .with_context(|| format!("Redirect url has no {name}"))
+        };
+        let spotify = client
+            .authenticate(param("code")?, param("state")?)
+            .await
+            .context("Authorizing spotify client")?;
+        let first = spotify_rs::saved_tracks()
+            .market(MARKET)
+            .limit(50)
             .get(&spotify)
             .await
-            .unwrap();
-        let pl = playlist
-            .tracks
-            .items
+            .context("Fetching liked songs")?;
+        let saved = collect_pages(first, &spotify).await?;
+        Ok(saved
             .into_iter()
-            .flatten()
-            .flat_map(|track| {
-                if let PlayableItem::Track(song) = track.track {
-                    let song2 = song.clone();
-                    Some(Track::Query(SearchItem::new(
-                        song.clone().name,
-                        song.clone().album.name,
-                        song2.artists.clone().first().unwrap().name.clone(),
-                    )))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
-        Ok(pl)
+            .filter_map(|saved| item_from_track(saved.track))
+            .collect())
+    }
+    pub async fn fetch_source(source: &dyn PlaylistSource) -> anyhow::Result<Vec<Track>> {
+        let items = source
+            .search_items()
+            .await
+            .context("Reading playlist source")?;
+        tracing::info!(tracks = items.len(), "Fetched playlist source");
+        Ok(items.into_iter().map(Track::Query).collect())
     }
     pub async fn run(&self) -> anyhow::Result<Vec<Track>> {
         let data_string = include_str!("../parsing/sample.json");
@@ -75,3 +233,284 @@ impl QueryManager {
         Ok(vals)
     }
 }
+
+async fn fetch_playlist(
+    spotify: &Client<Token, impl AuthFlow>,
+    id: String,
+) -> anyhow::Result<Vec<SearchItem>> {
+    let playlist = spotify_rs::playlist(id)
+        .market(MARKET)
+        .get(spotify)
+        .await
+        .context("Fetching playlist")?;
+    let items = collect_pages(playlist.tracks, spotify).await?;
+    Ok(items
+        .into_iter()
+        .filter_map(|item| match item.track {
+            PlayableItem::Track(song) => item_from_track(song),
+            PlayableItem::Episode(_) => None,
+        })
+        .collect())
+}
+
+async fn fetch_album(
+    spotify: &Client<Token, impl AuthFlow>,
+    id: String,
+) -> anyhow::Result<Vec<SearchItem>> {
+    let album = spotify_rs::album(id)
+        .market(MARKET)
+        .get(spotify)
+        .await
+        .context("Fetching album")?;
+    let tracks = collect_pages(album.tracks, spotify).await?;
+    Ok(tracks
+        .into_iter()
+        .filter_map(|song| item_from_simplified(song, &album.name))
+        .collect())
+}
+
+async fn fetch_top_tracks(
+    spotify: &Client<Token, impl AuthFlow>,
+    id: String,
+) -> anyhow::Result<Vec<SearchItem>> {
+    let tracks = spotify_rs::artist_top_tracks(id)
+        .market(MARKET)
+        .get(spotify)
+        .await
+        .context("Fetching artist top tracks")?;
+    Ok(tracks.into_iter().filter_map(item_from_track).collect())
+}
+
+/// Every album and single of the artist. Songs released both as a single and
+/// on an album share an ISRC and are only kept once.
+async fn fetch_discography(
+    spotify: &Client<Token, impl AuthFlow>,
+    id: String,
+) -> anyhow::Result<Vec<SearchItem>> {
+    let first = spotify_rs::artist_albums(id)
+        .include_groups(&[AlbumGroup::Album, AlbumGroup::Single])
+        .market(MARKET)
+        .limit(50)
+        .get(spotify)
+        .await
+        .context("Fetching artist albums")?;
+    let albums = collect_pages(first, spotify).await?;
+    let mut ids = vec![];
+    for album in albums {
+        let first = spotify_rs::album_tracks(album.id.clone())
+            .market(MARKET)
+            .limit(50)
+            .get(spotify)
+            .await
+            .with_context(|| format!("Fetching tracks of {}", album.name))?;
+        ids.extend(
+            collect_pages(first, spotify)
+                .await?
+                .into_iter()
+                .map(|song| song.id),
+        );
+    }
+    // Album listings leave out the ISRC, only full tracks carry it.
+    let mut items = vec![];
+    for chunk in ids.chunks(50) {
+        let songs = spotify_rs::tracks(chunk)
+            .market(MARKET)
+            .get(spotify)
+            .await
+            .context("Fetching discography tracks")?;
+        items.extend(songs.into_iter().filter_map(item_from_track));
+    }
+    Ok(unique_recordings(items))
+}
+
+/// Drops repeated recordings, told apart by ISRC, or by track identity
+/// without one.
+fn unique_recordings(items: Vec<SearchItem>) -> Vec<SearchItem> {
+    let mut seen = HashSet::new();
+    items
+        .into_iter()
+        .filter(|item| {
+            let key = match item.isrc.as_deref().filter(|isrc| !isrc.is_empty()) {
+                Some(isrc) => format!("isrc:{}", isrc.trim().to_uppercase()),
+                None => item.track_id.clone(),
+            };
+            seen.insert(key)
+        })
+        .collect()
+}
+
+fn item_from_track(song: SpotifyTrack) -> Option<SearchItem> {
+    let artist = song.artists.first()?.name.clone();
+    let artists = song.artists.into_iter().map(|a| a.name).collect();
+    Some(
+        SearchItem::new(song.name, song.album.name, artist)
+            .with_artists(artists)
+            .with_duration_ms(i32::try_from(song.duration_ms).ok())
+            .with_isrc(song.external_ids.isrc)
+            .with_spotify_id(Some(song.id))
+            .with_position(
+                i32::try_from(song.track_number).ok(),
+                i32::try_from(song.disc_number).ok(),
+            ),
+    )
+}
+
+fn item_from_simplified(song: SimplifiedTrack, album: &str) -> Option<SearchItem> {
+    let artist = song.artists.first()?.name.clone();
+    let artists = song.artists.into_iter().map(|a| a.name).collect();
+    Some(
+        SearchItem::new(song.name, album.to_string(), artist)
+            .with_artists(artists)
+            .with_duration_ms(i32::try_from(song.duration_ms).ok())
+            .with_spotify_id(Some(song.id))
+            .with_position(
+                i32::try_from(song.track_number).ok(),
+                i32::try_from(song.disc_number).ok(),
+            ),
+    )
+}
+
+/// Walks every page after `first`, reporting progress as it goes.
+async fn collect_pages<T: Clone + DeserializeOwned>(
+    first: Page<T>,
+    spotify: &Client<Token, impl AuthFlow>,
+) -> anyhow::Result<Vec<T>> {
+    let total_pages = first.total.div_ceil(first.limit.max(1));
+    let mut page = first;
+    let mut items = vec![];
+    let mut pages_fetched = 1;
+    loop {
+        tracing::info!(pages_fetched, total_pages, "Fetched page");
+        let next = page.next.is_some();
+        items.extend(page.items.drain(..).flatten());
+        if !next {
+            break;
+        }
+        page = next_page_with_backoff(&page, spotify)
+            .await
+            .context("Fetching next page")?;
+        pages_fetched += 1;
+    }
+    Ok(items)
+}
+
+/// Fetches the page after `page` itself rather than through `Page::get_next`,
+/// which drops the response headers, so a `Retry-After` on 429 is honoured.
+/// Without one it backs off exponentially with jitter.
+async fn next_page_with_backoff<T: Clone + DeserializeOwned>(
+    page: &Page<T>,
+    spotify: &Client<Token, impl AuthFlow>,
+) -> anyhow::Result<Page<T>> {
+    let next = page.next.as_deref().context("No next page")?;
+    let http = reqwest::Client::new();
+    let mut attempt = 0;
+    loop {
+        let response = http
+            .get(next)
+            .bearer_auth(fresh_access_token(spotify).await?)
+            .send()
+            .await
+            .context("Spotify page request")?;
+        let status = response.status();
+        if status.is_success() {
+            let body = response.bytes().await.context("Reading Spotify page")?;
+            return serde_json::from_slice(&body).context("Decoding Spotify page");
+        }
+        if (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+            && attempt < MAX_PAGE_RETRIES
+        {
+            let wait = retry_after(response.headers()).unwrap_or_else(|| {
+                let jitter = rand::rng().random_range(0..250);
+                Duration::from_millis(500 * 2u64.pow(attempt) + jitter)
+            });
+            tracing::warn!(
+                status = status.as_u16(),
+                attempt,
+                ?wait,
+                "Spotify throttled page fetch, backing off"
+            );
+            sleep(wait).await;
+            attempt += 1;
+            continue;
+        }
+        let body = response.text().await.unwrap_or_default();
+        bail!("Spotify page request failed with {status}: {body}");
+    }
+}
+
+/// The client's access token, refreshed first when it expired and the client
+/// refreshes on its own.
+async fn fresh_access_token(spotify: &Client<Token, impl AuthFlow>) -> anyhow::Result<String> {
+    let expired = spotify
+        .token()
+        .read()
+        .map_err(|_| anyhow::anyhow!("Spotify token lock poisoned"))?
+        .is_expired();
+    if expired && spotify.auto_refresh {
+        spotify
+            .exchange_refresh_token()
+            .await
+            .context("Refreshing Spotify token")?;
+    }
+    spotify.access_token().context("Reading Spotify token")
+}
+
+/// The wait a `Retry-After` header asks for. Spotify sends it in seconds.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let secs = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str, album: &str, isrc: Option<&str>, id: &str) -> SearchItem {
+        SearchItem::new(title.to_string(), album.to_string(), "Artist".to_string())
+            .with_isrc(isrc.map(str::to_string))
+            .with_spotify_id(Some(id.to_string()))
+    }
+
+    #[test]
+    fn keeps_songs_sharing_a_title_and_drops_repeated_recordings() {
+        let items = vec![
+            song("Intro", "First", None, "a"),
+            song("Intro", "Second", None, "b"),
+            song("Hit", "Hit - Single", Some("usabc0000001"), "c"),
+            song("Hit", "Second", Some("USABC0000001"), "d"),
+            song("Hit", "Live", Some("USABC0000002"), "e"),
+        ];
+        let kept: Vec<(String, String)> = unique_recordings(items)
+            .into_iter()
+            .map(|item| (item.track, item.album))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                ("Intro".to_string(), "First".to_string()),
+                ("Intro".to_string(), "Second".to_string()),
+                ("Hit".to_string(), "Hit - Single".to_string()),
+                ("Hit".to_string(), "Live".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_the_wait_spotify_asks_for() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+use crate::internals::{query::sources::PlaylistSource, search::search_manager::SearchItem};
+
+/// Header names to read each field from. `album` is optional since most
+/// exports of other services only carry artist and title.
+#[derive(Debug, Clone)]
+pub struct CsvColumns {
+    pub track: String,
+    pub artist: String,
+    pub album: Option<String>,
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        CsvColumns {
+            track: "title".to_string(),
+            artist: "artist".to_string(),
+            album: Some("album".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvSource {
+    pub path: PathBuf,
+    pub columns: CsvColumns,
+    pub delimiter: u8,
+}
+
+impl CsvSource {
+    pub fn new(path: impl Into<PathBuf>, columns: CsvColumns) -> Self {
+        CsvSource {
+            path: path.into(),
+            columns,
+            delimiter: b',',
+        }
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn parse(&self, content: &str) -> anyhow::Result<Vec<SearchItem>> {
+        let mut reader = ::csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .flexible(true)
+            .from_reader(content.as_bytes());
+        let headers = reader.headers().context("Reading csv headers")?.clone();
+        let position = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h.trim().eq_ignore_ascii_case(name))
+                .with_context(|| format!("Column {name} not found in csv headers"))
+        };
+        let track_idx = position(&self.columns.track)?;
+        let artist_idx = position(&self.columns.artist)?;
+        let album_idx = match &self.columns.album {
+            Some(album) => Some(position(album)?),
+            None => None,
+        };
+        let mut items = vec![];
+        for (line, record) in reader.records().enumerate() {
+            let record = record.with_context(|| format!("Reading csv record {line}"))?;
+            let field = |idx: usize| record.get(idx).unwrap_or_default().trim().to_string();
+            let (track, artist) = (field(track_idx), field(artist_idx));
+            if track.is_empty() || artist.is_empty() {
+                tracing::warn!(line, "Skipping csv record without artist or title");
+                continue;
+            }
+            let album = album_idx.map(field).unwrap_or_default();
+            items.push(SearchItem::new(track, album, artist));
+        }
+        Ok(items)
+    }
+}
+
+#[async_trait]
+impl PlaylistSource for CsvSource {
+    async fn search_items(&self) -> anyhow::Result<Vec<SearchItem>> {
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("Reading csv playlist {}", self.path.display()))?;
+        self.parse(&content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(columns: CsvColumns) -> CsvSource {
+        CsvSource::new("playlist.csv", columns)
+    }
+
+    #[test]
+    fn reads_columns_by_header_in_any_order_and_case() {
+        let items = source(CsvColumns::default())
+            .parse("Album, ARTIST ,Title\nPablo Honey,Radiohead,Creep\nDummy,,Roads\n")
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            (
+                items[0].track.as_str(),
+                items[0].album.as_str(),
+                items[0].artist.as_str()
+            ),
+            ("Creep", "Pablo Honey", "Radiohead")
+        );
+    }
+
+    #[test]
+    fn reads_custom_columns_and_delimiters() {
+        let columns = CsvColumns {
+            track: "Track Name".to_string(),
+            artist: "Artist Name(s)".to_string(),
+            album: None,
+        };
+        let items = source(columns)
+            .with_delimiter(b';')
+            .parse("Artist Name(s);Track Name\nPortishead;Roads\n")
+            .unwrap();
+        assert_eq!(items[0].track, "Roads");
+        assert!(items[0].album.is_empty());
+    }
+
+    #[test]
+    fn fails_on_missing_columns() {
+        let err = source(CsvColumns::default())
+            .parse("artist,title\nRadiohead,Creep\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("album"));
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+use crate::internals::{
+    query::sources::{PlaylistSource, split_artist_title},
+    search::search_manager::SearchItem,
+};
+
+/// Extended M3U playlist. Artist and title come from the `#EXTINF` line, and
+/// fall back to the entry's file stem when the tag is missing.
+#[derive(Debug, Clone)]
+pub struct M3uSource {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct M3uEntry {
+    pub artist: String,
+    pub title: String,
+    pub duration_secs: Option<u32>,
+}
+
+impl M3uSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        M3uSource { path: path.into() }
+    }
+
+    pub fn parse(content: &str) -> Vec<M3uEntry> {
+        let mut entries = vec![];
+        let mut pending: Option<M3uEntry> = None;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line == "#EXTM3U" {
+                continue;
+            }
+            if let Some(info) = line.strip_prefix("#EXTINF:") {
+                pending = Self::parse_extinf(info);
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            match pending.take().or_else(|| Self::parse_location(line)) {
+                Some(entry) => entries.push(entry),
+                None => tracing::warn!(line, "Skipping m3u entry without artist and title"),
+            }
+        }
+        entries
+    }
+
+    fn parse_extinf(info: &str) -> Option<M3uEntry> {
+        let (duration, name) = info.split_once(',')?;
+        // Attributes such as `tvg-id="..."` may follow the duration.
+        let duration = duration.split_whitespace().next()?;
+        let duration_secs = duration
+            .parse::<i64>()
+            .ok()
+            .and_then(|d| u32::try_from(d).ok());
+        let (artist, title) = split_artist_title(name)?;
+        Some(M3uEntry {
+            artist,
+            title,
+            duration_secs,
+        })
+    }
+
+    fn parse_location(location: &str) -> Option<M3uEntry> {
+        let stem = Path::new(&location.replace('\\', "/"))
+            .file_stem()?
+            .to_str()?
+            .to_string();
+        let (artist, title) = split_artist_title(&stem)?;
+        Some(M3uEntry {
+            artist,
+            title,
+            duration_secs: None,
+        })
+    }
+}
+
+impl From<M3uEntry> for SearchItem {
+    fn from(value: M3uEntry) -> Self {
+        let duration_ms = value
+            .duration_secs
+            .and_then(|secs| i32::try_from(secs).ok())
+            .and_then(|secs| secs.checked_mul(1000));
+        SearchItem::new(value.title, String::new(), value.artist).with_duration_ms(duration_ms)
+    }
+}
+
+#[async_trait]
+impl PlaylistSource for M3uSource {
+    async fn search_items(&self) -> anyhow::Result<Vec<SearchItem>> {
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("Reading m3u playlist {}", self.path.display()))?;
+        Ok(Self::parse(&content).into_iter().map(Into::into).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(artist: &str, title: &str, duration_secs: Option<u32>) -> M3uEntry {
+        M3uEntry {
+            artist: artist.to_string(),
+            title: title.to_string(),
+            duration_secs,
+        }
+    }
+
+    #[test]
+    fn reads_extinf_and_falls_back_to_the_file_stem() {
+        let content = "#EXTM3U\n\
+            #EXTINF:238,Radiohead - Creep\n\
+            Music/Radiohead/Creep.mp3\n\
+            #EXTINF:-1 tvg-id=\"x\",Portishead - Roads\n\
+            http://example.com/stream\n\
+            #EXTALB:Dummy\n\
+            C:\\Music\\Massive Attack - Teardrop.flac\n\
+            Music/untagged.mp3\n";
+        assert_eq!(
+            M3uSource::parse(content),
+            [
+                entry("Radiohead", "Creep", Some(238)),
+                entry("Portishead", "Roads", None),
+                entry("Massive Attack", "Teardrop", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn converts_duration_to_milliseconds() {
+        let item = SearchItem::from(entry("Radiohead", "Creep", Some(238)));
+        assert_eq!(item.duration_ms, Some(238_000));
+        assert_eq!(
+            (item.artist.as_str(), item.track.as_str()),
+            ("Radiohead", "Creep")
+        );
+    }
+}
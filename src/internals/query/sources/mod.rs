@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::internals::{
+    parsing::parse_manager::ParseManager,
+    query::sources::{csv::CsvSource, m3u::M3uSource, text::TextListSource},
+    search::search_manager::SearchItem,
+};
+
+pub mod csv;
+pub mod m3u;
+pub mod text;
+
+/// Anything that can produce the list of tracks to look for.
+#[async_trait]
+pub trait PlaylistSource: Send + Sync {
+    async fn search_items(&self) -> anyhow::Result<Vec<SearchItem>>;
+}
+
+/// Picks the reader for a local playlist by extension. Directories are read as
+/// paginated Spotify dumps. Returns `None` when `path` does not exist, so the
+/// caller can treat the value as a Spotify URI instead.
+pub fn from_path(path: &Path) -> Option<Box<dyn PlaylistSource>> {
+    if path.is_dir() {
+        return Some(Box::new(ParseManager::new(path)));
+    }
+    if !path.is_file() {
+        return None;
+    }
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "m3u" | "m3u8" => Some(Box::new(M3uSource::new(path))),
+        "csv" => Some(Box::new(CsvSource::new(path, Default::default()))),
+        "json" => Some(Box::new(ParseManager::new(path))),
+        _ => Some(Box::new(TextListSource::new(path))),
+    }
+}
+
+/// Splits an `"Artist - Title"` line on the first separator.
+pub(crate) fn split_artist_title(line: &str) -> Option<(String, String)> {
+    let (artist, title) = line.split_once(" - ")?;
+    let (artist, title) = (artist.trim(), title.trim());
+    if artist.is_empty() || title.is_empty() {
+        return None;
+    }
+    Some((artist.to_string(), title.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_the_first_separator() {
+        assert_eq!(
+            split_artist_title("Radiohead - Creep - Acoustic"),
+            Some(("Radiohead".to_string(), "Creep - Acoustic".to_string()))
+        );
+        assert_eq!(
+            split_artist_title("  Björk -  Jóga "),
+            Some(("Björk".to_string(), "Jóga".to_string()))
+        );
+        assert_eq!(split_artist_title("Radiohead-Creep"), None);
+        assert_eq!(split_artist_title(" - Creep"), None);
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+use crate::internals::{
+    query::sources::{PlaylistSource, split_artist_title},
+    search::search_manager::SearchItem,
+};
+
+/// Newline delimited `Artist - Title` list. Blank lines and lines starting
+/// with `#` are ignored.
+#[derive(Debug, Clone)]
+pub struct TextListSource {
+    pub path: PathBuf,
+}
+
+impl TextListSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TextListSource { path: path.into() }
+    }
+
+    pub fn parse(content: &str) -> Vec<SearchItem> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let parsed = split_artist_title(line);
+                if parsed.is_none() {
+                    tracing::warn!(line, "Skipping line without `Artist - Title` shape");
+                }
+                parsed
+            })
+            .map(|(artist, title)| SearchItem::new(title, String::new(), artist))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl PlaylistSource for TextListSource {
+    async fn search_items(&self) -> anyhow::Result<Vec<SearchItem>> {
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("Reading text playlist {}", self.path.display()))?;
+        Ok(Self::parse(&content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_comments_blank_and_malformed_lines() {
+        let items = TextListSource::parse(
+            "# favourites\n\nRadiohead - Creep\nnot a track\r\n  Portishead - Roads  \n",
+        );
+        let pairs: Vec<(&str, &str)> = items
+            .iter()
+            .map(|item| (item.artist.as_str(), item.track.as_str()))
+            .collect();
+        assert_eq!(pairs, [("Radiohead", "Creep"), ("Portishead", "Roads")]);
+        assert!(items.iter().all(|item| item.album.is_empty()));
+    }
+}
@@ -1 +1,2 @@
+pub mod query_planner;
 pub mod search_manager;
@@ -0,0 +1,307 @@
+use std::{collections::HashSet, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+use crate::internals::search::search_manager::SearchItem;
+
+/// Words that start a featured artist credit.
+const FEATURING: [&str; 3] = ["feat", "ft", "featuring"];
+
+/// How a Soulseek query is built from a track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueryStrategy {
+    /// `title - artist`, as given.
+    Full,
+    /// Title and artist without featured artists and bracketed or dashed
+    /// qualifiers like `(Remastered 2011)` or `- Radio Edit`.
+    Stripped,
+    /// `Stripped` with diacritics folded and other non-ASCII dropped.
+    Transliterated,
+    /// Stripped title and album, for tracks whose artist is spelled
+    /// differently by peers.
+    TitleAlbum,
+    /// Artist and album, to find the album folder the track is in.
+    ArtistAlbum,
+}
+
+impl QueryStrategy {
+    pub const ALL: [QueryStrategy; 5] = [
+        QueryStrategy::Full,
+        QueryStrategy::Stripped,
+        QueryStrategy::Transliterated,
+        QueryStrategy::TitleAlbum,
+        QueryStrategy::ArtistAlbum,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueryStrategy::Full => "full",
+            QueryStrategy::Stripped => "stripped",
+            QueryStrategy::Transliterated => "transliterated",
+            QueryStrategy::TitleAlbum => "title-album",
+            QueryStrategy::ArtistAlbum => "artist-album",
+        }
+    }
+}
+
+impl Display for QueryStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One query to search for a track.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryVariant {
+    pub strategy: QueryStrategy,
+    pub query: String,
+}
+
+/// A track's query variants, best first, and the one being searched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchPlan {
+    pub item: SearchItem,
+    /// Never empty.
+    pub variants: Vec<QueryVariant>,
+    pub position: usize,
+}
+
+impl SearchPlan {
+    pub fn variant(&self) -> &QueryVariant {
+        &self.variants[self.position]
+    }
+
+    /// The plan moved on to the next variant, if there is one.
+    pub fn next(&self) -> Option<SearchPlan> {
+        (self.position + 1 < self.variants.len()).then(|| SearchPlan {
+            position: self.position + 1,
+            ..self.clone()
+        })
+    }
+}
+
+/// Whether a searched variant turned up a candidate the judge accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchAttempt {
+    pub plan: SearchPlan,
+    pub accepted: bool,
+}
+
+/// Builds the query variants of a track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlanner {
+    /// Tried in this order, variants repeating an earlier query skipped.
+    pub strategies: Vec<QueryStrategy>,
+    /// Words kept in the queries the planner rewrites. Peers match every
+    /// word, so long titles find nothing.
+    pub max_terms: usize,
+}
+
+impl Default for QueryPlanner {
+    fn default() -> Self {
+        QueryPlanner {
+            strategies: QueryStrategy::ALL.to_vec(),
+            max_terms: 8,
+        }
+    }
+}
+
+/// Cuts `value` at the first bracketed part, ` - ` qualifier or featured
+/// artist credit.
+pub fn strip_qualifiers(value: &str) -> String {
+    let mut kept = String::new();
+    let mut depth = 0usize;
+    for c in value.chars() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => kept.push(c),
+            _ => {}
+        }
+    }
+    let kept = kept.split(" - ").next().unwrap_or_default();
+    kept.split_whitespace()
+        .take_while(|word| {
+            let word = word.trim_end_matches('.').to_lowercase();
+            !FEATURING.contains(&word.as_str())
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Folds diacritics and replaces what is left outside ASCII with spaces.
+pub fn transliterate(value: &str) -> String {
+    let folded: String = value
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .map(|c| match c {
+            'ß' => "ss".to_string(),
+            'æ' => "ae".to_string(),
+            'Æ' => "AE".to_string(),
+            'ø' => "o".to_string(),
+            'Ø' => "O".to_string(),
+            'ł' => "l".to_string(),
+            'Ł' => "L".to_string(),
+            c if c.is_ascii() => c.to_string(),
+            _ => " ".to_string(),
+        })
+        .collect();
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl QueryPlanner {
+    pub fn new(strategies: Vec<QueryStrategy>, max_terms: usize) -> Self {
+        QueryPlanner {
+            strategies,
+            max_terms,
+        }
+    }
+
+    /// Words with a letter or digit, at most `max_terms` of them.
+    fn terms(&self, parts: &[&str]) -> String {
+        parts
+            .iter()
+            .flat_map(|part| part.split_whitespace())
+            .filter(|word| word.chars().any(char::is_alphanumeric))
+            .take(self.max_terms.max(1))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn query(&self, strategy: QueryStrategy, item: &SearchItem) -> String {
+        let title = strip_qualifiers(&item.track);
+        let artist = strip_qualifiers(&item.artist);
+        let album = strip_qualifiers(&item.album);
+        match strategy {
+            QueryStrategy::Full => format!("{} - {}", item.track, item.artist),
+            QueryStrategy::Stripped => self.terms(&[&title, &artist]),
+            QueryStrategy::Transliterated => {
+                self.terms(&[&transliterate(&title), &transliterate(&artist)])
+            }
+            QueryStrategy::TitleAlbum if !album.is_empty() => self.terms(&[&title, &album]),
+            QueryStrategy::ArtistAlbum if !album.is_empty() => self.terms(&[&artist, &album]),
+            QueryStrategy::TitleAlbum | QueryStrategy::ArtistAlbum => String::new(),
+        }
+    }
+
+    /// The variants of `item` in strategy order, without empty or repeated
+    /// queries. Falls back to `Full` when no strategy yields a query.
+    pub fn plan(&self, item: &SearchItem) -> SearchPlan {
+        let mut seen = HashSet::new();
+        let mut variants: Vec<QueryVariant> = self
+            .strategies
+            .iter()
+            .map(|strategy| QueryVariant {
+                strategy: *strategy,
+                query: self.query(*strategy, item),
+            })
+            .filter(|variant| {
+                let words = variant
+                    .query
+                    .to_lowercase()
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                !words.is_empty() && seen.insert(words)
+            })
+            .collect();
+        if variants.is_empty() {
+            variants.push(QueryVariant {
+                strategy: QueryStrategy::Full,
+                query: self.query(QueryStrategy::Full, item),
+            });
+        }
+        SearchPlan {
+            item: item.clone(),
+            variants,
+            position: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queries(planner: &QueryPlanner, item: &SearchItem) -> Vec<(QueryStrategy, String)> {
+        planner
+            .plan(item)
+            .variants
+            .into_iter()
+            .map(|variant| (variant.strategy, variant.query))
+            .collect()
+    }
+
+    #[test]
+    fn strips_brackets_dashes_and_featured_artists() {
+        assert_eq!(
+            strip_qualifiers("Lose Yourself (From \"8 Mile\") [Soundtrack]"),
+            "Lose Yourself"
+        );
+        assert_eq!(strip_qualifiers("Heroes - 2017 Remaster"), "Heroes");
+        assert_eq!(
+            strip_qualifiers("Stay With Me feat. Mary J. Blige"),
+            "Stay With Me"
+        );
+        assert_eq!(strip_qualifiers("Daft Punk ft. Pharrell"), "Daft Punk");
+        assert_eq!(
+            transliterate("Sigur Rós – Hoppípolla"),
+            "Sigur Ros Hoppipolla"
+        );
+        assert_eq!(transliterate("宇多田ヒカル"), "");
+    }
+
+    #[test]
+    fn plans_variants_in_order_without_repeats() {
+        let item = SearchItem::new(
+            "Pájaros de Barro (feat. Björk) - Remastered".to_string(),
+            "Lo Más Nuevo".to_string(),
+            "Manolo García".to_string(),
+        );
+        assert_eq!(
+            queries(&QueryPlanner::default(), &item),
+            [
+                (
+                    QueryStrategy::Full,
+                    "Pájaros de Barro (feat. Björk) - Remastered - Manolo García".to_string()
+                ),
+                (
+                    QueryStrategy::Stripped,
+                    "Pájaros de Barro Manolo García".to_string()
+                ),
+                (
+                    QueryStrategy::Transliterated,
+                    "Pajaros de Barro Manolo Garcia".to_string()
+                ),
+                (
+                    QueryStrategy::TitleAlbum,
+                    "Pájaros de Barro Lo Más Nuevo".to_string()
+                ),
+                (
+                    QueryStrategy::ArtistAlbum,
+                    "Manolo García Lo Más Nuevo".to_string()
+                ),
+            ]
+        );
+
+        let plain = SearchItem::new("Creep".to_string(), String::new(), "Radiohead".to_string());
+        let plan = QueryPlanner::default().plan(&plain);
+        assert_eq!(plan.variants.len(), 1);
+        assert_eq!(plan.next(), None);
+    }
+
+    #[test]
+    fn keeps_long_queries_to_max_terms() {
+        let item = SearchItem::new(
+            "One Two Three Four Five Six".to_string(),
+            String::new(),
+            "Seven Eight".to_string(),
+        );
+        let planner = QueryPlanner::new(vec![QueryStrategy::Stripped], 4);
+        let plan = planner.plan(&item);
+        assert_eq!(plan.variant().query, "One Two Three Four");
+    }
+}
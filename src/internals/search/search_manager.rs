@@ -1,74 +1,135 @@
 use crate::internals::{
     context::context_manager::{Track, send},
-    database::schema,
-    parsing::deserialize::Playlist,
+    network::network_manager::{SearchBackend, SearchResults},
+    parsing::{deserialize::Playlist, parse_manager::ParseManager},
+    search::query_planner::SearchPlan,
 };
 use anyhow::Context;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use soulseek_rs::SearchResult;
 use std::{
-    collections::HashSet,
     fmt::Display,
-    hash::{DefaultHasher, Hash, Hasher},
     sync::{Arc, atomic::AtomicBool},
     time::Duration,
 };
 use tokio::{
     sync::{Semaphore, mpsc::Sender},
     task::JoinHandle,
-    time::sleep,
 };
 use tracing::{Instrument, info_span, instrument};
 
-const TIMES_WITH_NO_NEW_FILES: usize = 3;
-
 #[derive(Debug, Deserialize, Serialize, Clone, Hash, PartialEq, Eq)]
 pub struct SearchItem {
-    pub track_id: i32,
+    /// Stable identity of the track, see [`SearchItem::identity`].
+    pub track_id: String,
     pub track: String,
     pub album: String,
     pub artist: String,
+    /// Every credited artist, `artist` being the first of them.
+    #[serde(default)]
+    pub artists: Vec<String>,
+    #[serde(default)]
+    pub duration_ms: Option<i32>,
+    #[serde(default)]
+    pub isrc: Option<String>,
+    #[serde(default)]
+    pub track_number: Option<i32>,
+    #[serde(default)]
+    pub disc_number: Option<i32>,
+    #[serde(default)]
+    pub spotify_id: Option<String>,
 }
 impl SearchItem {
     pub fn new(track: String, album: String, artist: String) -> Self {
-        let track_id = {
-            let mut s = DefaultHasher::new();
-            track.hash(&mut s);
-            album.hash(&mut s);
-            artist.hash(&mut s);
-            s.finish()
-        } as i32;
-        SearchItem {
-            track_id,
+        let mut item = SearchItem {
+            track_id: String::new(),
+            artists: vec![artist.clone()],
             track,
             album,
             artist,
+            duration_ms: None,
+            isrc: None,
+            track_number: None,
+            disc_number: None,
+            spotify_id: None,
+        };
+        item.track_id = item.identity();
+        item
+    }
+    /// Deterministic key for the track: the Spotify ID when known, then the
+    /// ISRC, and otherwise a hash of the normalized track, artist and album.
+    ///
+    /// The metadata hash is the first 16 hex digits of a SHA-256 so the same
+    /// value can be computed from SQL (see the `stable_track_identity`
+    /// migration).
+    pub fn identity(&self) -> String {
+        self.identities().remove(0)
+    }
+    /// Every key the track may be stored under, [`SearchItem::identity`]
+    /// first. Rows from before Spotify IDs were kept are keyed by ISRC or
+    /// metadata hash, so those are matched too.
+    pub fn identities(&self) -> Vec<String> {
+        let spotify = self
+            .spotify_id
+            .as_deref()
+            .filter(|id| !id.is_empty())
+            .map(|id| format!("spotify:{id}"));
+        let isrc = self
+            .isrc
+            .as_deref()
+            .filter(|isrc| !isrc.is_empty())
+            .map(|isrc| format!("isrc:{}", isrc.trim().to_uppercase()));
+        spotify
+            .into_iter()
+            .chain(isrc)
+            .chain([self.metadata_identity()])
+            .collect()
+    }
+    fn metadata_identity(&self) -> String {
+        // ASCII only lowercasing keeps the key independent of the database locale.
+        let normalize = |value: &str| value.split_whitespace().join(" ").to_ascii_lowercase();
+        let mut hasher = Sha256::new();
+        hasher.update(normalize(&self.track));
+        hasher.update([0x1f]);
+        hasher.update(normalize(&self.artist));
+        hasher.update([0x1f]);
+        hasher.update(normalize(&self.album));
+        let digest = hasher.finalize();
+        let hex: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+        format!("meta:{hex}")
+    }
+    pub fn with_spotify_id(mut self, spotify_id: Option<String>) -> Self {
+        self.spotify_id = spotify_id;
+        self.track_id = self.identity();
+        self
+    }
+    pub fn with_artists(mut self, artists: Vec<String>) -> Self {
+        if !artists.is_empty() {
+            self.artists = artists;
         }
+        self
+    }
+    pub fn with_duration_ms(mut self, duration_ms: Option<i32>) -> Self {
+        self.duration_ms = duration_ms;
+        self
+    }
+    pub fn with_isrc(mut self, isrc: Option<String>) -> Self {
+        self.isrc = isrc;
+        self.track_id = self.identity();
+        self
+    }
+    pub fn with_position(mut self, track_number: Option<i32>, disc_number: Option<i32>) -> Self {
+        self.track_number = track_number;
+        self.disc_number = disc_number;
+        self
     }
 }
 impl From<Playlist> for Vec<SearchItem> {
     fn from(value: Playlist) -> Vec<SearchItem> {
-        let tracks = value.tracks.unwrap().items.unwrap();
-        tracks
-            .into_iter()
-            .map(|tr| {
-                let track = tr.clone().track.unwrap().name.unwrap();
-                let artist = tr
-                    .track
-                    .clone()
-                    .unwrap()
-                    .artists
-                    .unwrap()
-                    .first()
-                    .unwrap()
-                    .name
-                    .clone()
-                    .unwrap()
-                    .clone();
-                let album = tr.track.unwrap().album.unwrap().name.unwrap();
-                SearchItem::new(track, album, artist)
-            })
-            .collect()
+        let items = value.tracks.and_then(|t| t.items).unwrap_or_default();
+        ParseManager::parse_items(items).items
     }
 }
 
@@ -77,6 +138,68 @@ pub struct DownloadableFile {
     pub filename: String,
     pub username: String,
     pub size: i32,
+    /// Kbps as advertised by the peer, average for VBR files.
+    #[serde(default)]
+    pub bitrate: Option<i32>,
+    #[serde(default)]
+    pub duration_secs: Option<i32>,
+    #[serde(default)]
+    pub sample_rate: Option<i32>,
+    #[serde(default)]
+    pub bit_depth: Option<i32>,
+    #[serde(default)]
+    pub vbr: Option<bool>,
+    /// Upload speed the peer advertised, in bytes per second.
+    #[serde(default)]
+    pub upload_speed: Option<i32>,
+    /// Whether the peer had an upload slot free, so the transfer starts
+    /// without queueing.
+    #[serde(default)]
+    pub free_slot: Option<bool>,
+}
+
+/// Soulseek file attribute codes.
+const ATTRIBUTE_BITRATE: u32 = 0;
+const ATTRIBUTE_DURATION: u32 = 1;
+const ATTRIBUTE_VBR: u32 = 2;
+const ATTRIBUTE_SAMPLE_RATE: u32 = 4;
+const ATTRIBUTE_BIT_DEPTH: u32 = 5;
+
+impl DownloadableFile {
+    pub fn new(filename: String, username: String, size: i32) -> Self {
+        DownloadableFile {
+            filename,
+            username,
+            size,
+            bitrate: None,
+            duration_secs: None,
+            sample_rate: None,
+            bit_depth: None,
+            vbr: None,
+            upload_speed: None,
+            free_slot: None,
+        }
+    }
+}
+
+impl From<soulseek_rs::File> for DownloadableFile {
+    fn from(file: soulseek_rs::File) -> Self {
+        // Peers send zeros for attributes they could not read.
+        let attribute = |code: u32| {
+            file.attribs
+                .get(&code)
+                .filter(|value| **value > 0)
+                .and_then(|value| i32::try_from(*value).ok())
+        };
+        DownloadableFile {
+            bitrate: attribute(ATTRIBUTE_BITRATE),
+            duration_secs: attribute(ATTRIBUTE_DURATION),
+            sample_rate: attribute(ATTRIBUTE_SAMPLE_RATE),
+            bit_depth: attribute(ATTRIBUTE_BIT_DEPTH),
+            vbr: file.attribs.get(&ATTRIBUTE_VBR).map(|value| *value == 1),
+            ..DownloadableFile::new(file.name, file.username, file.size as i32)
+        }
+    }
 }
 impl Display for SearchItem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -89,27 +212,57 @@ pub struct JudgeSubmission {
     pub query: DownloadableFile,
 }
 
+#[cfg(test)]
+impl JudgeSubmission {
+    /// Radiohead's "Creep" off Pablo Honey, offered as `filename` by "peer".
+    pub fn creep(filename: &str) -> Self {
+        JudgeSubmission {
+            track: SearchItem::new(
+                "Creep".to_string(),
+                "Pablo Honey".to_string(),
+                "Radiohead".to_string(),
+            ),
+            query: DownloadableFile::new(filename.to_string(), "peer".to_string(), 0),
+        }
+    }
+}
+
 pub struct SearchManager {
-    pub client: Arc<soulseek_rs::Client>,
+    pub backend: Arc<dyn SearchBackend>,
     pub handles: Vec<tokio::task::JoinHandle<anyhow::Result<()>>>,
+    pub search_timeout: Duration,
+    /// How long a search waits for a new file before it ends.
+    pub idle_timeout: Duration,
 }
 
 impl SearchManager {
-    pub fn new(client: Arc<soulseek_rs::Client>) -> Self {
+    pub fn new(backend: Arc<dyn SearchBackend>) -> Self {
         SearchManager {
-            client,
+            backend,
             handles: vec![],
+            search_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(10),
         }
     }
+    pub fn with_search_timeout(mut self, search_timeout: Duration) -> Self {
+        self.search_timeout = search_timeout;
+        self
+    }
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
     fn build_submissions(track: SearchItem, result: SearchResult) -> Vec<JudgeSubmission> {
+        let upload_speed = i32::try_from(result.speed).ok();
+        let free_slot = Some(result.slots > 0);
         result
             .files
             .into_iter()
             .map(|f| JudgeSubmission {
                 query: DownloadableFile {
-                    filename: f.name,
-                    size: f.size as i32,
-                    username: f.username,
+                    upload_speed,
+                    free_slot,
+                    ..DownloadableFile::from(f)
                 },
                 track: track.clone(),
             })
@@ -117,15 +270,16 @@ impl SearchManager {
     }
     pub async fn run(
         &self,
-        track: SearchItem,
-        count_cutoff: usize,
+        plan: SearchPlan,
+        idle_timeout: Duration,
         semaphore: Arc<Semaphore>,
         sender: Arc<Sender<Track>>,
     ) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
-        let client = self.client.clone();
+        let backend = self.backend.clone();
+        let search_timeout = self.search_timeout;
         let hand: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
             let _permit = semaphore.acquire().await.context("Getting permit")?;
-            track_search_task(client, track, count_cutoff, sender)
+            track_search_task(backend, plan, idle_timeout, search_timeout, sender)
                 .await
                 .context("Track search context")?;
             Ok(())
@@ -136,76 +290,115 @@ impl SearchManager {
 
 #[instrument(
     name = "track_search_task",
-    skip(client ),
+    skip(backend, plan),
     fields(
-        id = data.track_id,
-        query = ?data.track,
+        id = plan.item.track_id,
+        query = plan.variant().query,
+        strategy = %plan.variant().strategy,
     )
 )]
 pub async fn track_search_task(
-    client: Arc<soulseek_rs::Client>,
-    data: SearchItem,
-    count_cutoff: usize,
+    backend: Arc<dyn SearchBackend>,
+    plan: SearchPlan,
+    idle_timeout: Duration,
+    search_timeout: Duration,
     sender: Arc<Sender<Track>>,
 ) -> anyhow::Result<()> {
-    let query_string = format!("{} - {}", data.track.as_str(), data.artist);
+    let data = &plan.item;
+    let query_string = plan.variant().query.clone();
     let cancel = Arc::new(AtomicBool::new(false));
     let span = info_span!("track_blocking");
     let search_thread = {
-        let search_client = client.clone();
+        let search_backend = backend.clone();
         let query_string_search = query_string.clone();
         let cancel_search = cancel.clone();
         tokio::task::spawn_blocking(move || {
-            search_client.search_with_cancel(
+            search_backend.search_with_cancel(
                 query_string_search.as_str(),
-                Duration::from_secs(30), // Reduced duration for faster exit
-                Some(cancel_search),
+                search_timeout,
+                cancel_search,
             )
         })
     }
     .instrument(span);
-    let mut previous_submissions = HashSet::new();
-    let mut count = 0;
-    let mut total_files_found = 0;
-    'main: loop {
-        sleep(Duration::from_secs(10)).await;
-        let results = client.get_search_results(&query_string);
-        let results_count: usize = results.iter().map(|res| res.files.len()).sum();
-        if !results.is_empty() && !total_files_found.eq(&results_count) {
-            total_files_found += results_count - total_files_found;
-            for result in results {
-                let submisssions = SearchManager::build_submissions(data.clone(), result);
-                for submission in submisssions {
-                    if !previous_submissions.contains(&(
-                        submission.query.filename.clone(),
-                        submission.query.username.clone(),
-                    )) {
-                        send(Track::Result(submission.clone()), &sender)
-                            .await
-                            .context("Sending result")?;
-                        previous_submissions
-                            .insert((submission.query.filename, submission.query.username));
-                    }
-                }
-            }
-            count = 0;
-        } else {
-            count += 1;
-        }
-        if count > count_cutoff {
-            tracing::info!(
-                times = TIMES_WITH_NO_NEW_FILES,
-                query_string = query_string,
-                "Exited because consecutive empty results",
-            );
-            cancel.store(true, std::sync::atomic::Ordering::Relaxed);
-            break 'main;
+    let mut results = SearchResults::new(backend, &query_string);
+    let mut files_found = 0;
+    while let Some(result) = results.next(idle_timeout).await {
+        files_found += result.files.len();
+        for submission in SearchManager::build_submissions(data.clone(), result) {
+            send(Track::Result(submission), &sender)
+                .await
+                .context("Sending result")?;
         }
     }
+    tracing::info!(
+        files_found,
+        idle_secs = idle_timeout.as_secs_f32(),
+        query_string,
+        "Exited because no new files arrived",
+    );
     cancel.store(true, std::sync::atomic::Ordering::Relaxed);
     search_thread
         .await
         .unwrap()
         .context("Inner search thread issue")?;
+    send(Track::SearchDone(plan), &sender)
+        .await
+        .context("Sending search done")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creep() -> SearchItem {
+        SearchItem::new(
+            " Creep".to_string(),
+            "Pablo  Honey".to_string(),
+            "RADIOHEAD".to_string(),
+        )
+    }
+
+    #[test]
+    fn prefers_spotify_id_then_isrc_then_metadata() {
+        // Same value as the SQL in the `stable_track_identity` migration.
+        let meta = "meta:47de07152b585d20".to_string();
+        assert_eq!(creep().track_id, meta);
+
+        let with_isrc = creep().with_isrc(Some(" gbaye9200070 ".to_string()));
+        assert_eq!(with_isrc.track_id, "isrc:GBAYE9200070");
+
+        let with_both = with_isrc.with_spotify_id(Some("70LcF31zb1H0PyJoS1Sx1r".to_string()));
+        assert_eq!(with_both.track_id, "spotify:70LcF31zb1H0PyJoS1Sx1r");
+        assert_eq!(
+            with_both.identities(),
+            [
+                "spotify:70LcF31zb1H0PyJoS1Sx1r".to_string(),
+                "isrc:GBAYE9200070".to_string(),
+                meta,
+            ]
+        );
+
+        let empty = creep()
+            .with_isrc(Some(String::new()))
+            .with_spotify_id(Some(String::new()));
+        assert_eq!(empty.identities(), [creep().track_id]);
+    }
+
+    #[test]
+    fn metadata_hash_ignores_case_and_spacing_only() {
+        let spaced = SearchItem::new(
+            "creep".to_string(),
+            " pablo honey ".to_string(),
+            "Radiohead".to_string(),
+        );
+        assert_eq!(spaced.track_id, creep().track_id);
+        let other_album = SearchItem::new(
+            "Creep".to_string(),
+            "Creep EP".to_string(),
+            "Radiohead".to_string(),
+        );
+        assert_ne!(other_album.track_id, creep().track_id);
+    }
+}
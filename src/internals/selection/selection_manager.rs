@@ -0,0 +1,626 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    time::Instant,
+};
+
+use crate::internals::{
+    context::context_manager::{
+        Candidate, PendingWork, RetryNext, RetryRequest, Track, WorkToken, send,
+    },
+    judge::judges::token::ParsedPath,
+    search::{
+        query_planner::{SearchAttempt, SearchPlan},
+        search_manager::JudgeSubmission,
+    },
+};
+
+/// What the selection stage is told about a track.
+#[derive(Debug)]
+pub enum SelectionEvent {
+    /// A file the judge accepted.
+    Candidate(Box<Candidate>),
+    /// The search for the plan's current variant returned its last results.
+    SearchDone(Box<SearchPlan>),
+    /// The selected file could not be downloaded.
+    DownloadFailed(Box<JudgeSubmission>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionSettings {
+    /// How long after its search completes a track is chosen, so candidates
+    /// still being judged get a chance. Also how long a search that found
+    /// nothing waits before the next query variant.
+    pub grace: Duration,
+    /// Upper bound from the first candidate, for searches that never finish.
+    pub max_wait: Duration,
+    /// Extensions, best first. Others rank after all of them.
+    pub preferred_formats: Vec<String>,
+    /// Scores within the same step of this size count as equal, so format,
+    /// bitrate and peer decide between near identical matches.
+    pub score_tolerance: f32,
+}
+
+impl Default for SelectionSettings {
+    fn default() -> Self {
+        SelectionSettings {
+            grace: Duration::from_secs(5),
+            max_wait: Duration::from_secs(60),
+            preferred_formats: vec!["flac".to_string(), "mp3".to_string()],
+            score_tolerance: 0.05,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TrackCandidates {
+    first_seen: Instant,
+    search_done: Option<Instant>,
+    /// Best first. After selection only the fallbacks remain.
+    ranked: Vec<Candidate>,
+    selected: Option<JudgeSubmission>,
+    /// Peers a download of this track failed from, skipped from then on.
+    failed_peers: HashSet<String>,
+    failed_downloads: u8,
+    searched_again: bool,
+    /// The finished search not reported as an attempt yet.
+    plan: Option<SearchPlan>,
+}
+
+/// Collects accepted candidates per track and downloads only the best one,
+/// keeping the rest ranked as fallbacks. Searches that end without any are
+/// reported so the next query variant is tried.
+#[derive(Debug, Default)]
+pub struct SelectionManager {
+    pub settings: SelectionSettings,
+    tracks: HashMap<String, TrackCandidates>,
+    /// Outcomes of finished searches, waiting to be sent.
+    attempts: Vec<SearchAttempt>,
+}
+
+impl SelectionManager {
+    pub fn new(settings: SelectionSettings) -> Self {
+        SelectionManager {
+            settings,
+            tracks: HashMap::new(),
+            attempts: vec![],
+        }
+    }
+
+    fn format_rank(&self, submission: &JudgeSubmission) -> usize {
+        let extension = ParsedPath::parse(&submission.query.filename).extension;
+        self.settings
+            .preferred_formats
+            .iter()
+            .position(|format| Some(format.to_lowercase()) == extension)
+            .unwrap_or(self.settings.preferred_formats.len())
+    }
+
+    fn score_step(&self, candidate: &Candidate) -> i64 {
+        (candidate.decision.score / self.settings.score_tolerance.max(f32::EPSILON)).floor() as i64
+    }
+
+    /// Better candidates order first: higher score, preferred format, higher
+    /// bitrate, a free upload slot, then a faster peer.
+    pub fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        let (file_a, file_b) = (&a.submission.query, &b.submission.query);
+        self.score_step(b)
+            .cmp(&self.score_step(a))
+            .then_with(|| {
+                self.format_rank(&a.submission)
+                    .cmp(&self.format_rank(&b.submission))
+            })
+            .then_with(|| file_b.bitrate.cmp(&file_a.bitrate))
+            .then_with(|| file_b.free_slot.cmp(&file_a.free_slot))
+            .then_with(|| file_b.upload_speed.cmp(&file_a.upload_speed))
+            .then_with(|| b.decision.score.total_cmp(&a.decision.score))
+    }
+
+    fn entry(&mut self, track_id: &str) -> &mut TrackCandidates {
+        self.tracks
+            .entry(track_id.to_string())
+            .or_insert_with(|| TrackCandidates {
+                first_seen: Instant::now(),
+                search_done: None,
+                ranked: vec![],
+                selected: None,
+                failed_peers: HashSet::new(),
+                failed_downloads: 0,
+                searched_again: false,
+                plan: None,
+            })
+    }
+
+    pub fn offer(&mut self, candidate: Candidate) {
+        let track_id = candidate.submission.track.track_id.clone();
+        if self.tracks.get(&track_id).is_some_and(|entry| {
+            entry
+                .failed_peers
+                .contains(&candidate.submission.query.username)
+        }) {
+            return;
+        }
+        let position = self.tracks.get(&track_id).map_or(0, |entry| {
+            entry
+                .ranked
+                .partition_point(|ranked| self.compare(ranked, &candidate) != Ordering::Greater)
+        });
+        self.entry(&track_id).ranked.insert(position, candidate);
+    }
+
+    /// Candidates judged after this still count until the grace period ends.
+    /// A track without any by then is reported as a failed attempt.
+    pub fn search_done(&mut self, plan: SearchPlan) {
+        let entry = self.entry(&plan.item.track_id);
+        entry.search_done.get_or_insert_with(Instant::now);
+        if entry.selected.is_some() {
+            self.attempts.push(SearchAttempt {
+                plan,
+                accepted: true,
+            });
+        } else {
+            entry.plan = Some(plan);
+        }
+    }
+
+    fn deadline(&self, entry: &TrackCandidates) -> Option<Instant> {
+        if entry.selected.is_some() || entry.ranked.is_empty() {
+            return None;
+        }
+        let max_wait = entry.first_seen + self.settings.max_wait;
+        Some(match entry.search_done {
+            Some(done) => (done + self.settings.grace).min(max_wait),
+            None => max_wait,
+        })
+    }
+
+    /// When a finished search with no candidate gives up on its variant.
+    fn escalation_deadline(&self, entry: &TrackCandidates) -> Option<Instant> {
+        if entry.selected.is_some() || !entry.ranked.is_empty() || entry.plan.is_none() {
+            return None;
+        }
+        entry.search_done.map(|done| done + self.settings.grace)
+    }
+
+    /// When the next track is due, if any is waiting.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.tracks
+            .values()
+            .flat_map(|entry| [self.deadline(entry), self.escalation_deadline(entry)])
+            .flatten()
+            .min()
+    }
+
+    /// Reports the searches that found nothing acceptable by `now`. The
+    /// track waits for the search of the next variant from then on.
+    pub fn escalate_due(&mut self, now: Instant) {
+        let due: Vec<String> = self
+            .tracks
+            .iter()
+            .filter(|(_, entry)| {
+                self.escalation_deadline(entry)
+                    .is_some_and(|deadline| deadline <= now)
+            })
+            .map(|(track_id, _)| track_id.clone())
+            .collect();
+        for track_id in due {
+            let Some(entry) = self.tracks.get_mut(&track_id) else {
+                continue;
+            };
+            entry.search_done = None;
+            if let Some(plan) = entry.plan.take() {
+                self.attempts.push(SearchAttempt {
+                    plan,
+                    accepted: false,
+                });
+            }
+        }
+    }
+
+    /// Outcomes of finished searches since the last call.
+    pub fn take_attempts(&mut self) -> Vec<SearchAttempt> {
+        std::mem::take(&mut self.attempts)
+    }
+
+    /// Chooses the best candidate of every track due by `now`.
+    pub fn select_due(&mut self, now: Instant) -> Vec<JudgeSubmission> {
+        let due: Vec<String> = self
+            .tracks
+            .iter()
+            .filter(|(_, entry)| self.deadline(entry).is_some_and(|deadline| deadline <= now))
+            .map(|(track_id, _)| track_id.clone())
+            .collect();
+        due.iter()
+            .filter_map(|track_id| self.select(track_id))
+            .collect()
+    }
+
+    /// Chooses now, whatever the deadlines.
+    pub fn select_all(&mut self) -> Vec<JudgeSubmission> {
+        let waiting: Vec<String> = self
+            .tracks
+            .iter()
+            .filter(|(_, entry)| entry.selected.is_none())
+            .map(|(track_id, _)| track_id.clone())
+            .collect();
+        waiting
+            .iter()
+            .filter_map(|track_id| self.select(track_id))
+            .collect()
+    }
+
+    fn select(&mut self, track_id: &str) -> Option<JudgeSubmission> {
+        let entry = self.tracks.get_mut(track_id)?;
+        if entry.ranked.is_empty() {
+            return None;
+        }
+        let best = entry.ranked.remove(0);
+        tracing::info!(
+            track_id,
+            file_q = best.submission.query.filename,
+            score = best.decision.score,
+            fallbacks = entry.ranked.len(),
+            "selected candidate"
+        );
+        entry.selected = Some(best.submission.clone());
+        if let Some(plan) = entry.plan.take() {
+            self.attempts.push(SearchAttempt {
+                plan,
+                accepted: true,
+            });
+        }
+        Some(best.submission)
+    }
+
+    /// Records a failed download and picks what to do next: the best
+    /// remaining candidate from another peer, else one more search, after
+    /// which the track is abandoned.
+    pub fn download_failed(&mut self, failed: JudgeSubmission) -> RetryRequest {
+        let entry = self.entry(&failed.track.track_id);
+        entry.failed_downloads = entry.failed_downloads.saturating_add(1);
+        entry.failed_peers.insert(failed.query.username.clone());
+        let failed_peers = &entry.failed_peers;
+        entry
+            .ranked
+            .retain(|candidate| !failed_peers.contains(&candidate.submission.query.username));
+        let next = if entry.ranked.is_empty() {
+            entry.selected = None;
+            if entry.searched_again {
+                RetryNext::Abandon
+            } else {
+                // Candidates of the new search are collected from scratch.
+                entry.searched_again = true;
+                entry.first_seen = Instant::now();
+                entry.search_done = None;
+                entry.plan = None;
+                RetryNext::Search
+            }
+        } else {
+            let fallback = entry.ranked.remove(0).submission;
+            entry.selected = Some(fallback.clone());
+            RetryNext::Fallback(Box::new(fallback))
+        };
+        tracing::info!(
+            track_id = failed.track.track_id,
+            file_q = failed.query.filename,
+            attempts = entry.failed_downloads,
+            ?next,
+            "download failed"
+        );
+        RetryRequest {
+            retry_attempts: entry.failed_downloads,
+            failed_download_result: failed.query.clone(),
+            request: failed,
+            next,
+        }
+    }
+
+    /// Candidates left for a track after its selection, best first.
+    pub fn fallbacks(&self, track_id: &str) -> &[Candidate] {
+        self.tracks
+            .get(track_id)
+            .map_or(&[], |entry| entry.ranked.as_slice())
+    }
+
+    /// Receives events until `receiver` closes, sending each chosen file as
+    /// `Track::Downloadable`. Tracks still waiting are chosen on close.
+    pub async fn run(
+        &mut self,
+        mut receiver: Receiver<(SelectionEvent, WorkToken)>,
+        sender: Arc<Sender<Track>>,
+        pending: PendingWork,
+    ) -> anyhow::Result<()> {
+        // Held while a deadline is set, as reaching it sends to the cycle.
+        let mut waiting = None;
+        loop {
+            let deadline = self.next_deadline();
+            let due = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let (selected, work) = tokio::select! {
+                event = receiver.recv() => match event {
+                    Some((SelectionEvent::Candidate(candidate), work)) => {
+                        self.offer(*candidate);
+                        (vec![], Some(work))
+                    }
+                    Some((SelectionEvent::SearchDone(plan), work)) => {
+                        self.search_done(*plan);
+                        (vec![], Some(work))
+                    }
+                    Some((SelectionEvent::DownloadFailed(failed), work)) => {
+                        let retry = self.download_failed(*failed);
+                        send(Track::Retry(retry), &sender)
+                            .await
+                            .context("sending retry")?;
+                        (vec![], Some(work))
+                    }
+                    None => break,
+                },
+                _ = due => {
+                    let now = Instant::now();
+                    self.escalate_due(now);
+                    (self.select_due(now), None)
+                }
+            };
+            for attempt in self.take_attempts() {
+                send(Track::Attempt(attempt), &sender)
+                    .await
+                    .context("sending search attempt")?;
+            }
+            for submission in selected {
+                send(Track::Downloadable(submission), &sender)
+                    .await
+                    .context("sending selection")?;
+            }
+            waiting = match self.next_deadline() {
+                Some(_) => waiting.or_else(|| Some(pending.start())),
+                None => None,
+            };
+            drop(work);
+        }
+        for submission in self.select_all() {
+            send(Track::Downloadable(submission), &sender)
+                .await
+                .context("sending selection")?;
+        }
+        for attempt in self.take_attempts() {
+            send(Track::Attempt(attempt), &sender)
+                .await
+                .context("sending search attempt")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internals::{
+        judge::judge_manager::{AcceptancePolicy, JudgeDecision},
+        search::{
+            query_planner::QueryPlanner,
+            search_manager::{DownloadableFile, SearchItem},
+        },
+    };
+
+    fn candidate(filename: &str, score: f32, file: DownloadableFile) -> Candidate {
+        Candidate {
+            submission: JudgeSubmission {
+                query: DownloadableFile {
+                    filename: filename.to_string(),
+                    ..file
+                },
+                ..JudgeSubmission::creep(filename)
+            },
+            decision: JudgeDecision::from_score("token", score, AcceptancePolicy::new(0.5)),
+        }
+    }
+
+    fn file() -> DownloadableFile {
+        DownloadableFile::new(String::new(), "peer".to_string(), 5_000_000)
+    }
+
+    fn selected(candidates: Vec<Candidate>) -> Vec<String> {
+        let mut manager = SelectionManager::new(SelectionSettings::default());
+        for candidate in candidates {
+            manager.offer(candidate);
+        }
+        let track_id = manager.tracks.keys().next().unwrap().clone();
+        let best = manager.select_all().remove(0).query.filename;
+        let mut order = vec![best];
+        order.extend(
+            manager
+                .fallbacks(&track_id)
+                .iter()
+                .map(|candidate| candidate.submission.query.filename.clone()),
+        );
+        order
+    }
+
+    #[test]
+    fn ranks_clearly_better_scores_first() {
+        let order = selected(vec![
+            candidate("Radiohead\\Creep.flac", 0.6, file()),
+            candidate("Radiohead\\Pablo Honey\\02 Creep.mp3", 0.95, file()),
+        ]);
+        assert_eq!(order[0], "Radiohead\\Pablo Honey\\02 Creep.mp3");
+    }
+
+    #[test]
+    fn prefers_formats_then_bitrate_within_tolerance() {
+        let order = selected(vec![
+            candidate(
+                "a\\Creep.mp3",
+                0.92,
+                DownloadableFile {
+                    bitrate: Some(128),
+                    ..file()
+                },
+            ),
+            candidate(
+                "b\\Creep.mp3",
+                0.91,
+                DownloadableFile {
+                    bitrate: Some(320),
+                    ..file()
+                },
+            ),
+            candidate("c\\Creep.flac", 0.9, file()),
+            candidate("d\\Creep.wma", 0.93, file()),
+        ]);
+        assert_eq!(
+            order,
+            [
+                "c\\Creep.flac",
+                "b\\Creep.mp3",
+                "a\\Creep.mp3",
+                "d\\Creep.wma"
+            ]
+        );
+    }
+
+    #[test]
+    fn prefers_free_and_fast_peers() {
+        let order = selected(vec![
+            candidate(
+                "queued\\Creep.mp3",
+                0.9,
+                DownloadableFile {
+                    free_slot: Some(false),
+                    upload_speed: Some(5_000_000),
+                    ..file()
+                },
+            ),
+            candidate(
+                "slow\\Creep.mp3",
+                0.9,
+                DownloadableFile {
+                    free_slot: Some(true),
+                    upload_speed: Some(100_000),
+                    ..file()
+                },
+            ),
+            candidate(
+                "fast\\Creep.mp3",
+                0.9,
+                DownloadableFile {
+                    free_slot: Some(true),
+                    upload_speed: Some(2_000_000),
+                    ..file()
+                },
+            ),
+        ]);
+        assert_eq!(
+            order,
+            ["fast\\Creep.mp3", "slow\\Creep.mp3", "queued\\Creep.mp3"]
+        );
+    }
+
+    #[test]
+    fn selects_after_grace_once_search_is_done() {
+        let mut manager = SelectionManager::new(SelectionSettings::default());
+        let first = candidate("a\\Creep.mp3", 0.9, file());
+        let plan = QueryPlanner::default().plan(&first.submission.track);
+        manager.offer(first);
+        let start = Instant::now();
+        assert!(manager.select_due(start).is_empty());
+
+        manager.search_done(plan);
+        assert!(
+            manager
+                .select_due(start + Duration::from_secs(4))
+                .is_empty()
+        );
+        assert_eq!(manager.select_due(start + Duration::from_secs(6)).len(), 1);
+        assert_eq!(manager.next_deadline(), None);
+    }
+
+    #[test]
+    fn reports_variants_without_candidates_then_the_one_that_found_the_track() {
+        let mut manager = SelectionManager::new(SelectionSettings::default());
+        let item = SearchItem::new(
+            "Creep".to_string(),
+            "Pablo Honey".to_string(),
+            "Radiohead".to_string(),
+        );
+        let plan = QueryPlanner::default().plan(&item);
+        let start = Instant::now();
+        manager.search_done(plan.clone());
+        manager.escalate_due(start + Duration::from_secs(4));
+        assert!(manager.take_attempts().is_empty());
+        manager.escalate_due(start + Duration::from_secs(6));
+        let failed = manager.take_attempts();
+        assert_eq!(failed.len(), 1);
+        assert!(!failed[0].accepted);
+        assert_eq!(manager.next_deadline(), None);
+
+        let next = failed[0].plan.next().unwrap();
+        manager.offer(candidate("a\\Creep.mp3", 0.9, file()));
+        manager.search_done(next.clone());
+        assert_eq!(manager.select_all().len(), 1);
+        assert_eq!(
+            manager.take_attempts(),
+            [SearchAttempt {
+                plan: next,
+                accepted: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn selects_unfinished_searches_after_max_wait() {
+        let mut manager = SelectionManager::new(SelectionSettings::default());
+        manager.offer(candidate("a\\Creep.mp3", 0.9, file()));
+        let start = Instant::now();
+        assert!(
+            manager
+                .select_due(start + Duration::from_secs(30))
+                .is_empty()
+        );
+        assert_eq!(manager.select_due(start + Duration::from_secs(61)).len(), 1);
+    }
+
+    #[test]
+    fn falls_back_to_other_peers_then_searches_once() {
+        let peer = |username: &str| DownloadableFile {
+            username: username.to_string(),
+            ..file()
+        };
+        let mut manager = SelectionManager::new(SelectionSettings::default());
+        manager.offer(candidate("a\\Creep.flac", 0.9, peer("alice")));
+        manager.offer(candidate("a\\Creep.mp3", 0.9, peer("alice")));
+        manager.offer(candidate("b\\Creep.mp3", 0.9, peer("bob")));
+        let first = manager.select_all().remove(0);
+        assert_eq!(first.query.filename, "a\\Creep.flac");
+
+        // Alice's other file is skipped along with her.
+        let retry = manager.download_failed(first);
+        assert_eq!(retry.retry_attempts, 1);
+        let RetryNext::Fallback(fallback) = retry.next else {
+            panic!("expected a fallback, got {:?}", retry.next);
+        };
+        assert_eq!(fallback.query.username, "bob");
+
+        let retry = manager.download_failed(*fallback);
+        assert!(matches!(retry.next, RetryNext::Search));
+        // Failed peers stay excluded from the new search.
+        manager.offer(candidate("a\\Creep.flac", 0.9, peer("alice")));
+        manager.offer(candidate("c\\Creep.mp3", 0.9, peer("carol")));
+        let again = manager.select_all().remove(0);
+        assert_eq!(again.query.username, "carol");
+
+        let retry = manager.download_failed(again);
+        assert_eq!(retry.retry_attempts, 3);
+        assert!(matches!(retry.next, RetryNext::Abandon));
+    }
+}
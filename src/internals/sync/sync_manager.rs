@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+
+use crate::internals::{
+    context::context_manager::Track, database::manager::DatabaseManager,
+    search::search_manager::SearchItem,
+};
+
+/// Outcome of diffing a source playlist against the download history.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Tracks never queued before.
+    pub added: usize,
+    /// Tracks queued on a previous run that never got downloaded.
+    pub failed: usize,
+    /// Tracks already downloaded.
+    pub unchanged: usize,
+    /// Tracks listed by a source at its last sync that it no longer lists.
+    pub removed: usize,
+}
+
+pub struct SyncManager;
+
+impl SyncManager {
+    /// Keeps only the queries that are new or were not downloaded yet.
+    pub fn plan(
+        sources: &[(String, Vec<SearchItem>)],
+        database_manager: &mut DatabaseManager,
+    ) -> anyhow::Result<(Vec<Track>, SyncReport)> {
+        let known = database_manager
+            .known_track_ids()
+            .context("Loading known tracks")?;
+        let downloaded = database_manager
+            .downloaded_track_ids()
+            .context("Loading downloaded tracks")?;
+        let mut listed = HashMap::new();
+        for (source, _) in sources {
+            let ids = database_manager
+                .source_track_ids(source)
+                .with_context(|| format!("Loading tracks last synced from {source}"))?;
+            listed.insert(source.clone(), ids);
+        }
+        Ok(Self::diff(sources, &listed, &known, &downloaded))
+    }
+
+    /// Records what each source lists now, for the next sync to compare with.
+    pub fn remember(
+        sources: &[(String, Vec<SearchItem>)],
+        database_manager: &mut DatabaseManager,
+    ) -> anyhow::Result<()> {
+        for (source, items) in sources {
+            let ids: Vec<String> = items.iter().map(|item| item.track_id.clone()).collect();
+            database_manager
+                .set_source_tracks(source, &ids)
+                .with_context(|| format!("Recording tracks of {source}"))?;
+        }
+        Ok(())
+    }
+
+    /// Matches tracks on any of their identities, so rows keyed before the
+    /// current identity scheme still count as the same track. `listed` holds
+    /// what each source listed at the last sync, so only tracks that left the
+    /// source being synced count as removed.
+    pub fn diff(
+        sources: &[(String, Vec<SearchItem>)],
+        listed: &HashMap<String, HashSet<String>>,
+        known: &HashSet<String>,
+        downloaded: &HashSet<String>,
+    ) -> (Vec<Track>, SyncReport) {
+        let mut report = SyncReport::default();
+        let mut seen = HashSet::new();
+        let mut pending = vec![];
+        for (source, items) in sources {
+            let mut in_source = HashSet::new();
+            for search_item in items {
+                let identities = search_item.identities();
+                in_source.extend(identities.iter().cloned());
+                if !seen.insert(search_item.track_id.clone()) {
+                    continue;
+                }
+                if identities.iter().any(|id| downloaded.contains(id)) {
+                    report.unchanged += 1;
+                    continue;
+                }
+                if identities.iter().any(|id| known.contains(id)) {
+                    report.failed += 1;
+                } else {
+                    report.added += 1;
+                }
+                pending.push(Track::Query(search_item.clone()));
+            }
+            if let Some(listed) = listed.get(source) {
+                report.removed += listed.difference(&in_source).count();
+            }
+        }
+        tracing::info!(
+            added = report.added,
+            failed = report.failed,
+            unchanged = report.unchanged,
+            removed = report.removed,
+            "Synced playlist against history"
+        );
+        (pending, report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(track: &str) -> SearchItem {
+        SearchItem::new(track.to_string(), "Album".to_string(), "Artist".to_string())
+    }
+
+    fn ids(items: &[&SearchItem]) -> HashSet<String> {
+        items.iter().map(|item| item.track_id.clone()).collect()
+    }
+
+    fn source(items: Vec<SearchItem>) -> Vec<(String, Vec<SearchItem>)> {
+        vec![("playlist".to_string(), items)]
+    }
+
+    fn queued(pending: &[Track]) -> Vec<String> {
+        pending
+            .iter()
+            .filter_map(|track| match track {
+                Track::Query(item) => Some(item.track.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sorts_tracks_into_added_failed_unchanged_and_removed() {
+        let (new, failed, done, gone) = (item("New"), item("Failed"), item("Done"), item("Gone"));
+        let known = ids(&[&failed, &done, &gone]);
+        let listed = HashMap::from([("playlist".to_string(), known.clone())]);
+        let downloaded = ids(&[&done]);
+        let cases = [
+            (
+                vec![],
+                SyncReport {
+                    removed: 3,
+                    ..SyncReport::default()
+                },
+                vec![],
+            ),
+            (
+                vec![new.clone(), failed.clone(), done.clone(), new.clone()],
+                SyncReport {
+                    added: 1,
+                    failed: 1,
+                    unchanged: 1,
+                    removed: 1,
+                },
+                vec!["New", "Failed"],
+            ),
+            (
+                vec![done.clone(), gone.clone()],
+                SyncReport {
+                    failed: 1,
+                    unchanged: 1,
+                    removed: 1,
+                    ..SyncReport::default()
+                },
+                vec!["Gone"],
+            ),
+        ];
+        for (tracks, report, expected) in cases {
+            let (pending, got) = SyncManager::diff(&source(tracks), &listed, &known, &downloaded);
+            assert_eq!(got, report);
+            assert_eq!(queued(&pending), expected);
+        }
+    }
+
+    #[test]
+    fn matches_history_keyed_by_an_older_identity() {
+        let legacy = item("Creep").with_isrc(Some("GBAYE9200070".to_string()));
+        let current = legacy
+            .clone()
+            .with_spotify_id(Some("70LcF31zb1H0PyJoS1Sx1r".to_string()));
+        let history = ids(&[&legacy]);
+        let listed = HashMap::from([("playlist".to_string(), history.clone())]);
+        let (pending, report) =
+            SyncManager::diff(&source(vec![current]), &listed, &history, &history);
+        assert!(pending.is_empty());
+        assert_eq!(
+            report,
+            SyncReport {
+                unchanged: 1,
+                ..SyncReport::default()
+            }
+        );
+    }
+
+    #[test]
+    fn counts_only_tracks_that_left_the_source_being_synced() {
+        let (kept, dropped, elsewhere) = (item("Kept"), item("Dropped"), item("Elsewhere"));
+        let known = ids(&[&kept, &dropped, &elsewhere]);
+        let listed = HashMap::from([
+            ("playlist".to_string(), ids(&[&kept, &dropped])),
+            ("album".to_string(), ids(&[&elsewhere])),
+        ]);
+        let (_, report) = SyncManager::diff(&source(vec![kept.clone()]), &listed, &known, &known);
+        assert_eq!(
+            report,
+            SyncReport {
+                unchanged: 1,
+                removed: 1,
+                ..SyncReport::default()
+            }
+        );
+    }
+}
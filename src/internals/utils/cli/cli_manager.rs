@@ -0,0 +1,195 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::internals::utils::config::config_manager::{ConfigLayer, JudgeKind};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "convert-invert",
+    version,
+    about = "Download playlists from Soulseek"
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Search, judge and download every track of the configured sources.
+    Run(RunArgs),
+    /// Like `run`, but only queue tracks that were not downloaded yet.
+    Sync(SyncArgs),
+    /// Queue again every track from earlier runs that never finished downloading.
+    RetryFailed(RunArgs),
+    /// Show the outcome of a run, or list recent runs.
+    Status(StatusArgs),
+    /// Write the track history as CSV or JSON.
+    Export(ExportArgs),
+    /// Show rejected files with why the judges turned them down.
+    Rejections(RejectionsArgs),
+    /// Score candidate filenames for a track with the configured judge.
+    JudgeTest(JudgeTestArgs),
+    /// Measure judges against a labeled dataset of candidates.
+    JudgeEval(JudgeEvalArgs),
+    /// Inspect the effective configuration.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RunArgs {
+    /// Attempt number, appended to the run id.
+    #[arg(long, default_value_t = 1)]
+    pub attempt: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct SyncArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+    /// Print what would be queued without downloading anything.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct StatusArgs {
+    /// Run to report on, e.g. `my_run_attempt_1`. Lists recent runs when omitted.
+    pub run_id: Option<String>,
+    /// How many runs to list.
+    #[arg(long, default_value_t = 20)]
+    pub limit: i64,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ExportArgs {
+    #[arg(long, value_enum, default_value_t)]
+    pub format: ExportFormat,
+    /// Written to stdout when omitted.
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+    /// Only tracks queued by this run.
+    #[arg(long)]
+    pub run: Option<String>,
+    /// Only tracks without a completed download.
+    #[arg(long)]
+    pub pending: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RejectionsArgs {
+    /// Only tracks queued by this run.
+    #[arg(long)]
+    pub run: Option<String>,
+    /// Only tracks whose title or artist contains this, ignoring case.
+    #[arg(long)]
+    pub track: Option<String>,
+    #[arg(long, default_value_t = 20)]
+    pub limit: i64,
+    /// Print the rejections as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct JudgeTestArgs {
+    #[arg(long)]
+    pub track: String,
+    #[arg(long)]
+    pub artist: String,
+    #[arg(long, default_value = "")]
+    pub album: String,
+    /// Track duration, as Spotify reports it.
+    #[arg(long)]
+    pub duration_ms: Option<i32>,
+    /// Size in bytes advertised for every candidate.
+    #[arg(long, default_value_t = 0)]
+    pub size: i32,
+    /// Bitrate in kbps advertised for every candidate.
+    #[arg(long)]
+    pub bitrate: Option<i32>,
+    /// Duration in seconds advertised for every candidate.
+    #[arg(long)]
+    pub file_duration_secs: Option<i32>,
+    /// Judge all candidates in one batch, as `judge_batch_size` does.
+    #[arg(long)]
+    pub batch: bool,
+    /// Candidate Soulseek filenames.
+    #[arg(required = true)]
+    pub filenames: Vec<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct JudgeEvalArgs {
+    /// Labeled candidates, `.csv` or `.json`.
+    #[arg(required_unless_present = "export_history")]
+    pub dataset: Option<PathBuf>,
+    /// Judges to compare, defaults to the configured one.
+    #[arg(long, value_delimiter = ',')]
+    pub judges: Vec<JudgeKind>,
+    /// Thresholds in the ROC table, evenly spaced from 0 to 1.
+    #[arg(long, default_value_t = 20)]
+    pub steps: usize,
+    /// Misclassified candidates listed per judge.
+    #[arg(long, default_value_t = 20)]
+    pub show: usize,
+    /// Write every judged candidate from the database as a CSV to label,
+    /// downloaded files marked `true`, and exit.
+    #[arg(long, conflicts_with = "dataset")]
+    pub export_history: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the merged configuration with secrets redacted and validate it.
+    Check,
+}
+
+/// Overrides that win over both the config file and the environment.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConfigArgs {
+    /// Config file, defaults to `CONFIG_FILE` or `convert-invert.toml`.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Spotify URI/URL or playlist file, may be repeated.
+    #[arg(long = "source", global = true)]
+    pub sources: Vec<String>,
+    #[arg(long, global = true)]
+    pub download_root: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub chunk_size: Option<usize>,
+    #[arg(long, global = true)]
+    pub track_limit: Option<usize>,
+    #[arg(long, global = true)]
+    pub run_id: Option<String>,
+    #[arg(long, global = true)]
+    pub judge: Option<JudgeKind>,
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+}
+
+impl From<ConfigArgs> for ConfigLayer {
+    fn from(args: ConfigArgs) -> Self {
+        ConfigLayer {
+            sources: (!args.sources.is_empty()).then_some(args.sources),
+            download_root: args.download_root,
+            chunk_size: args.chunk_size,
+            track_limit: args.track_limit,
+            run_id: args.run_id,
+            judge: args.judge,
+            log_level: args.log_level,
+            ..Default::default()
+        }
+    }
+}
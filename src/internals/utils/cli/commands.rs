@@ -0,0 +1,561 @@
+use std::io::Write;
+
+use anyhow::Context;
+use diesel::PgConnection;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::internals::{
+    context::context_manager::{
+        Managers, RejectReason, Track, build_judge, build_judge_cache, build_judge_manager,
+        build_plausibility_judge,
+    },
+    database::{establish_connection_to, manager::DatabaseManager},
+    eval::eval_manager::{self, Confusion, LabeledRow},
+    judge::judge_manager::{Judge, JudgeDecision},
+    judge::judges::version::VersionFilter,
+    query::query_manager::QueryManager,
+    search::search_manager::{DownloadableFile, JudgeSubmission, SearchItem},
+    sync::sync_manager::SyncManager,
+    utils::{
+        cli::cli_manager::{
+            ExportArgs, ExportFormat, JudgeEvalArgs, JudgeTestArgs, RejectionsArgs, StatusArgs,
+        },
+        config::config_manager::Config,
+        trace,
+    },
+};
+
+/// Where a download run takes its tracks from.
+#[derive(Debug, Clone, Copy)]
+pub enum RunPlan {
+    Playlist,
+    Sync { dry_run: bool },
+    RetryFailed,
+}
+
+impl RunPlan {
+    fn command(&self) -> &'static str {
+        match self {
+            RunPlan::Playlist => "run",
+            RunPlan::Sync { .. } => "sync",
+            RunPlan::RetryFailed => "retry-failed",
+        }
+    }
+}
+
+fn connect(config: &Config) -> anyhow::Result<PgConnection> {
+    let database_url = config
+        .database_url
+        .as_deref()
+        .context("database_url must be set")?;
+    establish_connection_to(database_url)
+}
+
+/// Prints the redacted config, failing when it does not validate.
+pub fn config_check(config: &Config) -> anyhow::Result<()> {
+    print!("{}", config.to_redacted_toml()?);
+    config.validate().context("Configuration is invalid")?;
+    println!("# configuration is valid");
+    Ok(())
+}
+
+pub async fn download(mut config: Config, plan: RunPlan, attempt: usize) -> anyhow::Result<()> {
+    config.run_id = format!("{}_attempt_{}", config.run_id, attempt);
+    match plan {
+        RunPlan::RetryFailed => config.validate_downloads(),
+        _ => config.validate(),
+    }
+    .context("Invalid configuration")?;
+    std::fs::create_dir_all(&config.download_root).context("Creating download root")?;
+
+    trace::otel_trace::init_tracing_with_otel(
+        "convert-invert".to_string(),
+        config.run_id.clone(),
+        config.otlp_endpoint.clone(),
+        &config.log_level,
+    )
+    .context("Tracing")?;
+
+    let connection = &mut connect(&config)?;
+    let playlist = match plan {
+        RunPlan::RetryFailed => DatabaseManager::new(connection)
+            .undownloaded_search_items()
+            .context("Loading failed tracks")?
+            .into_iter()
+            .map(Track::Query)
+            .collect(),
+        _ => {
+            let query_manager = QueryManager::new(
+                config.sources.clone(),
+                config.client_id.clone(),
+                config.client_secret.clone(),
+            )
+            .with_redirect_uri(config.redirect_uri.clone());
+            if let RunPlan::Sync { dry_run } = plan {
+                let sources = query_manager
+                    .fetch_sources()
+                    .await
+                    .context("Fetching sources")?;
+                let mut database_manager = DatabaseManager::new(connection);
+                let (pending, report) = SyncManager::plan(&sources, &mut database_manager)
+                    .context("Diffing playlist against history")?;
+                if !dry_run {
+                    SyncManager::remember(&sources, &mut database_manager)
+                        .context("Recording synced sources")?;
+                }
+                println!(
+                    "Sync: {} added, {} failed before, {} unchanged, {} removed",
+                    report.added, report.failed, report.unchanged, report.removed
+                );
+                pending
+            } else {
+                query_manager.fetch().await.context("Fetching sources")?
+            }
+        }
+    };
+    if let RunPlan::Sync { dry_run: true } = plan {
+        for track in &playlist {
+            if let Track::Query(item) = track {
+                println!("{item}");
+            }
+        }
+        trace::otel_trace::shutdown_otel();
+        return Ok(());
+    }
+
+    let run = DatabaseManager::new(connection)
+        .start_run(&config.run_id, plan.command())
+        .context("Recording run")?;
+    let judge_cache = build_judge_cache(&config).context("Opening judge cache")?;
+    let mut count = 0;
+    let track_limit = config.track_limit.unwrap_or(usize::MAX);
+    for chunk in &playlist
+        .into_iter()
+        .take(track_limit)
+        .chunks(config.chunk_size)
+    {
+        count += 1;
+        let (sender, receiver) = tokio::sync::mpsc::channel(20000);
+        let managers = Managers::new(config.clone())?.with_judge_cache(judge_cache.clone());
+        let sender = Managers::inject_tracks(chunk, sender).await?;
+        let mut store = DatabaseManager::new(connection).with_run(Some(run));
+        managers
+            .run_cycle(sender, receiver, &mut store)
+            .await
+            .context("Running cycle")?;
+        tracing::info!(cycle_n = count, "\n\nDone with cycle\n\n");
+    }
+    DatabaseManager::new(connection)
+        .finish_run(run)
+        .context("Finishing run")?;
+    if let Some(cache) = &judge_cache {
+        println!(
+            "Judge cache: {} hits, {} misses",
+            cache.stats.hits(),
+            cache.stats.misses()
+        );
+    }
+
+    trace::otel_trace::shutdown_otel();
+    Ok(())
+}
+
+pub fn status(config: &Config, args: StatusArgs) -> anyhow::Result<()> {
+    let connection = &mut connect(config)?;
+    let mut database_manager = DatabaseManager::new(connection);
+    let Some(run_id) = args.run_id else {
+        for run in database_manager.list_runs(args.limit)? {
+            let finished = run
+                .finished_at
+                .map_or("running".to_string(), |at| at.to_string());
+            println!(
+                "{}\t{}\t{}\t{}",
+                run.run_id, run.command, run.started_at, finished
+            );
+        }
+        return Ok(());
+    };
+    let summary = database_manager
+        .run_summary(&run_id)?
+        .with_context(|| format!("No run named {run_id}"))?;
+    let finished = summary
+        .run
+        .finished_at
+        .map_or("still running or interrupted".to_string(), |at| {
+            at.to_string()
+        });
+    println!("run:        {}", summary.run.run_id);
+    println!("command:    {}", summary.run.command);
+    println!("started:    {}", summary.run.started_at);
+    println!("finished:   {finished}");
+    println!("tracks:     {}", summary.tracks);
+    println!("downloaded: {}", summary.downloaded);
+    println!("pending:    {}", summary.tracks - summary.downloaded);
+    println!(
+        "rejected:   {} (tracks with at least one rejected file)",
+        summary.rejected
+    );
+    println!("retried:    {}", summary.retried);
+    if !summary.found_by.is_empty() {
+        let found_by = summary
+            .found_by
+            .iter()
+            .map(|(strategy, count)| format!("{strategy} {count}"))
+            .join(", ");
+        println!("found by:   {found_by}");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct ExportRow {
+    track_id: String,
+    artist: String,
+    track: String,
+    album: String,
+    isrc: Option<String>,
+    spotify_id: Option<String>,
+    duration_ms: Option<i32>,
+    status: &'static str,
+    downloaded_file: Option<String>,
+}
+
+pub fn export(config: &Config, args: ExportArgs) -> anyhow::Result<()> {
+    let connection = &mut connect(config)?;
+    let rows: Vec<ExportRow> = DatabaseManager::new(connection)
+        .track_history(args.run.as_deref())?
+        .into_iter()
+        .filter(|history| !args.pending || history.downloaded_file.is_none())
+        .map(|history| ExportRow {
+            status: match history.downloaded_file {
+                Some(_) => "downloaded",
+                None => "pending",
+            },
+            track_id: history.item.track_id,
+            artist: history.item.artist,
+            track: history.item.track,
+            album: history.item.album,
+            isrc: history.item.isrc,
+            spotify_id: history.item.spotify_id,
+            duration_ms: history.item.duration_ms,
+            downloaded_file: history.downloaded_file,
+        })
+        .collect();
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(
+            std::fs::File::create(path).with_context(|| format!("Creating {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+    match args.format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            for row in &rows {
+                writer.serialize(row).context("Writing csv row")?;
+            }
+            writer.flush().context("Flushing csv")?;
+        }
+        ExportFormat::Json => {
+            let mut output = output;
+            serde_json::to_writer_pretty(&mut output, &rows).context("Writing json")?;
+            writeln!(output).context("Writing json")?;
+        }
+    }
+    Ok(())
+}
+
+pub fn rejections(config: &Config, args: RejectionsArgs) -> anyhow::Result<()> {
+    let connection = &mut connect(config)?;
+    let rejections = DatabaseManager::new(connection).rejections(
+        args.run.as_deref(),
+        args.track.as_deref(),
+        args.limit,
+    )?;
+    if args.json {
+        let mut output = std::io::stdout();
+        serde_json::to_writer_pretty(&mut output, &rejections).context("Writing json")?;
+        writeln!(output).context("Writing json")?;
+        return Ok(());
+    }
+    for rejection in &rejections {
+        let (submission, reason) = rejection.parts();
+        println!("{}", submission.track);
+        println!(
+            "  file:   {} from {}",
+            submission.query.filename, submission.query.username
+        );
+        match reason {
+            RejectReason::AlreadyDownloaded => println!("  reason: already downloaded"),
+            RejectReason::NotMusic(filename) => println!("  reason: not music ({filename})"),
+            RejectReason::JudgeFailed(error) => println!("  reason: judge failed ({error})"),
+            RejectReason::AbandonedAttemptingSearch => {
+                println!("  reason: abandoned after failed downloads and a new search")
+            }
+            RejectReason::VersionMismatch(mismatch) => {
+                let side = if mismatch.in_file { "file" } else { "query" };
+                println!(
+                    "  reason: version, only the {side} is {}",
+                    mismatch.qualifier
+                );
+            }
+            RejectReason::LowScore(decision) => {
+                println!("  reason: low score");
+                print_decision(decision, 2);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A decision, its explanation and the decisions it combined, indented.
+fn print_decision(decision: &JudgeDecision, indent: usize) {
+    let pad = " ".repeat(indent);
+    println!(
+        "{pad}{} {:.3} (threshold {:.3}, {})",
+        decision.judge,
+        decision.score,
+        decision.threshold,
+        if decision.accepted {
+            "accepted"
+        } else {
+            "rejected"
+        }
+    );
+    let explanation = &decision.explanation;
+    if !explanation.fields.is_empty() {
+        let fields = explanation
+            .fields
+            .iter()
+            .map(|(field, score)| format!("{field}={score:.2}"))
+            .join(" ");
+        println!("{pad}  fields:  {fields}");
+    }
+    if !explanation.matched.is_empty() {
+        println!("{pad}  matched: {}", explanation.matched.join(" "));
+    }
+    if !explanation.missing.is_empty() {
+        println!("{pad}  missing: {}", explanation.missing.join(" "));
+    }
+    for rule in &explanation.rules {
+        println!("{pad}  rule:    {rule}");
+    }
+    for part in &decision.parts {
+        print_decision(part, indent + 2);
+    }
+}
+
+pub async fn judge_test(config: &Config, args: JudgeTestArgs) -> anyhow::Result<()> {
+    let judge = build_judge(config)?;
+    println!("# judge {}", judge.name());
+    let version_filter = config
+        .versions
+        .enabled
+        .then(|| VersionFilter::new(&config.versions.keywords));
+    let plausibility = config
+        .plausibility
+        .enabled
+        .then(|| build_plausibility_judge(config));
+    let item =
+        SearchItem::new(args.track, args.album, args.artist).with_duration_ms(args.duration_ms);
+    let mut pending = vec![];
+    for filename in args.filenames {
+        let submission = JudgeSubmission {
+            track: item.clone(),
+            query: DownloadableFile {
+                bitrate: args.bitrate,
+                duration_secs: args.file_duration_secs,
+                ..DownloadableFile::new(filename.clone(), "judge-test".to_string(), args.size)
+            },
+        };
+        if let Some(mismatch) = version_filter
+            .as_ref()
+            .and_then(|filter| filter.check(&submission))
+        {
+            let side = if mismatch.in_file { "file" } else { "query" };
+            println!(
+                "-\treject\t{filename}\tversion: only the {side} is {}",
+                mismatch.qualifier
+            );
+            continue;
+        }
+        if let Some(plausibility) = &plausibility {
+            let decision = plausibility
+                .decide(submission.clone())
+                .await
+                .context("Judging")?;
+            if !decision.accepted {
+                println!(
+                    "{:.3}\treject\t{filename}\tplausibility={:.3}",
+                    decision.score, decision.score
+                );
+                continue;
+            }
+        }
+        pending.push(submission);
+    }
+    let decisions = if args.batch {
+        judge
+            .decide_batch(pending.clone())
+            .await
+            .context("Judging")?
+    } else {
+        let mut decisions = vec![];
+        for submission in &pending {
+            decisions.push(judge.decide(submission.clone()).await.context("Judging")?);
+        }
+        decisions
+    };
+    for (submission, decision) in pending.iter().zip(decisions) {
+        let parts = decision
+            .parts
+            .iter()
+            .map(|part| format!("{}={:.3}", part.judge, part.score))
+            .join(" ");
+        println!(
+            "{:.3}\t{}\t{}\tthreshold={:.3} {parts}",
+            decision.score,
+            if decision.accepted {
+                "accept"
+            } else {
+                "reject"
+            },
+            submission.query.filename,
+            decision.threshold
+        );
+    }
+    Ok(())
+}
+
+fn export_labeling_history(config: &Config, path: &std::path::Path) -> anyhow::Result<()> {
+    let connection = &mut connect(config)?;
+    let submissions = DatabaseManager::new(connection).judged_submissions()?;
+    let file =
+        std::fs::File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+    let mut writer = csv::Writer::from_writer(file);
+    for (submission, downloaded) in &submissions {
+        let row = LabeledRow::from_submission(submission, downloaded.then_some(true));
+        writer.serialize(row).context("Writing csv row")?;
+    }
+    writer.flush().context("Flushing csv")?;
+    println!(
+        "Wrote {} candidates to {}, {} downloaded. Fill in `label` with true or false.",
+        submissions.len(),
+        path.display(),
+        submissions
+            .iter()
+            .filter(|(_, downloaded)| *downloaded)
+            .count()
+    );
+    Ok(())
+}
+
+fn print_confusion(label: &str, confusion: &Confusion) {
+    println!(
+        "{label}\tprecision {:.3}\trecall {:.3}\tf1 {:.3}\t(tp {} fp {} fn {} tn {})",
+        confusion.precision(),
+        confusion.recall(),
+        confusion.f1(),
+        confusion.true_positives,
+        confusion.false_positives,
+        confusion.false_negatives,
+        confusion.true_negatives
+    );
+}
+
+pub async fn judge_eval(config: &Config, args: JudgeEvalArgs) -> anyhow::Result<()> {
+    if let Some(path) = &args.export_history {
+        return export_labeling_history(config, path);
+    }
+    let path = args.dataset.context("A dataset is required")?;
+    let rows = eval_manager::load_dataset(&path)?;
+    let positives = rows.iter().filter(|row| row.label == Some(true)).count();
+    let negatives = rows.iter().filter(|row| row.label == Some(false)).count();
+    println!(
+        "# {}: {} positive, {} negative, {} unlabeled skipped",
+        path.display(),
+        positives,
+        negatives,
+        rows.len() - positives - negatives
+    );
+    let kinds = if args.judges.is_empty() {
+        vec![config.judge]
+    } else {
+        args.judges
+    };
+    for kind in kinds {
+        let mut judge_config = config.clone();
+        judge_config.judge = kind;
+        let manager = build_judge_manager(&judge_config)?;
+        let report = eval_manager::evaluate(&manager, &rows).await;
+        let scored = report.scored(&rows);
+        println!();
+        println!(
+            "## {} ({} judged, {} prefiltered, {} errors)",
+            report.judge,
+            report.decisions.len(),
+            report.prefiltered.len(),
+            report.errors.len()
+        );
+        if let Some((index, error)) = report.errors.first() {
+            println!("first error on {}: {error}", rows[*index].filename);
+        }
+        print_confusion("as decided", &Confusion::of(&report.outcomes(&rows)));
+        let points = eval_manager::roc(&scored, args.steps);
+        if let Some(best) = points
+            .iter()
+            .max_by(|a, b| a.confusion.f1().total_cmp(&b.confusion.f1()))
+        {
+            print_confusion(
+                &format!("best f1 at {:.2}", best.threshold),
+                &best.confusion,
+            );
+        }
+        match eval_manager::auc(&scored) {
+            Some(auc) => println!("auc {auc:.3}"),
+            None => println!("auc needs positive and negative labels"),
+        }
+        println!("threshold\ttpr\tfpr\tprecision\tf1");
+        for point in &points {
+            println!(
+                "{:.2}\t{:.3}\t{:.3}\t{:.3}\t{:.3}",
+                point.threshold,
+                point.confusion.recall(),
+                point.confusion.false_positive_rate(),
+                point.confusion.precision(),
+                point.confusion.f1()
+            );
+        }
+        let misclassified = report.misclassified(&rows);
+        println!("misclassified: {}", misclassified.len());
+        for (row, decision) in misclassified.into_iter().take(args.show) {
+            let (score, kind) = match decision {
+                None => ("-".to_string(), "missed by prefilter"),
+                Some(decision) if decision.accepted => {
+                    (format!("{:.3}", decision.score), "wrongly accepted")
+                }
+                Some(decision) => (format!("{:.3}", decision.score), "missed"),
+            };
+            println!(
+                "{score}\t{kind}\t{} - {}\t{}",
+                row.artist, row.track, row.filename
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_check_fails_on_an_invalid_config() {
+        let config = Config::builder()
+            .download_root(std::env::temp_dir())
+            .database_url("postgres://localhost/convert_invert")
+            .build();
+        let err = config_check(&config).unwrap_err();
+        assert!(format!("{err:#}").contains("sources"), "{err:#}");
+    }
+}
@@ -0,0 +1,2 @@
+pub mod cli_manager;
+pub mod commands;
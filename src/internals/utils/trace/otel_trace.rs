@@ -1,3 +1,4 @@
+use anyhow::Context;
 use opentelemetry::trace::TracerProvider;
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::{
@@ -15,7 +16,12 @@ use tracing_subscriber::{
 
 /// Initialize tracing with OpenTelemetry integration
 /// This enables trace correlation in logs
-pub fn init_tracing_with_otel(service_name: String, run_id: String) -> anyhow::Result<()> {
+pub fn init_tracing_with_otel(
+    service_name: String,
+    run_id: String,
+    endpoint: String,
+    log_level: &str,
+) -> anyhow::Result<()> {
     let resource = Resource::new(vec![
         opentelemetry::KeyValue::new(SERVICE_NAME, service_name.clone()),
         opentelemetry::KeyValue::new("RUN_ID", run_id),
@@ -28,9 +34,7 @@ pub fn init_tracing_with_otel(service_name: String, run_id: String) -> anyhow::R
     // Configure OTLP exporter
     let otlp_exporter = opentelemetry_otlp::new_exporter()
         .tonic()
-        .with_endpoint(
-            std::env::var("OTLP_ENDPOINT").unwrap_or_else(|_| "http://localhost:4317".to_string()),
-        )
+        .with_endpoint(endpoint)
         .with_timeout(Duration::from_secs(3));
 
     // Build tracer provider
@@ -62,7 +66,8 @@ pub fn init_tracing_with_otel(service_name: String, run_id: String) -> anyhow::R
         .with_span_events(FmtSpan::CLOSE | FmtSpan::ENTER);
 
     // Environment filter
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let env_filter =
+        EnvFilter::try_new(log_level).with_context(|| format!("Invalid log level {log_level}"))?;
 
     // Initialize subscriber with all layers
     tracing_subscriber::registry()
@@ -1,58 +1,39 @@
-use std::{path::PathBuf, str::FromStr};
-
 use anyhow::Context;
-use itertools::Itertools;
+use clap::Parser;
 use tracing::instrument;
 
-use convert_invert::internals::{
-    context::context_manager::Managers,
-    utils::{config::config_manager::Config, trace},
+use convert_invert::internals::utils::{
+    cli::{
+        cli_manager::{Cli, Command, ConfigCommand},
+        commands::{self, RunPlan},
+    },
+    config::config_manager::Config,
 };
 
-use convert_invert::internals::database::establish_connection;
 #[instrument(name = "main-span")]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let connection = &mut establish_connection();
-    let mut config = Config::try_from_env().context("Cannot read env vars for config")?;
-    let attempt_num: usize = match std::env::args().nth(1) {
-        Some(value) => value.parse().unwrap(),
-        None => 1usize,
-    };
-    config.run_id = format!("{}_attempt_{}", config.run_id, attempt_num);
-
-    trace::otel_trace::init_tracing_with_otel("convert-invert".to_string(), config.run_id.clone())
-        .context("Tracing")?;
-
-    let download_path =
-        PathBuf::from_str("/home/gonik/Music/widerisimoBigChannelWithAsyncDownloadMasRaro")
-            .context("Acquiring download dir")?;
-
-    let managers = Managers::new(
-        config.judge_score_levenshtein,
-        download_path.clone(),
-        config.clone(),
-    );
-    let playlist = managers.get_playlist().await;
-    let mut count = 0;
-    for chunk in &playlist.into_iter().take(30).chunks(15) {
-        count += 1;
-        let (sender, receiver) = tokio::sync::mpsc::channel(20000);
-        let managers = Managers::new(
-            config.judge_score_levenshtein,
-            download_path.clone(),
-            config.clone(),
-        );
-        let sender = Managers::inject_tracks(chunk, sender).await.unwrap();
-        managers
-            .run_cycle(sender, receiver, connection)
-            .await
-            .unwrap();
-        tracing::info!(cycle_n = count, "\n\nDone with cycle\n\n");
-        println!("CHUNKERO DUOS {count}")
+    let cli = Cli::parse();
+    let config_file = cli.config.config.clone();
+    let config =
+        Config::load(config_file.as_deref(), cli.config.into()).context("Loading config")?;
+
+    match cli.command {
+        Command::Run(args) => commands::download(config, RunPlan::Playlist, args.attempt).await,
+        Command::Sync(args) => {
+            let plan = RunPlan::Sync {
+                dry_run: args.dry_run,
+            };
+            commands::download(config, plan, args.run.attempt).await
+        }
+        Command::RetryFailed(args) => {
+            commands::download(config, RunPlan::RetryFailed, args.attempt).await
+        }
+        Command::Status(args) => commands::status(&config, args),
+        Command::Export(args) => commands::export(&config, args),
+        Command::Rejections(args) => commands::rejections(&config, args),
+        Command::JudgeTest(args) => commands::judge_test(&config, args).await,
+        Command::JudgeEval(args) => commands::judge_eval(&config, args).await,
+        Command::Config(ConfigCommand::Check) => commands::config_check(&config),
     }
-
-    trace::otel_trace::shutdown_otel();
-
-    Ok(())
 }
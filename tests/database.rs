@@ -0,0 +1,308 @@
+//! `DatabaseManager` against Postgres. Each test migrates its own schema in
+//! the database at `DATABASE_URL`, so the tests are ignored unless asked for
+//! with `--include-ignored`.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use convert_invert::internals::{
+    context::context_manager::{
+        DownloadedFile, Managers, RejectReason, RejectedTrack, RetryNext, RetryRequest, Track,
+    },
+    database::{
+        manager::DatabaseManager,
+        model::RetryNextRow,
+        schema::{downloaded_file, retry_request, search_items},
+    },
+    network::fake::{FakeFile, FakeNetwork, FakePeer, FakeTransfer},
+    search::search_manager::{DownloadableFile, JudgeSubmission, SearchItem},
+    utils::config::config_manager::{Config, JudgeKind, SelectionConfig},
+};
+use diesel::{connection::SimpleConnection, prelude::*};
+
+/// A freshly migrated schema, dropped afterwards.
+struct TestDb {
+    url: String,
+    schema: String,
+}
+
+impl TestDb {
+    fn new(name: &str) -> Self {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let schema = format!("db_{name}_{}", std::process::id());
+        let db = TestDb { url, schema };
+        let mut connection = PgConnection::establish(&db.url).unwrap();
+        connection
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0};",
+                db.schema
+            ))
+            .unwrap();
+        let mut connection = db.connect();
+        let mut migrations: Vec<PathBuf> = std::fs::read_dir("migrations")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect();
+        migrations.sort();
+        for migration in migrations {
+            let up = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+            connection
+                .batch_execute(&up)
+                .unwrap_or_else(|err| panic!("{}: {err}", migration.display()));
+        }
+        db
+    }
+
+    /// A connection that sees only this test's schema.
+    fn connect(&self) -> PgConnection {
+        let mut connection = PgConnection::establish(&self.url).unwrap();
+        connection
+            .batch_execute(&format!("SET search_path TO {}", self.schema))
+            .unwrap();
+        connection
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        if let Ok(mut connection) = PgConnection::establish(&self.url) {
+            let _ = connection.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema));
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs DATABASE_URL"]
+fn moves_a_legacy_row_to_the_spotify_key() {
+    let db = TestDb::new("legacy_key");
+    let mut connection = db.connect();
+    let legacy = SearchItem::new(
+        "Creep".to_string(),
+        "Pablo Honey".to_string(),
+        "Radiohead".to_string(),
+    )
+    .with_isrc(Some("GBAYE9200070".to_string()));
+    let current = legacy
+        .clone()
+        .with_spotify_id(Some("70LcF31zb1H0PyJoS1Sx1r".to_string()));
+    let mut manager = DatabaseManager::new(&mut connection);
+    manager
+        .load_item_to_database(&Track::Query(legacy))
+        .unwrap();
+    manager
+        .load_item_to_database(&Track::Query(current))
+        .unwrap();
+
+    let keys: Vec<String> = search_items::table
+        .select(search_items::track_id)
+        .load(&mut connection)
+        .unwrap();
+    assert_eq!(keys, ["spotify:70LcF31zb1H0PyJoS1Sx1r"]);
+}
+
+#[test]
+#[ignore = "needs DATABASE_URL"]
+fn counts_a_download_only_for_the_track_it_was_downloaded_for() {
+    let db = TestDb::new("downloaded");
+    let mut connection = db.connect();
+    let submission = |track: &str, username: &str| JudgeSubmission {
+        track: SearchItem::new(
+            track.to_string(),
+            "Singles".to_string(),
+            "Various".to_string(),
+        ),
+        query: DownloadableFile::new("01 Intro.mp3".to_string(), username.to_string(), 1),
+    };
+    let (fetched, other) = (submission("Intro", "alice"), submission("Outro", "bob"));
+    let mut manager = DatabaseManager::new(&mut connection);
+    for submission in [&fetched, &other] {
+        manager
+            .load_item_to_database(&Track::Query(submission.track.clone()))
+            .unwrap();
+        manager
+            .load_item_to_database(&Track::Result(submission.clone()))
+            .unwrap();
+    }
+    manager
+        .load_item_to_database(&Track::File(DownloadedFile {
+            filename: fetched.query.filename.clone(),
+            submission: Box::new(fetched.clone()),
+        }))
+        .unwrap();
+
+    let downloaded = manager.downloaded_track_ids().unwrap();
+    assert_eq!(downloaded, [fetched.track.track_id].into());
+}
+
+#[test]
+#[ignore = "needs DATABASE_URL"]
+fn replaces_what_a_source_listed() {
+    let db = TestDb::new("source_tracks");
+    let mut connection = db.connect();
+    let mut manager = DatabaseManager::new(&mut connection);
+    manager
+        .set_source_tracks("playlist", &["a".to_string(), "b".to_string()])
+        .unwrap();
+    manager
+        .set_source_tracks("album", &["c".to_string()])
+        .unwrap();
+    manager
+        .set_source_tracks("playlist", &["b".to_string()])
+        .unwrap();
+
+    assert_eq!(
+        manager.source_track_ids("playlist").unwrap(),
+        ["b".to_string()].into()
+    );
+    assert_eq!(
+        manager.source_track_ids("album").unwrap(),
+        ["c".to_string()].into()
+    );
+}
+
+#[test]
+#[ignore = "needs DATABASE_URL"]
+fn keeps_why_the_judge_failed() {
+    let db = TestDb::new("judge_failed");
+    let mut connection = db.connect();
+    let submission = JudgeSubmission {
+        track: SearchItem::new(
+            "Creep".to_string(),
+            "Pablo Honey".to_string(),
+            "Radiohead".to_string(),
+        ),
+        query: DownloadableFile::new("02 Creep.flac".to_string(), "peer".to_string(), 1),
+    };
+    let mut manager = DatabaseManager::new(&mut connection);
+    for track in [
+        Track::Query(submission.track.clone()),
+        Track::Result(submission.clone()),
+        Track::Reject(RejectedTrack::new(
+            submission,
+            RejectReason::JudgeFailed("LLM timed out".to_string()),
+        )),
+    ] {
+        manager.load_item_to_database(&track).unwrap();
+    }
+
+    let rejections = manager.rejections(None, None, 10).unwrap();
+    assert!(matches!(
+        rejections[0].parts().1,
+        RejectReason::JudgeFailed(error) if error == "LLM timed out"
+    ));
+}
+
+#[test]
+#[ignore = "needs DATABASE_URL"]
+fn stores_what_a_failed_download_led_to() {
+    let db = TestDb::new("retry_next");
+    let mut connection = db.connect();
+    let submission = |filename: &str| JudgeSubmission {
+        track: SearchItem::new(
+            "Creep".to_string(),
+            "Pablo Honey".to_string(),
+            "Radiohead".to_string(),
+        ),
+        query: DownloadableFile::new(filename.to_string(), "peer".to_string(), 1),
+    };
+    let (failed, fallback) = (submission("02 Creep.flac"), submission("02 Creep.mp3"));
+    let retry = |attempts: u8, next: RetryNext| RetryRequest {
+        request: failed.clone(),
+        retry_attempts: attempts,
+        failed_download_result: failed.query.clone(),
+        next,
+    };
+    let mut manager = DatabaseManager::new(&mut connection);
+    for track in [
+        Track::Query(failed.track.clone()),
+        Track::Result(failed.clone()),
+        Track::Result(fallback.clone()),
+        Track::Retry(retry(1, RetryNext::Fallback(Box::new(fallback)))),
+        Track::Retry(retry(2, RetryNext::Search)),
+    ] {
+        manager.load_item_to_database(&track).unwrap();
+    }
+
+    let stored: Vec<(RetryNextRow, bool)> = retry_request::table
+        .select((
+            retry_request::next,
+            retry_request::next_candidate.is_not_null(),
+        ))
+        .order(retry_request::id)
+        .load(&mut connection)
+        .unwrap();
+    assert_eq!(
+        stored,
+        [
+            (RetryNextRow::Fallback, true),
+            (RetryNextRow::Search, false)
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs DATABASE_URL"]
+async fn records_a_cycle_with_its_fallback() {
+    let db = TestDb::new("cycle");
+    let flac = "Radiohead\\Pablo Honey\\02 Creep.flac";
+    let mp3 = "Radiohead\\Pablo Honey\\02 Creep.mp3";
+    let network = Arc::new(
+        FakeNetwork::new()
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("flaky").with_file(
+                    FakeFile::new(flac, b"flac bytes").with_transfer(FakeTransfer::Partial(4)),
+                ),
+            )
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("steady").with_file(FakeFile::new(mp3, b"mp3 bytes")),
+            ),
+    );
+    let download_root = std::env::temp_dir().join(&db.schema);
+    std::fs::create_dir_all(&download_root).unwrap();
+    let config = Config::builder()
+        .judge(JudgeKind::Token)
+        .timeouts(2, 5)
+        .download_root(&download_root)
+        .selection(SelectionConfig {
+            grace_secs: 1,
+            ..SelectionConfig::default()
+        })
+        .build();
+    let managers = Managers::with_backends(config, network.clone(), network).unwrap();
+    let (sender, receiver) = tokio::sync::mpsc::channel(20000);
+    let creep = SearchItem::new(
+        "Creep".to_string(),
+        "Pablo Honey".to_string(),
+        "Radiohead".to_string(),
+    );
+    let sender = Managers::inject_tracks([Track::Query(creep)], sender)
+        .await
+        .unwrap();
+    let mut connection = db.connect();
+    let mut store = DatabaseManager::new(&mut connection);
+    tokio::time::timeout(
+        Duration::from_secs(120),
+        managers.run_cycle(sender, receiver, &mut store),
+    )
+    .await
+    .expect("run cycle did not return")
+    .unwrap();
+    let _ = std::fs::remove_dir_all(&download_root);
+
+    let fallbacks: Vec<(RetryNextRow, Option<i32>)> = retry_request::table
+        .select((retry_request::next, retry_request::next_candidate))
+        .load(&mut connection)
+        .unwrap();
+    assert!(matches!(fallbacks[..], [(RetryNextRow::Fallback, Some(_))]));
+    let downloaded: Vec<(String, Option<i32>)> = downloaded_file::table
+        .order(downloaded_file::filename)
+        .select((downloaded_file::filename, downloaded_file::submission))
+        .load(&mut connection)
+        .unwrap();
+    assert!(matches!(
+        &downloaded[..],
+        [(filename, Some(_))] if filename == mp3
+    ));
+}
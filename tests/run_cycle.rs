@@ -0,0 +1,290 @@
+//! `Managers::run_cycle` end to end against the in-memory network, so no
+//! Soulseek account is needed. Tracks are recorded in memory, so no database
+//! is needed either.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use convert_invert::internals::{
+    context::context_manager::{Managers, MemoryTrackStore, RetryNext, Track},
+    network::fake::{FakeFile, FakeNetwork, FakePeer, FakeTransfer},
+    search::{query_planner::QueryStrategy, search_manager::SearchItem},
+    utils::config::config_manager::{Config, JudgeKind, QueryPlanConfig, SelectionConfig},
+};
+
+const FLAC: &str = "Radiohead\\Pablo Honey\\02 Creep.flac";
+const MP3: &str = "Radiohead\\Pablo Honey\\02 Creep.mp3";
+const JUNK: &str = "Misc\\holiday voicemail.mp3";
+
+/// A download root of its own, removed afterwards.
+struct TestRun {
+    download_root: PathBuf,
+}
+
+impl TestRun {
+    fn new(name: &str) -> Self {
+        let download_root = std::env::temp_dir().join(format!("e2e_{name}_{}", std::process::id()));
+        std::fs::create_dir_all(&download_root).unwrap();
+        TestRun { download_root }
+    }
+
+    fn config(&self) -> Config {
+        Config::builder()
+            .judge(JudgeKind::Token)
+            .timeouts(2, 5)
+            .search_idle_timeout(1)
+            .download_root(&self.download_root)
+            .selection(SelectionConfig {
+                grace_secs: 1,
+                ..SelectionConfig::default()
+            })
+            .build()
+    }
+
+    fn downloaded(&self, filename: &str) -> Option<Vec<u8>> {
+        std::fs::read(Path::new(&self.download_root).join(filename)).ok()
+    }
+
+    /// Runs a cycle over `items` until it returns, which it does once every
+    /// search, selection, download and retry has settled. Returns the tracks
+    /// the cycle recorded.
+    async fn run(&self, managers: Managers, items: Vec<SearchItem>) -> Vec<Track> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(20000);
+        let sender = Managers::inject_tracks(items.into_iter().map(Track::Query), sender)
+            .await
+            .unwrap();
+        let mut store = MemoryTrackStore::default();
+        tokio::time::timeout(
+            Duration::from_secs(120),
+            managers.run_cycle(sender, receiver, &mut store),
+        )
+        .await
+        .expect("run cycle did not return")
+        .unwrap();
+        store.tracks
+    }
+}
+
+impl Drop for TestRun {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.download_root);
+    }
+}
+
+fn retries(tracks: &[Track]) -> Vec<&RetryNext> {
+    tracks
+        .iter()
+        .filter_map(|track| match track {
+            Track::Retry(retry) => Some(&retry.next),
+            _ => None,
+        })
+        .collect()
+}
+
+fn creep() -> SearchItem {
+    SearchItem::new(
+        "Creep".to_string(),
+        "Pablo Honey".to_string(),
+        "Radiohead".to_string(),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn downloads_the_best_candidate_and_skips_the_rest() {
+    let test = TestRun::new("best");
+    let network = Arc::new(
+        FakeNetwork::new()
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("mp3_peer").with_file(FakeFile::new(MP3, b"mp3 bytes")),
+            )
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("flac_peer")
+                    .with_latency(Duration::from_millis(500))
+                    .with_file(FakeFile::new(FLAC, b"flac bytes"))
+                    .with_file(FakeFile::new(JUNK, b"junk")),
+            ),
+    );
+    let managers =
+        Managers::with_backends(test.config(), network.clone(), network.clone()).unwrap();
+
+    test.run(managers, vec![creep()]).await;
+
+    assert_eq!(
+        network.downloads(),
+        [("flac_peer".to_string(), FLAC.to_string())]
+    );
+    assert_eq!(test.downloaded(FLAC).as_deref(), Some(&b"flac bytes"[..]));
+    assert_eq!(test.downloaded(MP3), None);
+    assert_eq!(test.downloaded(JUNK), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn falls_back_to_the_next_candidate_after_a_partial_download() {
+    let test = TestRun::new("fallback");
+    let network = Arc::new(
+        FakeNetwork::new()
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("flaky").with_file(
+                    FakeFile::new(FLAC, b"flac bytes").with_transfer(FakeTransfer::Partial(4)),
+                ),
+            )
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("steady").with_file(FakeFile::new(MP3, b"mp3 bytes")),
+            ),
+    );
+    let managers =
+        Managers::with_backends(test.config(), network.clone(), network.clone()).unwrap();
+
+    let tracks = test.run(managers, vec![creep()]).await;
+
+    assert_eq!(
+        network.downloads(),
+        [
+            ("flaky".to_string(), FLAC.to_string()),
+            ("steady".to_string(), MP3.to_string()),
+        ]
+    );
+    assert_eq!(test.downloaded(FLAC).as_deref(), Some(&b"flac"[..]));
+    assert_eq!(test.downloaded(MP3).as_deref(), Some(&b"mp3 bytes"[..]));
+    assert!(matches!(retries(&tracks)[..], [RetryNext::Fallback(_)]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn escalates_to_the_next_query_when_nothing_is_accepted() {
+    let test = TestRun::new("escalation");
+    let network = Arc::new(
+        FakeNetwork::new()
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("junk_peer").with_file(FakeFile::new(JUNK, b"junk")),
+            )
+            .respond(
+                "Creep Pablo Honey",
+                FakePeer::new("album_peer").with_file(FakeFile::new(FLAC, b"flac bytes")),
+            ),
+    );
+    let mut config = test.config();
+    config.query_plan = QueryPlanConfig {
+        strategies: vec![QueryStrategy::Full, QueryStrategy::TitleAlbum],
+        ..QueryPlanConfig::default()
+    };
+    let managers = Managers::with_backends(config, network.clone(), network.clone()).unwrap();
+
+    let tracks = test.run(managers, vec![creep()]).await;
+
+    assert_eq!(
+        network.searched(),
+        ["Creep - Radiohead", "Creep Pablo Honey"]
+    );
+    assert_eq!(test.downloaded(FLAC).as_deref(), Some(&b"flac bytes"[..]));
+    let attempts: Vec<(QueryStrategy, bool)> = tracks
+        .iter()
+        .filter_map(|track| match track {
+            Track::Attempt(attempt) => Some((attempt.plan.variant().strategy, attempt.accepted)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        attempts,
+        [
+            (QueryStrategy::Full, false),
+            (QueryStrategy::TitleAlbum, true)
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn returns_once_every_track_has_settled() {
+    let test = TestRun::new("settled");
+    let karma_police = "Radiohead\\OK Computer\\06 Karma Police.flac";
+    let network = Arc::new(
+        FakeNetwork::new()
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("steady").with_file(FakeFile::new(FLAC, b"flac bytes")),
+            )
+            .respond(
+                "Karma Police - Radiohead",
+                FakePeer::new("flaky").with_file(
+                    FakeFile::new(karma_police, b"flac bytes").with_transfer(FakeTransfer::Fail),
+                ),
+            ),
+    );
+    let managers =
+        Managers::with_backends(test.config(), network.clone(), network.clone()).unwrap();
+    let items = vec![
+        creep(),
+        SearchItem::new(
+            "Karma Police".to_string(),
+            "OK Computer".to_string(),
+            "Radiohead".to_string(),
+        ),
+        SearchItem::new(
+            "Nowhere To Be Found".to_string(),
+            "Unreleased".to_string(),
+            "Nobody".to_string(),
+        ),
+    ];
+
+    let tracks = test.run(managers, items).await;
+
+    let downloaded: Vec<&str> = tracks
+        .iter()
+        .filter_map(|track| match track {
+            Track::File(file) => Some(file.filename.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(downloaded, [FLAC]);
+    let downloads = network.downloads();
+    assert_eq!(downloads.len(), 2);
+    assert!(downloads.contains(&("flaky".to_string(), karma_police.to_string())));
+    let searched = network.searched();
+    // The failed track was searched again, the missing one through every
+    // query variant.
+    assert_eq!(
+        searched
+            .iter()
+            .filter(|query| *query == "Karma Police - Radiohead")
+            .count(),
+        2
+    );
+    assert!(searched.contains(&"Nowhere To Be Found Unreleased".to_string()));
+    assert!(matches!(retries(&tracks)[..], [RetryNext::Search]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_default_judge_downloads_the_closest_name() {
+    let test = TestRun::new("default_judge");
+    let named = "Creep - Radiohead - Pablo Honey.flac";
+    let network = Arc::new(
+        FakeNetwork::new()
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("junk_peer").with_file(FakeFile::new(JUNK, b"junk")),
+            )
+            .respond(
+                "Creep - Radiohead",
+                FakePeer::new("named_peer").with_file(FakeFile::new(named, b"flac bytes")),
+            ),
+    );
+    let mut config = test.config();
+    config.judge = JudgeKind::default();
+    let managers = Managers::with_backends(config, network.clone(), network.clone()).unwrap();
+
+    test.run(managers, vec![creep()]).await;
+
+    assert_eq!(
+        network.downloads(),
+        [("named_peer".to_string(), named.to_string())]
+    );
+    assert_eq!(test.downloaded(named).as_deref(), Some(&b"flac bytes"[..]));
+    assert_eq!(test.downloaded(JUNK), None);
+}